@@ -0,0 +1,367 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use futures::{Future, Poll, Async};
+use futures::task::{self, Task};
+use tokio_core::reactor::{Handle, Timeout};
+
+/// What a server does with new connections once it has reached its
+/// connection limit.
+///
+/// See `TcpServer::max_connections` and `UnixServer::max_connections`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Stop accepting connections until a slot frees up. Pending connections
+    /// wait in the listen backlog of the operating system.
+    Pause,
+    /// Accept connections and close them immediately.
+    Reject,
+}
+
+/// How a server accepts connections, shared by every worker.
+#[derive(Clone)]
+pub struct Config {
+    pub backoff: Duration,
+    pub on_error: Option<Arc<Fn(&io::Error) + Send + Sync>>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_worker: Option<usize>,
+    pub limit_policy: LimitPolicy,
+}
+
+/// A listener that accepts connections without blocking.
+pub trait Listen {
+    /// An accepted connection.
+    type Conn;
+
+    /// Accept a connection, failing with `WouldBlock` if there is none.
+    fn accept(&mut self) -> io::Result<Self::Conn>;
+}
+
+/// Accepts the connections of a worker, subject to the connection limits.
+///
+/// Accepting is suspended for a while after errors such as running out of
+/// file descriptors, which would otherwise make the worker spin.
+pub struct Acceptor<L: Listen> {
+    listener: L,
+    config: Config,
+    limits: Limits,
+    handle: Handle,
+    // Set while accepting is suspended after an error
+    backoff: Option<Timeout>,
+    // Accepted connection waiting for a slot
+    pending: Option<L::Conn>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            backoff: Duration::from_secs(1),
+            on_error: None,
+            max_connections: None,
+            max_connections_per_worker: None,
+            limit_policy: LimitPolicy::Pause,
+        }
+    }
+
+    /// Returns the limit shared by all workers of a server.
+    pub fn global_limit(&self) -> Arc<Limit> {
+        Arc::new(Limit::new(self.max_connections))
+    }
+}
+
+impl<L: Listen> Acceptor<L> {
+    pub fn new(listener: L, config: Config, global: Arc<Limit>, handle: &Handle) -> Acceptor<L> {
+        let limits = Limits {
+            worker: Arc::new(Limit::new(config.max_connections_per_worker)),
+            global: global,
+        };
+
+        Acceptor {
+            listener: listener,
+            config: config,
+            limits: limits,
+            handle: handle.clone(),
+            backoff: None,
+            pending: None,
+        }
+    }
+
+    /// Returns the next connection that has been given a slot.
+    ///
+    /// The connection counts toward the limits until the slot is dropped.
+    pub fn poll_next(&mut self) -> Poll<(L::Conn, Slot), io::Error> {
+        loop {
+            // Wait for a slot before accepting, or before retrying a pending
+            // connection. The task is notified once a slot frees up.
+            if self.config.limit_policy == LimitPolicy::Pause {
+                if let Async::NotReady = self.limits.poll_ready() {
+                    return Ok(Async::NotReady);
+                }
+            }
+
+            let conn = match self.pending.take() {
+                Some(conn) => conn,
+                None => try_ready!(self.accept()),
+            };
+
+            if let Some(slot) = self.limits.acquire() {
+                return Ok(Async::Ready((conn, slot)));
+            }
+
+            match self.config.limit_policy {
+                LimitPolicy::Pause => {
+                    // Another worker took the last slot in the meantime. Hold
+                    // on to the connection until a slot frees up.
+                    self.pending = Some(conn);
+
+                    if let Async::NotReady = self.limits.poll_ready() {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                LimitPolicy::Reject => {
+                    debug!("connection limit reached; closing connection");
+                }
+            }
+        }
+    }
+
+    fn accept(&mut self) -> Poll<L::Conn, io::Error> {
+        loop {
+            if let Some(mut backoff) = self.backoff.take() {
+                if let Async::NotReady = try!(backoff.poll()) {
+                    self.backoff = Some(backoff);
+                    return Ok(Async::NotReady);
+                }
+            }
+
+            let err = match self.listener.accept() {
+                Ok(conn) => return Ok(Async::Ready(conn)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => e,
+            };
+
+            if let Some(ref f) = self.config.on_error {
+                f(&err);
+            }
+
+            if is_connection_error(&err) {
+                // Only the connection being accepted is affected
+                debug!("failed to accept connection; err={}", err);
+            } else {
+                // Most likely out of file descriptors. Accepting again right
+                // away would just spin, so wait for connections to close.
+                error!("accept error; backing off for {:?}; err={}",
+                       self.config.backoff, err);
+
+                let backoff = try!(Timeout::new(self.config.backoff, &self.handle));
+                self.backoff = Some(backoff);
+            }
+        }
+    }
+}
+
+/// Counts connections against an optional maximum.
+pub struct Limit {
+    max: Option<usize>,
+    active: AtomicUsize,
+    // Tasks waiting for a slot to free up
+    waiting: Mutex<Vec<Task>>,
+}
+
+/// The limits that apply to the connections of a worker.
+#[derive(Clone)]
+struct Limits {
+    worker: Arc<Limit>,
+    global: Arc<Limit>,
+}
+
+/// A connection slot, given back when dropped.
+pub struct Slot {
+    limits: Limits,
+}
+
+impl Limit {
+    pub fn new(max: Option<usize>) -> Limit {
+        Limit {
+            max: max,
+            active: AtomicUsize::new(0),
+            waiting: Mutex::new(vec![]),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        match self.max {
+            Some(max) => self.active.load(Ordering::SeqCst) >= max,
+            None => false,
+        }
+    }
+
+    /// Returns `NotReady` and arranges for the current task to be notified
+    /// if there is no free slot.
+    fn poll_ready(&self) -> Async<()> {
+        if !self.is_full() {
+            return Async::Ready(());
+        }
+
+        {
+            let mut waiting = self.waiting.lock().unwrap();
+
+            // A worker polls again on every wakeup, so only store its task once
+            if !waiting.iter().any(|task| task.will_notify_current()) {
+                waiting.push(task::current());
+            }
+        }
+
+        // Check again in case a slot was released before the task was stored
+        if self.is_full() {
+            Async::NotReady
+        } else {
+            Async::Ready(())
+        }
+    }
+
+    fn acquire(&self) -> bool {
+        let max = match self.max {
+            Some(max) => max,
+            None => {
+                self.active.fetch_add(1, Ordering::SeqCst);
+                return true;
+            }
+        };
+
+        let mut active = self.active.load(Ordering::SeqCst);
+
+        while active < max {
+            match self.active.compare_exchange(active, active + 1,
+                                               Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return true,
+                Err(prev) => active = prev,
+            }
+        }
+
+        false
+    }
+
+    fn release(&self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+
+        for task in self.waiting.lock().unwrap().drain(..) {
+            task.unpark();
+        }
+    }
+}
+
+impl Limits {
+    fn poll_ready(&self) -> Async<()> {
+        match self.worker.poll_ready() {
+            Async::Ready(()) => self.global.poll_ready(),
+            Async::NotReady => Async::NotReady,
+        }
+    }
+
+    fn acquire(&self) -> Option<Slot> {
+        if !self.worker.acquire() {
+            return None;
+        }
+
+        if !self.global.acquire() {
+            self.worker.release();
+            return None;
+        }
+
+        Some(Slot { limits: self.clone() })
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.limits.worker.release();
+        self.limits.global.release();
+    }
+}
+
+fn is_connection_error(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionRefused |
+        io::ErrorKind::Interrupted => true,
+        _ => false,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{Acceptor, Config, Listen};
+
+    use std::collections::VecDeque;
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    use futures::future;
+    use tokio_core::reactor::Core;
+
+    struct MockListener {
+        results: VecDeque<io::Result<usize>>,
+    }
+
+    impl Listen for MockListener {
+        type Conn = usize;
+
+        fn accept(&mut self) -> io::Result<usize> {
+            match self.results.pop_front() {
+                Some(res) => res,
+                None => Err(io::Error::new(io::ErrorKind::WouldBlock, "would block")),
+            }
+        }
+    }
+
+    // Accepts the first connection from a listener that fails with `err`
+    // first, returning the time it took and the number of reported errors
+    fn accept_after(err: io::Error, backoff: Duration) -> (Duration, usize) {
+        let mut core = Core::new().unwrap();
+        let errors = Arc::new(AtomicUsize::new(0));
+
+        let mut config = Config::new();
+        let counter = errors.clone();
+        config.backoff = backoff;
+        config.on_error = Some(Arc::new(move |_: &io::Error| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        let listener = MockListener { results: vec![Err(err), Ok(1)].into_iter().collect() };
+        let limit = config.global_limit();
+        let mut acceptor = Acceptor::new(listener, config, limit, &core.handle());
+
+        let start = Instant::now();
+        let (conn, _slot) = core.run(future::poll_fn(|| acceptor.poll_next())).unwrap();
+
+        assert_eq!(1, conn);
+        (start.elapsed(), errors.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn test_backs_off_after_listener_error() {
+        let emfile = io::Error::new(io::ErrorKind::Other, "too many open files");
+        let (elapsed, errors) = accept_after(emfile, Duration::from_millis(200));
+
+        assert!(elapsed >= Duration::from_millis(200));
+        assert_eq!(1, errors);
+    }
+
+    #[test]
+    fn test_connection_error_does_not_back_off() {
+        let aborted = io::Error::new(io::ErrorKind::ConnectionAborted, "aborted");
+        let (elapsed, errors) = accept_after(aborted, Duration::from_secs(10));
+
+        assert!(elapsed < Duration::from_secs(1));
+        assert_eq!(1, errors);
+    }
+}
@@ -0,0 +1,66 @@
+use std::io::{self, Read, Write};
+
+use futures::Async;
+use futures::sync::oneshot;
+use tokio_core::io::Io;
+use tokio_core::net::TcpStream;
+
+/// The I/O object that `ReconnectingClient` and `util::pool::Pool` bind their
+/// protocol to.
+///
+/// Wraps the `TcpStream` of a connection, and lets the client know when the
+/// connection has been dropped.
+pub struct ClientStream {
+    inner: TcpStream,
+    _closed: oneshot::Sender<()>,
+}
+
+impl ClientStream {
+    /// Wraps `socket`. The returned receiver completes once the stream has
+    /// been dropped.
+    pub fn new(socket: TcpStream) -> (ClientStream, oneshot::Receiver<()>) {
+        let (tx, rx) = oneshot::channel();
+        let stream = ClientStream {
+            inner: socket,
+            _closed: tx,
+        };
+
+        (stream, rx)
+    }
+
+    /// Returns a reference to the underlying `TcpStream`.
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying `TcpStream`.
+    pub fn get_mut(&mut self) -> &mut TcpStream {
+        &mut self.inner
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Io for ClientStream {
+    fn poll_read(&mut self) -> Async<()> {
+        self.inner.poll_read()
+    }
+
+    fn poll_write(&mut self) -> Async<()> {
+        self.inner.poll_write()
+    }
+}
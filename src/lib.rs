@@ -183,6 +183,11 @@ extern crate take;
 extern crate tokio_core;
 extern crate tokio_service;
 
+#[cfg(unix)]
+extern crate libc;
+#[cfg(unix)]
+extern crate tokio_uds;
+
 #[macro_use]
 extern crate futures;
 
@@ -196,10 +201,35 @@ pub mod streaming;
 pub mod util;
 
 mod tcp_client;
-pub use tcp_client::{TcpClient, Connect};
+pub use tcp_client::{TcpClient, Connect, ConnectWithAddr};
+
+mod client_stream;
+pub use client_stream::ClientStream;
+
+mod reconnecting_client;
+pub use reconnecting_client::{ReconnectingClient, ReconnectingResponse, ConnectionState};
+
+mod accept;
+mod server;
 
 mod tcp_server;
-pub use tcp_server::TcpServer;
+pub use tcp_server::{TcpServer, ServerHandle, ConnectionInfo, WithMultiplexConfig};
+pub use accept::LimitPolicy;
+
+#[cfg(unix)]
+mod listen_fds;
+#[cfg(unix)]
+pub use listen_fds::listen_fds;
+
+#[cfg(unix)]
+mod unix_client;
+#[cfg(unix)]
+pub use unix_client::UnixClient;
+
+#[cfg(unix)]
+mod unix_server;
+#[cfg(unix)]
+pub use unix_server::{UnixServer, UnixServerHandle};
 
 use tokio_core::reactor::Handle;
 use tokio_service::Service;
@@ -0,0 +1,265 @@
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStringExt;
+use std::net;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{self, Child, Command};
+use std::ptr;
+
+use libc;
+
+/// The first file descriptor passed by the supervisor.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Returns the listening sockets passed down to the process by a supervisor,
+/// following the systemd socket activation protocol.
+///
+/// The supervisor passes the number of sockets in the `LISTEN_FDS`
+/// environment variable, and the ID of the process they are meant for in
+/// `LISTEN_PID`. The sockets themselves are open on the file descriptors
+/// starting at 3.
+///
+/// An empty list is returned if no sockets were passed to this process. Both
+/// environment variables are removed, so that the sockets are not picked up
+/// again, and the sockets are not inherited by child processes.
+///
+/// The listeners can be used with `TcpServer::from_listener`.
+pub fn listen_fds() -> io::Result<Vec<net::TcpListener>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(vec![]),
+    };
+
+    if pid.parse::<u32>().ok() != Some(process::id()) {
+        debug!("LISTEN_PID is for another process; pid={}", pid);
+        return Ok(vec![]);
+    }
+
+    let fds = try!(fds.parse::<RawFd>().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, "invalid LISTEN_FDS")
+    }));
+
+    let mut listeners = Vec::with_capacity(fds as usize);
+
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + fds {
+        try!(check_socket(fd));
+
+        unsafe {
+            if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            listeners.push(net::TcpListener::from_raw_fd(fd));
+        }
+    }
+
+    Ok(listeners)
+}
+
+/// Makes sure that `fd` is a listening TCP socket before taking ownership of
+/// it, so that a wrong socket is reported here rather than on accept.
+fn check_socket(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let mut stat: libc::stat = mem::zeroed();
+
+        if libc::fstat(fd, &mut stat) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if stat.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+            return Err(invalid_socket("inherited file descriptor is not a socket"));
+        }
+
+        if try!(socket_option(fd, libc::SO_TYPE)) != libc::SOCK_STREAM {
+            return Err(invalid_socket("inherited socket is not a stream socket"));
+        }
+
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+        if libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET | libc::AF_INET6 => {}
+            _ => return Err(invalid_socket("inherited socket is not a TCP socket")),
+        }
+
+        if try!(socket_option(fd, libc::SO_ACCEPTCONN)) == 0 {
+            return Err(invalid_socket("inherited socket is not listening"));
+        }
+    }
+
+    Ok(())
+}
+
+fn socket_option(fd: RawFd, option: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(fd,
+                         libc::SOL_SOCKET,
+                         option,
+                         &mut value as *mut _ as *mut libc::c_void,
+                         &mut len)
+    };
+
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(value)
+}
+
+fn invalid_socket(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Spawns `command` with `listeners` passed to it following the socket
+/// activation protocol read by `listen_fds`.
+///
+/// The new process gets the environment of this one, with the variables of
+/// the protocol added. This only works when the environment of `command` has
+/// not been changed.
+pub fn spawn_with_fds(command: &mut Command, listeners: &[net::TcpListener]) -> io::Result<Child> {
+    if command.get_envs().next().is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "the environment of the command must be inherited"));
+    }
+
+    let fds: Vec<RawFd> = listeners.iter().map(|l| l.as_raw_fd()).collect();
+    let count = fds.len() as RawFd;
+
+    // Allocated up front, to keep the work done between fork and exec to a
+    // minimum
+    let mut moved: Vec<RawFd> = Vec::with_capacity(fds.len());
+    let mut env = Environment::new(count);
+
+    command.before_exec(move || {
+        unsafe {
+            // Move the sockets out of the way first, as they may currently
+            // occupy the descriptors they are moved to.
+            moved.clear();
+
+            for &fd in &fds {
+                let dup = libc::fcntl(fd, libc::F_DUPFD, LISTEN_FDS_START + count);
+
+                if dup == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                moved.push(dup);
+            }
+
+            for (i, &fd) in moved.iter().enumerate() {
+                // The duplicate does not have `FD_CLOEXEC` set
+                if libc::dup2(fd, LISTEN_FDS_START + i as RawFd) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                libc::close(fd);
+            }
+
+            // The ID of the new process is only known at this point
+            env.install(libc::getpid() as u32);
+        }
+
+        Ok(())
+    }).spawn()
+}
+
+extern "C" {
+    static mut environ: *const *const libc::c_char;
+}
+
+/// The environment of a process spawned by `spawn_with_fds`, built before the
+/// fork.
+///
+/// Setting variables in the new process with `setenv` is not safe between
+/// fork and exec, as it allocates. Instead, the entries are prepared up front,
+/// and the new process only writes its ID into the `LISTEN_PID` entry and
+/// points `environ` at them.
+struct Environment {
+    // Nul terminated `NAME=value` entries
+    _entries: Vec<Vec<u8>>,
+    // The `LISTEN_PID=` entry, with room for the ID
+    pid: Vec<u8>,
+    // Pointers to the entries, terminated by a null pointer
+    ptrs: Vec<*const libc::c_char>,
+}
+
+// The pointers only point into the entries owned by the struct
+unsafe impl Send for Environment {}
+unsafe impl Sync for Environment {}
+
+const LISTEN_PID: &'static [u8] = b"LISTEN_PID=";
+
+impl Environment {
+    fn new(count: RawFd) -> Environment {
+        let mut entries = env::vars_os().filter(|&(ref name, _)| {
+            name != "LISTEN_FDS" && name != "LISTEN_PID"
+        }).map(|(name, value)| {
+            let mut entry = name.into_vec();
+            entry.push(b'=');
+            entry.extend(value.into_vec());
+            entry.push(0);
+            entry
+        }).collect::<Vec<_>>();
+
+        entries.push(format!("LISTEN_FDS={}\0", count).into_bytes());
+
+        let mut pid = LISTEN_PID.to_vec();
+        pid.extend(&[0; 24]);
+
+        let mut ptrs = entries.iter()
+            .map(|entry| entry.as_ptr() as *const libc::c_char)
+            .collect::<Vec<_>>();
+        ptrs.push(pid.as_ptr() as *const libc::c_char);
+        ptrs.push(ptr::null());
+
+        Environment {
+            _entries: entries,
+            pid: pid,
+            ptrs: ptrs,
+        }
+    }
+
+    /// Makes the entries the environment of the current process, with `pid`
+    /// filled in. Only called between fork and exec.
+    unsafe fn install(&mut self, pid: u32) {
+        format_number(pid, &mut self.pid[LISTEN_PID.len()..]);
+        environ = self.ptrs.as_ptr();
+    }
+}
+
+/// Writes `n` to `buf` as a nul terminated string, without allocating.
+fn format_number(mut n: u32, buf: &mut [u8]) {
+    let mut digits = [0u8; 10];
+    let mut len = 0;
+
+    loop {
+        digits[len] = b'0' + (n % 10) as u8;
+        len += 1;
+        n /= 10;
+
+        if n == 0 {
+            break;
+        }
+    }
+
+    for i in 0..len {
+        buf[i] = digits[len - 1 - i];
+    }
+
+    buf[len] = 0;
+}
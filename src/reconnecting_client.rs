@@ -0,0 +1,336 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+use BindClient;
+use client_stream::ClientStream;
+use tcp_client::{ConnectSocket, Connector, TcpClient};
+use futures::{Future, Poll, Async};
+use futures::sync::oneshot;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_service::Service;
+
+/// A client `Service` that reconnects to the server whenever its connection
+/// is lost.
+///
+/// Connections are established with a `TcpClient`, using its configuration.
+/// Once the task managing a connection ends, for example because the server
+/// closed it, a new connection is established after a delay. The delay
+/// doubles with every failed connection attempt, and with every connection
+/// that is closed before it has stayed up for as long as the delay, up to a
+/// maximum.
+///
+/// Requests made while there is no connection are queued, and sent once a
+/// connection has been established. See `fail_fast` for failing them instead.
+///
+/// Reconnecting runs in a background task on the event loop given to `new`,
+/// which ends once the client and all of its clones have been dropped.
+pub struct ReconnectingClient<Kind, P> where P: BindClient<Kind, ClientStream> {
+    shared: Rc<RefCell<Shared<Kind, P>>>,
+}
+
+/// The state of the connection behind a `ReconnectingClient`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Requests are sent to the server.
+    Connected,
+    /// A connection is being established.
+    Connecting,
+    /// The connection was lost or the last connection attempt failed, and
+    /// the next one is waiting for the backoff delay to elapse.
+    Backoff,
+}
+
+/// Response future returned from a `ReconnectingClient`.
+pub struct ReconnectingResponse<F> where F: Future {
+    state: Response<F>,
+}
+
+enum Response<F> where F: Future {
+    Sent(F),
+    Queued(oneshot::Receiver<F>),
+    Failed(Option<F::Error>),
+}
+
+type Queued<S> = (<S as Service>::Request, oneshot::Sender<<S as Service>::Future>);
+
+struct Shared<Kind, P> where P: BindClient<Kind, ClientStream> {
+    client: Option<P::BindClient>,
+    state: ConnectionState,
+    // Requests waiting for a connection
+    queue: VecDeque<Queued<P::BindClient>>,
+    fail_fast: bool,
+    min_backoff: Duration,
+    max_backoff: Duration,
+}
+
+/// Establishes connections, and waits for them to be closed.
+struct Reconnect<Kind, P> where P: BindClient<Kind, ClientStream> {
+    shared: Weak<RefCell<Shared<Kind, P>>>,
+    client: TcpClient<Kind, P>,
+    addrs: Vec<SocketAddr>,
+    handle: Handle,
+    step: Step,
+    // The delay before the next connection attempt, should the current one
+    // fail or the current connection be closed
+    backoff: Option<Duration>,
+}
+
+enum Step {
+    Connecting(ConnectSocket),
+    Connected(oneshot::Receiver<()>, Instant),
+    Backoff(Timeout),
+}
+
+enum Next {
+    Connected(TcpStream),
+    Closed(Instant),
+    Reconnect,
+    BackOff,
+}
+
+impl<Kind, P> ReconnectingClient<Kind, P> where
+    Kind: 'static,
+    P: BindClient<Kind, ClientStream>,
+    P::ServiceError: From<io::Error>,
+{
+    /// Create a client that connects to `addrs` with `client`.
+    ///
+    /// The addresses are tried as described for `TcpClient::connect_any`.
+    /// The first connection attempt starts right away, on the event loop
+    /// behind `handle`.
+    pub fn new(client: TcpClient<Kind, P>,
+               addrs: &[SocketAddr],
+               handle: &Handle) -> ReconnectingClient<Kind, P> {
+        let shared = Rc::new(RefCell::new(Shared {
+            client: None,
+            state: ConnectionState::Connecting,
+            queue: VecDeque::new(),
+            fail_fast: false,
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+        }));
+
+        let socket = client.connect_socket(addrs, handle);
+
+        handle.spawn(Reconnect {
+            shared: Rc::downgrade(&shared),
+            client: client,
+            addrs: addrs.to_vec(),
+            handle: handle.clone(),
+            step: Step::Connecting(socket),
+            backoff: None,
+        });
+
+        ReconnectingClient { shared: shared }
+    }
+
+    /// Set the delay before retrying after the first failed connection
+    /// attempt, and the maximum the delay grows to after further failures.
+    ///
+    /// Defaults to 100 milliseconds and 30 seconds.
+    pub fn backoff(&mut self, min: Duration, max: Duration) {
+        assert!(min <= max);
+
+        let mut shared = self.shared.borrow_mut();
+        shared.min_backoff = min;
+        shared.max_backoff = max;
+    }
+
+    /// Set whether requests made while there is no connection fail right
+    /// away, with an error of kind `io::ErrorKind::NotConnected`.
+    ///
+    /// Defaults to `false`, in which case the requests are queued until a
+    /// connection has been established.
+    pub fn fail_fast(&mut self, fail_fast: bool) {
+        self.shared.borrow_mut().fail_fast = fail_fast;
+    }
+
+    /// Returns the current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.shared.borrow().state
+    }
+}
+
+impl<Kind, P> Clone for ReconnectingClient<Kind, P> where
+    P: BindClient<Kind, ClientStream>
+{
+    fn clone(&self) -> Self {
+        ReconnectingClient { shared: self.shared.clone() }
+    }
+}
+
+impl<Kind, P> Service for ReconnectingClient<Kind, P> where
+    P: BindClient<Kind, ClientStream>,
+    P::ServiceError: From<io::Error>,
+{
+    type Request = P::ServiceRequest;
+    type Response = P::ServiceResponse;
+    type Error = P::ServiceError;
+    type Future = ReconnectingResponse<<P::BindClient as Service>::Future>;
+
+    fn call(&self, request: P::ServiceRequest) -> Self::Future {
+        let mut shared = self.shared.borrow_mut();
+
+        let state = if let Some(ref client) = shared.client {
+            Response::Sent(client.call(request))
+        } else if shared.fail_fast {
+            let err = io::Error::new(io::ErrorKind::NotConnected, "not connected");
+            Response::Failed(Some(err.into()))
+        } else {
+            let (tx, rx) = oneshot::channel();
+            shared.queue.push_back((request, tx));
+            Response::Queued(rx)
+        };
+
+        ReconnectingResponse { state: state }
+    }
+}
+
+impl<Kind, P> Reconnect<Kind, P> where P: BindClient<Kind, ClientStream> {
+    fn connected(&mut self, shared: &mut Shared<Kind, P>, socket: TcpStream) {
+        let (stream, rx) = ClientStream::new(socket);
+
+        let client = self.client.protocol().bind_client(&self.handle, stream);
+
+        for (request, tx) in shared.queue.drain(..) {
+            // The response future has been dropped if this fails
+            drop(tx.send(client.call(request)));
+        }
+
+        shared.client = Some(client);
+        shared.state = ConnectionState::Connected;
+
+        self.step = Step::Connected(rx, Instant::now());
+    }
+
+    fn closed(&mut self, shared: &mut Shared<Kind, P>, connected_at: Instant) -> io::Result<()> {
+        // A connection that is closed right away counts as a failed attempt,
+        // so that a server dropping every connection is not hammered
+        if let Some(delay) = self.backoff {
+            if connected_at.elapsed() >= delay {
+                self.backoff = None;
+            }
+        }
+
+        shared.client = None;
+        self.back_off(shared)
+    }
+
+    fn reconnect(&mut self, shared: &mut Shared<Kind, P>) {
+        let socket = self.client.connect_socket(&self.addrs, &self.handle);
+
+        shared.state = ConnectionState::Connecting;
+        self.step = Step::Connecting(socket);
+    }
+
+    fn back_off(&mut self, shared: &mut Shared<Kind, P>) -> io::Result<()> {
+        let delay = self.backoff.unwrap_or(shared.min_backoff);
+        self.backoff = Some(cmp::min(delay * 2, shared.max_backoff));
+
+        shared.state = ConnectionState::Backoff;
+        self.step = Step::Backoff(try!(Timeout::new(delay, &self.handle)));
+
+        if shared.fail_fast {
+            // Requests queued before `fail_fast` was set; dropping them
+            // fails their response futures
+            shared.queue.clear();
+        }
+
+        Ok(())
+    }
+}
+
+impl<Kind, P> Future for Reconnect<Kind, P> where P: BindClient<Kind, ClientStream> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            // Stop once every client handle has been dropped
+            let shared = match self.shared.upgrade() {
+                Some(shared) => shared,
+                None => return Ok(Async::Ready(())),
+            };
+            let mut shared = shared.borrow_mut();
+
+            let next = match self.step {
+                Step::Connecting(ref mut socket) => {
+                    match socket.poll() {
+                        Ok(Async::Ready((socket, addr))) => {
+                            debug!("connected; addr={}", addr);
+                            Next::Connected(socket)
+                        }
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(e) => {
+                            debug!("failed to connect; err={}", e);
+                            Next::BackOff
+                        }
+                    }
+                }
+                Step::Connected(ref mut closed, connected_at) => {
+                    match closed.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        _ => {
+                            debug!("connection closed; reconnecting");
+                            Next::Closed(connected_at)
+                        }
+                    }
+                }
+                Step::Backoff(ref mut timeout) => {
+                    match timeout.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        _ => Next::Reconnect,
+                    }
+                }
+            };
+
+            let res = match next {
+                Next::Connected(socket) => Ok(self.connected(&mut shared, socket)),
+                Next::Closed(connected_at) => self.closed(&mut shared, connected_at),
+                Next::Reconnect => Ok(self.reconnect(&mut shared)),
+                Next::BackOff => self.back_off(&mut shared),
+            };
+
+            if let Err(e) = res {
+                error!("failed to set up reconnect timer; err={}", e);
+                return Err(());
+            }
+        }
+    }
+}
+
+impl<F> Future for ReconnectingResponse<F> where
+    F: Future,
+    F::Error: From<io::Error>,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let future = match self.state {
+            Response::Sent(ref mut future) => return future.poll(),
+            Response::Queued(ref mut rx) => {
+                match rx.poll() {
+                    Ok(Async::Ready(future)) => future,
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(_) => {
+                        let e = io::Error::new(io::ErrorKind::NotConnected, "not connected");
+                        return Err(e.into());
+                    }
+                }
+            }
+            Response::Failed(ref mut err) => {
+                return Err(err.take().expect("cannot poll ReconnectingResponse twice"));
+            }
+        };
+
+        self.state = Response::Sent(future);
+        self.poll()
+    }
+}
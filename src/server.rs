@@ -0,0 +1,748 @@
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::marker::PhantomData;
+use std::net;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use BindServer;
+use accept::{self, Acceptor, Limit, Listen, Slot};
+use futures::{future, Future, Poll, Async};
+use futures::sync::oneshot;
+use futures::task::{self, Task};
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_service::{Service, NewService};
+
+/// Settings shared by every worker of a server.
+#[derive(Clone)]
+pub struct Config {
+    pub accept: accept::Config,
+    pub max_idle_time: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+}
+
+/// A listening socket that is yet to be registered with the event loop of a
+/// worker.
+pub trait StdListener: Send + 'static {
+    /// The listener once registered with an event loop.
+    type Listener: Listen<Conn = Self::Conn>;
+
+    /// A connection accepted by the listener.
+    type Conn: Conn;
+
+    fn register(self, handle: &Handle) -> io::Result<Self::Listener>;
+}
+
+/// A connection accepted by a server.
+pub trait Conn {
+    /// The I/O object the protocol is bound to.
+    type Io: 'static;
+
+    /// A handle on the socket, used to shut the connection down.
+    type Socket: Socket;
+
+    /// Details about the connection, handed to the service factory.
+    type Info;
+
+    /// Registers the connection with the event loop.
+    fn register(self, handle: &Handle) -> io::Result<(Self::Io, Self::Socket, Self::Info)>;
+}
+
+/// A handle on the socket of a connection that is being served.
+pub trait Socket {
+    fn shutdown(&self, how: net::Shutdown) -> io::Result<()>;
+
+    /// Returns whether the send buffer of the socket has room for more data.
+    fn is_writable(&self) -> io::Result<bool>;
+}
+
+/// Makes the service for each connection accepted by a worker.
+pub trait MakeService<I> {
+    type Service: Service;
+
+    fn make_service(&self, info: &I) -> io::Result<Self::Service>;
+}
+
+/// Makes services out of a `NewService`, ignoring the connection info.
+pub struct FromNewService<S>(pub S);
+
+/// Makes services out of a closure taking the connection info.
+pub struct WithInfo<F>(pub Arc<F>);
+
+/// The worker threads of a server running in the background.
+pub struct Workers {
+    workers: Vec<Worker>,
+}
+
+struct Worker {
+    thread: thread::JoinHandle<()>,
+    shutdown: oneshot::Sender<Shutdown>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Shutdown {
+    Now,
+    Graceful(Instant),
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            accept: accept::Config::new(),
+            max_idle_time: None,
+            max_lifetime: None,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+
+    fn has_timeouts(&self) -> bool {
+        self.max_idle_time.is_some() ||
+            self.max_lifetime.is_some() ||
+            self.read_timeout.is_some() ||
+            self.write_timeout.is_some()
+    }
+}
+
+impl Workers {
+    /// Starts a worker thread for each listener.
+    pub fn start<Kind, P, T, F, M>(binder: &Arc<P>,
+                                   config: &Config,
+                                   listeners: Vec<T>,
+                                   make_service: F) -> io::Result<Workers>
+        where P: BindServer<Kind, <T::Conn as Conn>::Io> + Send + Sync,
+              T: StdListener,
+              F: Fn(&Handle) -> M + Send + Sync + 'static,
+              M: MakeService<<T::Conn as Conn>::Info>,
+              M::Service: Service<Request = P::ServiceRequest,
+                                  Response = P::ServiceResponse,
+                                  Error = P::ServiceError> + 'static,
+    {
+        let make_service = Arc::new(make_service);
+        let mut workers = Workers { workers: Vec::with_capacity(listeners.len()) };
+
+        // Shared by all workers
+        let limit = config.accept.global_limit();
+
+        for (i, listener) in listeners.into_iter().enumerate() {
+            let binder = binder.clone();
+            let config = config.clone();
+            let limit = limit.clone();
+            let make_service = make_service.clone();
+            let (tx, rx) = oneshot::channel();
+
+            let thread = thread::Builder::new().name(format!("worker{}", i)).spawn(move || {
+                serve(binder, config, limit, listener, &*make_service, rx)
+            });
+
+            match thread {
+                Ok(thread) => {
+                    workers.workers.push(Worker {
+                        thread: thread,
+                        shutdown: tx,
+                    });
+                }
+                Err(e) => {
+                    workers.shutdown();
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(workers)
+    }
+
+    /// Closes all connections right away. Blocks until every worker thread
+    /// has exited.
+    pub fn shutdown(self) {
+        self.signal(Shutdown::Now)
+    }
+
+    /// Stops accepting connections and reading requests, and closes the
+    /// connections that are still open once `timeout` has elapsed. Blocks
+    /// until every worker thread has exited.
+    pub fn shutdown_graceful(self, timeout: Duration) {
+        self.signal(Shutdown::Graceful(Instant::now() + timeout))
+    }
+
+    /// Blocks until every worker thread has exited.
+    pub fn wait(self) {
+        for worker in self.workers {
+            worker.thread.join().unwrap();
+        }
+    }
+
+    fn signal(self, shutdown: Shutdown) {
+        let threads = self.workers.into_iter().map(|worker| {
+            worker.shutdown.complete(shutdown);
+            worker.thread
+        }).collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// Runs a server on an existing event loop, for as long as the event loop
+/// runs.
+pub fn spawn<Kind, P, T, M>(binder: Arc<P>,
+                            config: Config,
+                            listener: T,
+                            make_service: M,
+                            handle: &Handle) -> io::Result<()>
+    where P: BindServer<Kind, <T::Conn as Conn>::Io>,
+          T: StdListener,
+          M: MakeService<<T::Conn as Conn>::Info> + 'static,
+          M::Service: Service<Request = P::ServiceRequest,
+                              Response = P::ServiceResponse,
+                              Error = P::ServiceError> + 'static,
+          Kind: 'static,
+{
+    let listener = try!(listener.register(handle));
+    let limit = config.accept.global_limit();
+    let server: Server<Kind, P, T, M> = Server::new(binder, config, limit, listener, make_service, handle);
+
+    handle.spawn(server.map_err(|e| {
+        error!("server error: {}", e);
+    }));
+
+    Ok(())
+}
+
+fn serve<Kind, P, T, F, M>(binder: Arc<P>,
+                           config: Config,
+                           limit: Arc<Limit>,
+                           listener: T,
+                           make_service: &F,
+                           shutdown: oneshot::Receiver<Shutdown>)
+    where P: BindServer<Kind, <T::Conn as Conn>::Io>,
+          T: StdListener,
+          F: Fn(&Handle) -> M,
+          M: MakeService<<T::Conn as Conn>::Info>,
+          M::Service: Service<Request = P::ServiceRequest,
+                              Response = P::ServiceResponse,
+                              Error = P::ServiceError> + 'static,
+{
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let make_service = make_service(&handle);
+    let listener = listener.register(&handle).unwrap();
+    let server: Server<Kind, P, T, M> = Server::new(binder, config, limit, listener, make_service, &handle);
+    let connections = server.connections.clone();
+
+    // A dropped handle leaves the server running
+    let shutdown = shutdown.or_else(|_| future::empty());
+
+    // Run until a shutdown is requested. This drops the listener, so no more
+    // connections are accepted after this point.
+    let (shutdown, _) = core.run(server.map(|()| None).select(shutdown.map(Some)))
+        .map_err(|(e, _)| e)
+        .unwrap();
+
+    if let Some(Shutdown::Graceful(deadline)) = shutdown {
+        connections.shutdown_read();
+
+        let timeout = Timeout::new_at(deadline, &core.handle()).unwrap();
+        drop(core.run(connections.drain().select(timeout)));
+    }
+
+    // Dropping the core closes any connections that are still open
+}
+
+/// Accepts connections and binds the service to each of them.
+struct Server<Kind, P, T: StdListener, M> {
+    _kind: PhantomData<Kind>,
+    binder: Arc<P>,
+    config: Config,
+    acceptor: Acceptor<T::Listener>,
+    make_service: M,
+    connections: Connections<<T::Conn as Conn>::Socket>,
+    handle: Handle,
+}
+
+impl<Kind, P, T, M> Server<Kind, P, T, M> where
+    P: BindServer<Kind, <T::Conn as Conn>::Io>,
+    T: StdListener,
+    M: MakeService<<T::Conn as Conn>::Info>,
+    M::Service: Service<Request = P::ServiceRequest,
+                        Response = P::ServiceResponse,
+                        Error = P::ServiceError> + 'static,
+{
+    fn new(binder: Arc<P>,
+           config: Config,
+           limit: Arc<Limit>,
+           listener: T::Listener,
+           make_service: M,
+           handle: &Handle) -> Server<Kind, P, T, M> {
+        let acceptor = Acceptor::new(listener, config.accept.clone(), limit, handle);
+
+        Server {
+            _kind: PhantomData,
+            binder: binder,
+            config: config,
+            acceptor: acceptor,
+            make_service: make_service,
+            connections: Connections::new(),
+            handle: handle.clone(),
+        }
+    }
+
+    fn bind(&self, conn: T::Conn, slot: Slot) -> io::Result<()> {
+        let (io, socket, info) = try!(conn.register(&self.handle));
+
+        // Track the connection so that it can be wound down on shutdown
+        let mut connection = self.connections.insert(socket, slot);
+
+        // Create the service
+        let service = try!(self.make_service.make_service(&info));
+
+        if self.config.has_timeouts() {
+            let activity = Rc::new(Activity::new());
+            let watchdog = try!(Watchdog::new(&connection, &activity, &self.config, &self.handle));
+            self.handle.spawn(watchdog);
+            connection.activity = Some(activity);
+        }
+
+        let service = Tracked {
+            inner: service,
+            connection: connection,
+        };
+
+        // Bind it!
+        self.binder.bind_server(&self.handle, io, service);
+
+        Ok(())
+    }
+}
+
+impl<Kind, P, T, M> Future for Server<Kind, P, T, M> where
+    P: BindServer<Kind, <T::Conn as Conn>::Io>,
+    T: StdListener,
+    M: MakeService<<T::Conn as Conn>::Info>,
+    M::Service: Service<Request = P::ServiceRequest,
+                        Response = P::ServiceResponse,
+                        Error = P::ServiceError> + 'static,
+{
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let (conn, slot) = try_ready!(self.acceptor.poll_next());
+
+            if let Err(e) = self.bind(conn, slot) {
+                // The socket has been dropped, which closes the connection
+                error!("failed to set up connection; err={}", e);
+            }
+        }
+    }
+}
+
+/// The set of connections open on a worker.
+struct Connections<S> {
+    inner: Rc<RefCell<ConnectionsInner<S>>>,
+}
+
+struct ConnectionsInner<S> {
+    // Handles on the connection sockets, used to shut them down
+    sockets: HashMap<usize, S>,
+    next_id: usize,
+    // Task waiting for the set to become empty
+    drain: Option<Task>,
+}
+
+/// Membership of a single connection in `Connections`.
+///
+/// The connection is removed from the set when this value is dropped, which
+/// happens once the task serving the connection completes.
+struct Connection<S: Socket> {
+    id: usize,
+    connections: Connections<S>,
+    // Set when the connection has timeouts to enforce
+    activity: Option<Rc<Activity>>,
+    _slot: Slot,
+}
+
+/// A future that completes once all connections have been closed.
+struct Drain<S> {
+    connections: Connections<S>,
+}
+
+/// Ties a `Connection` to the service bound to it.
+struct Tracked<T, S: Socket> {
+    inner: T,
+    // Dropped along with the service, once the task serving the connection
+    // completes
+    connection: Connection<S>,
+}
+
+/// A response future of a `Tracked` service.
+struct TrackedFuture<F> {
+    inner: F,
+    activity: Option<Rc<Activity>>,
+}
+
+/// Tracks the requests and responses moving on a connection, for the
+/// connection timeouts.
+///
+/// The protocol owns the I/O object, so requests are counted as they reach
+/// the service, and responses as they become ready to be written.
+struct Activity {
+    // When a request was last read from the connection
+    last_read: Cell<Instant>,
+    // When a response was last handed to the connection
+    last_write: Cell<Instant>,
+    // Set once the task serving the connection completes
+    closed: Cell<bool>,
+    // Watchdog enforcing the connection timeouts
+    task: RefCell<Option<Task>>,
+}
+
+/// Enforces the timeouts and lifetime of a connection.
+struct Watchdog<S> {
+    id: usize,
+    connections: Connections<S>,
+    activity: Rc<Activity>,
+    max_idle_time: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    // Since when the send buffer of the socket has been full, if it is
+    write_blocked: Option<Instant>,
+    // Fires when the timeouts should be checked again
+    timeout: Timeout,
+    max_lifetime: Option<Timeout>,
+}
+
+impl<S: NewService, I> MakeService<I> for FromNewService<S> {
+    type Service = S::Instance;
+
+    fn make_service(&self, _info: &I) -> io::Result<S::Instance> {
+        self.0.new_service()
+    }
+}
+
+impl<F, S, I> MakeService<I> for WithInfo<F> where
+    F: Fn(&I) -> io::Result<S>,
+    S: Service,
+{
+    type Service = S;
+
+    fn make_service(&self, info: &I) -> io::Result<S> {
+        (self.0)(info)
+    }
+}
+
+impl<S: Socket> Connections<S> {
+    fn new() -> Connections<S> {
+        let inner = ConnectionsInner {
+            sockets: HashMap::new(),
+            next_id: 0,
+            drain: None,
+        };
+
+        Connections { inner: Rc::new(RefCell::new(inner)) }
+    }
+
+    fn insert(&self, socket: S, slot: Slot) -> Connection<S> {
+        let mut inner = self.inner.borrow_mut();
+        let id = inner.next_id;
+
+        inner.next_id += 1;
+        inner.sockets.insert(id, socket);
+
+        Connection {
+            id: id,
+            connections: self.clone(),
+            activity: None,
+            _slot: slot,
+        }
+    }
+
+    /// Shut down the read half of every connection.
+    ///
+    /// The dispatcher sees the end of the stream, stops reading requests and
+    /// completes once the requests that are in flight have been answered.
+    fn shutdown_read(&self) {
+        for socket in self.inner.borrow().sockets.values() {
+            shutdown(socket, net::Shutdown::Read);
+        }
+    }
+
+    /// Shut down a single connection, if it is still open.
+    fn shutdown(&self, id: usize, how: net::Shutdown) {
+        if let Some(socket) = self.inner.borrow().sockets.get(&id) {
+            shutdown(socket, how);
+        }
+    }
+
+    /// Returns whether the socket of a connection has room in its send
+    /// buffer. A connection that is no longer open has nothing to write.
+    fn is_writable(&self, id: usize) -> io::Result<bool> {
+        match self.inner.borrow().sockets.get(&id) {
+            Some(socket) => socket.is_writable(),
+            None => Ok(true),
+        }
+    }
+
+    fn drain(&self) -> Drain<S> {
+        Drain { connections: self.clone() }
+    }
+}
+
+impl<S> Clone for Connections<S> {
+    fn clone(&self) -> Connections<S> {
+        Connections { inner: self.inner.clone() }
+    }
+}
+
+impl<S: Socket> Drop for Connection<S> {
+    fn drop(&mut self) {
+        if let Some(ref activity) = self.activity {
+            activity.close();
+        }
+
+        let mut inner = self.connections.inner.borrow_mut();
+        inner.sockets.remove(&self.id);
+
+        if inner.sockets.is_empty() {
+            if let Some(task) = inner.drain.take() {
+                task.unpark();
+            }
+        }
+    }
+}
+
+impl<S> Future for Drain<S> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let mut inner = self.connections.inner.borrow_mut();
+
+        if inner.sockets.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+
+        inner.drain = Some(task::park());
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T: Service, S: Socket> Service for Tracked<T, S> {
+    type Request = T::Request;
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = TrackedFuture<T::Future>;
+
+    fn call(&self, req: T::Request) -> TrackedFuture<T::Future> {
+        let activity = self.connection.activity.clone();
+
+        if let Some(ref activity) = activity {
+            activity.last_read.set(Instant::now());
+        }
+
+        TrackedFuture {
+            inner: self.inner.call(req),
+            activity: activity,
+        }
+    }
+}
+
+impl<F: Future> Future for TrackedFuture<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let res = self.inner.poll();
+
+        match res {
+            Ok(Async::NotReady) => {}
+            _ => {
+                if let Some(ref activity) = self.activity {
+                    activity.last_write.set(Instant::now());
+                }
+            }
+        }
+
+        res
+    }
+}
+
+impl Activity {
+    fn new() -> Activity {
+        let now = Instant::now();
+
+        Activity {
+            last_read: Cell::new(now),
+            last_write: Cell::new(now),
+            closed: Cell::new(false),
+            task: RefCell::new(None),
+        }
+    }
+
+    fn close(&self) {
+        self.closed.set(true);
+        self.notify();
+    }
+
+    fn notify(&self) {
+        if let Some(task) = self.task.borrow_mut().take() {
+            task.unpark();
+        }
+    }
+}
+
+impl<S: Socket> Watchdog<S> {
+    fn new(connection: &Connection<S>,
+           activity: &Rc<Activity>,
+           config: &Config,
+           handle: &Handle) -> io::Result<Watchdog<S>> {
+        let max_lifetime = match config.max_lifetime {
+            Some(max) => Some(try!(Timeout::new(max, handle))),
+            None => None,
+        };
+
+        Ok(Watchdog {
+            id: connection.id,
+            connections: connection.connections.clone(),
+            activity: activity.clone(),
+            max_idle_time: config.max_idle_time,
+            read_timeout: config.read_timeout,
+            write_timeout: config.write_timeout,
+            write_blocked: None,
+            timeout: try!(Timeout::new_at(Instant::now(), handle)),
+            max_lifetime: max_lifetime,
+        })
+    }
+
+    /// Returns the earliest time at which one of the timeouts expires, along
+    /// with the name of that timeout.
+    fn deadline(&self) -> Option<(Instant, &'static str)> {
+        let activity = &self.activity;
+        let last_active = cmp::max(activity.last_read.get(), activity.last_write.get());
+
+        let deadlines = [
+            self.max_idle_time.map(|max| (last_active + max, "idle")),
+            self.read_timeout.map(|max| (activity.last_read.get() + max, "read timeout")),
+            self.write_timeout.and_then(|max| {
+                self.write_blocked.map(|since| (since + max, "write timeout"))
+            }),
+        ];
+
+        deadlines.iter().filter_map(|deadline| *deadline).min()
+    }
+
+    fn poll_timeouts(&mut self) -> Poll<&'static str, io::Error> {
+        loop {
+            let now = Instant::now();
+            let mut wake_at = None;
+
+            if let Some(max) = self.write_timeout {
+                // Writes are not seen from here, so check every now and then
+                // whether the socket still takes data
+                if try!(self.connections.is_writable(self.id)) {
+                    self.write_blocked = None;
+                } else if self.write_blocked.is_none() {
+                    self.write_blocked = Some(now);
+                }
+
+                wake_at = Some(now + max / 4);
+            }
+
+            if let Some((deadline, reason)) = self.deadline() {
+                if deadline <= now {
+                    return Ok(Async::Ready(reason));
+                }
+
+                wake_at = Some(wake_at.map_or(deadline, |at| cmp::min(at, deadline)));
+            }
+
+            match wake_at {
+                Some(at) => self.timeout.reset(at),
+                None => return Ok(Async::NotReady),
+            }
+
+            try_ready!(self.timeout.poll());
+        }
+    }
+
+    fn poll_lifetime(&mut self) -> Poll<(), io::Error> {
+        match self.max_lifetime {
+            Some(ref mut timeout) => timeout.poll(),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl<S: Socket> Future for Watchdog<S> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        if self.activity.closed.get() {
+            return Ok(Async::Ready(()));
+        }
+
+        *self.activity.task.borrow_mut() = Some(task::park());
+
+        match self.poll_timeouts() {
+            Ok(Async::NotReady) => {}
+            res => {
+                debug!("connection timed out; closing; res={:?}", res);
+                self.connections.shutdown(self.id, net::Shutdown::Both);
+                return Ok(Async::Ready(()));
+            }
+        }
+
+        match self.poll_lifetime() {
+            Ok(Async::NotReady) => {}
+            res => {
+                debug!("connection reached its max lifetime; closing; res={:?}", res);
+
+                // Let the requests that are in flight complete. Nothing is
+                // read from here on, so only the other timeouts apply.
+                self.connections.shutdown(self.id, net::Shutdown::Read);
+                self.max_lifetime = None;
+                self.read_timeout = None;
+
+                if self.max_idle_time.is_none() && self.write_timeout.is_none() {
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+fn shutdown<S: Socket>(socket: &S, how: net::Shutdown) {
+    if let Err(e) = socket.shutdown(how) {
+        debug!("failed to shut down connection; err={:?}", e);
+    }
+}
+
+/// Returns whether the send buffer of a socket has room for more data.
+#[cfg(unix)]
+pub fn is_writable<S: ::std::os::unix::io::AsRawFd>(socket: &S) -> io::Result<bool> {
+    use libc;
+
+    let mut fd = libc::pollfd {
+        fd: socket.as_raw_fd(),
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+
+    if unsafe { libc::poll(&mut fd, 1, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // A connection that failed has nothing left to write either
+    Ok(fd.revents & (libc::POLLOUT | libc::POLLERR | libc::POLLHUP) != 0)
+}
@@ -1,5 +1,5 @@
 use BindClient;
-use super::{RequestId, Multiplex};
+use super::{RequestId, Multiplex, MultiplexConfig};
 use super::lift::{LiftBind, LiftTransport};
 use simple::LiftProto;
 
@@ -7,9 +7,11 @@ use std::io;
 
 use streaming::{self, Message};
 use streaming::multiplex::StreamingMultiplex;
+use util::client_proxy::Response;
 use tokio_core::reactor::Handle;
 use tokio_service::Service;
 use futures::{stream, Stream, Sink, Future, IntoFuture, Poll};
+use std::time::Duration;
 
 type MyStream<E> = stream::Empty<(), E>;
 
@@ -30,12 +32,16 @@ pub trait ClientProto<T: 'static>: 'static {
 
     /// The message transport, which usually take `T` as a parameter.
     ///
+    /// Requests are written with `solo` set when they are one-way
+    /// notifications sent with `ClientService::notify`. Responses read with
+    /// `solo` set are dropped.
+    ///
     /// An easy way to build a transport is to use `tokio_core::io::Framed`
     /// together with a `Codec`; in that case, the transport type is
     /// `Framed<T, YourCodec>`. See the crate docs for an example.
     type Transport: 'static +
-        Stream<Item = (RequestId, Self::Response), Error = io::Error> +
-        Sink<SinkItem = (RequestId, Self::Request), SinkError = io::Error>;
+        Stream<Item = (RequestId, Self::Response, bool), Error = io::Error> +
+        Sink<SinkItem = (RequestId, Self::Request, bool), SinkError = io::Error>;
 
     /// A future for initializing a transport from an I/O object.
     ///
@@ -49,6 +55,23 @@ pub trait ClientProto<T: 'static>: 'static {
     /// together with a `Codec`; in that case, `bind_transport` is just
     /// `io.framed(YourCodec)`. See the crate docs for an example.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// How long to wait for the response to a request.
+    ///
+    /// Requests that take longer fail with an error of kind
+    /// `io::ErrorKind::TimedOut`. Returns `None` by default, in which case
+    /// requests never time out.
+    fn request_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// The limits of each connection, such as the number of requests that
+    /// can be in flight at once.
+    ///
+    /// Returns `MultiplexConfig::default()` by default.
+    fn multiplex_config(&self) -> MultiplexConfig {
+        MultiplexConfig::default()
+    }
 }
 
 impl<T: 'static, P: ClientProto<T>> BindClient<Multiplex, T> for P {
@@ -84,6 +107,14 @@ impl<T, P> streaming::multiplex::ClientProto<T> for LiftProto<P> where
     fn bind_transport(&self, io: T) -> Self::BindTransport {
         LiftBind::lift(ClientProto::bind_transport(self.lower(), io).into_future())
     }
+
+    fn request_timeout(&self) -> Option<Duration> {
+        ClientProto::request_timeout(self.lower())
+    }
+
+    fn multiplex_config(&self) -> MultiplexConfig {
+        ClientProto::multiplex_config(self.lower())
+    }
 }
 
 /// Client `Service` for simple multiplex protocols
@@ -91,6 +122,18 @@ pub struct ClientService<T, P> where T: 'static, P: ClientProto<T> {
     inner: <LiftProto<P> as BindClient<StreamingMultiplex<MyStream<io::Error>>, T>>::BindClient
 }
 
+impl<T, P> ClientService<T, P> where T: 'static, P: ClientProto<T> {
+    /// Send a one-way notification to the server.
+    ///
+    /// The request is written with `solo` set, and no response is expected
+    /// for it. It waits in the same queue as the other requests, see
+    /// `MultiplexConfig::max_queued`. The returned future completes once the
+    /// request has been written.
+    pub fn notify(&self, req: P::Request) -> Response<(), io::Error> {
+        self.inner.notify(Message::WithoutBody(req))
+    }
+}
+
 impl<T, P> Service for ClientService<T, P> where T: 'static, P: ClientProto<T> {
     type Request = P::Request;
     type Response = P::Response;
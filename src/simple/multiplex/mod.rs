@@ -9,7 +9,12 @@ pub use self::client::ClientService;
 mod server;
 pub use self::server::ServerProto;
 
+pub use streaming::multiplex::MultiplexConfig;
+
 /// Identifies a request / response thread
+///
+/// Transports carry each message as `(RequestId, message, solo)`, where
+/// `solo` is true for one-way notifications, which are not responded to.
 pub type RequestId = u64;
 
 /// A marker used to flag protocols as being multiplexed RPC.
@@ -39,20 +44,20 @@ mod lift {
 
     impl<T, InnerItem, E> Stream for LiftTransport<T, E> where
         E: 'static,
-        T: Stream<Item = (RequestId, InnerItem), Error = io::Error>,
+        T: Stream<Item = (RequestId, InnerItem, bool), Error = io::Error>,
     {
         type Item = Frame<InnerItem, (), E>;
         type Error = io::Error;
 
         fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
-            let (id, msg) = match try_ready!(self.0.poll()) {
+            let (id, msg, solo) = match try_ready!(self.0.poll()) {
                 Some(msg) => msg,
                 None => return Ok(None.into()),
             };
             Ok(Some(Frame::Message {
                 message: msg,
                 body: false,
-                solo: false,
+                solo: solo,
                 id: id,
             }).into())
         }
@@ -60,7 +65,7 @@ mod lift {
 
     impl<T, InnerSink, E> Sink for LiftTransport<T, E> where
         E: 'static,
-        T: Sink<SinkItem = (RequestId, InnerSink), SinkError = io::Error>
+        T: Sink<SinkItem = (RequestId, InnerSink, bool), SinkError = io::Error>
     {
         type SinkItem = Frame<InnerSink, (), E>;
         type SinkError = io::Error;
@@ -68,15 +73,15 @@ mod lift {
         fn start_send(&mut self, request: Self::SinkItem)
                       -> StartSend<Self::SinkItem, io::Error> {
             if let Frame::Message { message, id, body, solo } = request {
-                if !body && !solo {
-                    match try!(self.0.start_send((id, message))) {
+                if !body {
+                    match try!(self.0.start_send((id, message, solo))) {
                         AsyncSink::Ready => return Ok(AsyncSink::Ready),
-                        AsyncSink::NotReady((id, msg)) => {
+                        AsyncSink::NotReady((id, msg, solo)) => {
                             let msg = Frame::Message {
                                 message: msg,
                                 id: id,
                                 body: false,
-                                solo: false,
+                                solo: solo,
                             };
                             return Ok(AsyncSink::NotReady(msg))
                         }
@@ -94,8 +99,8 @@ mod lift {
     impl<T, InnerItem, InnerSink, E> Transport<()> for LiftTransport<T, E> where
         E: 'static,
         T: 'static,
-        T: Stream<Item = (RequestId, InnerItem), Error = io::Error>,
-        T: Sink<SinkItem = (RequestId, InnerSink), SinkError = io::Error>
+        T: Stream<Item = (RequestId, InnerItem, bool), Error = io::Error>,
+        T: Sink<SinkItem = (RequestId, InnerSink, bool), SinkError = io::Error>
     {}
 
     impl<A, F, E> LiftBind<A, F, E> {
@@ -2,7 +2,7 @@ use std::io;
 use std::marker;
 
 use BindServer;
-use super::{RequestId, Multiplex};
+use super::{RequestId, Multiplex, MultiplexConfig};
 use super::lift::{LiftBind, LiftTransport};
 use simple::LiftProto;
 
@@ -11,6 +11,7 @@ use streaming::multiplex::StreamingMultiplex;
 use tokio_core::reactor::Handle;
 use tokio_service::Service;
 use futures::{stream, Stream, Sink, Future, IntoFuture, Poll};
+use std::time::Duration;
 
 type MyStream<E> = stream::Empty<(), E>;
 
@@ -31,12 +32,17 @@ pub trait ServerProto<T: 'static>: 'static {
 
     /// The message transport, which usually take `T` as a parameter.
     ///
+    /// Requests read with `solo` set are one-way notifications, such as the
+    /// ones sent with `ClientService::notify`. They are handled by the
+    /// service like any other request, but the response is dropped instead of
+    /// being written. Responses are always written with `solo` unset.
+    ///
     /// An easy way to build a transport is to use `tokio_core::io::Framed`
     /// together with a `Codec`; in that case, the transport type is
     /// `Framed<T, YourCodec>`. See the crate docs for an example.
     type Transport: 'static +
-        Stream<Item = (RequestId, Self::Request), Error = io::Error> +
-        Sink<SinkItem = (RequestId, Self::Response), SinkError = io::Error>;
+        Stream<Item = (RequestId, Self::Request, bool), Error = io::Error> +
+        Sink<SinkItem = (RequestId, Self::Response, bool), SinkError = io::Error>;
 
     /// A future for initializing a transport from an I/O object.
     ///
@@ -50,6 +56,22 @@ pub trait ServerProto<T: 'static>: 'static {
     /// together with a `Codec`; in that case, `bind_transport` is just
     /// `io.framed(YourCodec)`. See the crate docs for an example.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// How long the service may take to respond to a request.
+    ///
+    /// Requests that take longer are answered with an error instead. Returns
+    /// `None` by default, in which case requests never time out.
+    fn request_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// The limits of each connection, such as the number of requests that
+    /// can be in flight at once.
+    ///
+    /// Returns `MultiplexConfig::default()` by default.
+    fn multiplex_config(&self) -> MultiplexConfig {
+        MultiplexConfig::default()
+    }
 }
 
 impl<T: 'static, P: ServerProto<T>> BindServer<Multiplex, T> for P {
@@ -85,6 +107,14 @@ impl<T, P> streaming::multiplex::ServerProto<T> for LiftProto<P> where
     fn bind_transport(&self, io: T) -> Self::BindTransport {
         LiftBind::lift(ServerProto::bind_transport(self.lower(), io).into_future())
     }
+
+    fn request_timeout(&self) -> Option<Duration> {
+        ServerProto::request_timeout(self.lower())
+    }
+
+    fn multiplex_config(&self) -> MultiplexConfig {
+        ServerProto::multiplex_config(self.lower())
+    }
 }
 
 struct LiftService<S>(S);
@@ -9,6 +9,7 @@ use tokio_core::reactor::Handle;
 use tokio_service::Service;
 use futures::{stream, Stream, Sink, Future, Poll, IntoFuture};
 use std::io;
+use std::time::Duration;
 
 type MyStream<E> = stream::Empty<(), E>;
 
@@ -48,6 +49,15 @@ pub trait ClientProto<T: 'static>: 'static {
     /// together with a `Codec`; in that case, `bind_transport` is just
     /// `io.framed(YourCodec)`. See the crate docs for an example.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// How long to wait for the response to a request.
+    ///
+    /// Requests that take longer fail with an error of kind
+    /// `io::ErrorKind::TimedOut`. Returns `None` by default, in which case
+    /// requests never time out.
+    fn request_timeout(&self) -> Option<Duration> {
+        None
+    }
 }
 
 impl<T: 'static, P: ClientProto<T>> BindClient<Pipeline, T> for P {
@@ -83,6 +93,10 @@ impl<T, P> streaming::pipeline::ClientProto<T> for LiftProto<P> where
     fn bind_transport(&self, io: T) -> Self::BindTransport {
         LiftBind::lift(ClientProto::bind_transport(self.lower(), io).into_future())
     }
+
+    fn request_timeout(&self) -> Option<Duration> {
+        ClientProto::request_timeout(self.lower())
+    }
 }
 
 /// Client `Service` for simple pipeline protocols
@@ -11,6 +11,7 @@ use streaming::pipeline::StreamingPipeline;
 use tokio_core::reactor::Handle;
 use tokio_service::Service;
 use futures::{stream, Stream, Sink, Future, IntoFuture, Poll};
+use std::time::Duration;
 
 type MyStream<E> = stream::Empty<(), E>;
 
@@ -50,6 +51,14 @@ pub trait ServerProto<T: 'static>: 'static {
     /// together with a `Codec`; in that case, `bind_transport` is just
     /// `io.framed(YourCodec)`. See the crate docs for an example.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// How long the service may take to respond to a request.
+    ///
+    /// Requests that take longer are answered with an error instead. Returns
+    /// `None` by default, in which case requests never time out.
+    fn request_timeout(&self) -> Option<Duration> {
+        None
+    }
 }
 
 impl<T: 'static, P: ServerProto<T>> BindServer<Pipeline, T> for P {
@@ -85,6 +94,10 @@ impl<T, P> streaming::pipeline::ServerProto<T> for LiftProto<P> where
     fn bind_transport(&self, io: T) -> Self::BindTransport {
         LiftBind::lift(ServerProto::bind_transport(self.lower(), io).into_future())
     }
+
+    fn request_timeout(&self) -> Option<Duration> {
+        ServerProto::request_timeout(self.lower())
+    }
 }
 
 struct LiftService<S>(S);
@@ -9,7 +9,8 @@ use streaming::{Message, Body};
 use futures::sync::mpsc;
 use futures::{Future, Poll, Async, Stream, Sink, AsyncSink, StartSend};
 use std::collections::hash_map::Entry;
-use std::collections::{HashMap, VecDeque};
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
 use super::frame_buf::{FrameBuf, FrameDeque};
 use super::{Frame, RequestId, Transport};
@@ -25,15 +26,73 @@ use buffer_one::BufferOne;
  *    * What happens if there are in-flight *in* bodies
  *    * What happens if the out message is buffered?
  * - [BUG] Can only poll from body sender FutureSender in `flush`
- * - Move constants to configuration settings
  *
  */
 
-/// The max number of buffered frames that the connection can support. Once
-/// this number is reached.
+/// Limits of a multiplexed connection.
 ///
-/// See module docs for more detail
-const MAX_BUFFERED_FRAMES: usize = 128;
+/// Used with `Multiplex::with_config`, and returned by the
+/// `multiplex_config` method of the multiplexed `ServerProto` and
+/// `ClientProto` traits.
+#[derive(Debug, Clone)]
+pub struct MultiplexConfig {
+    /// The number of requests that can be in flight at once.
+    ///
+    /// The multiplexer itself does not enforce this limit: the `Dispatch` is
+    /// expected to report itself as not ready once it is reached. Defaults
+    /// to 32.
+    pub max_in_flight: usize,
+
+    /// The number of requests a client queues up while `max_in_flight`
+    /// requests are in flight.
+    ///
+    /// Once the queue is full, the client is no longer ready, and calling it
+    /// fails with an error of kind `io::ErrorKind::WouldBlock`; see
+    /// `ClientProxy::poll_ready` and `ClientProxy::try_call`. The queue holds
+    /// one extra request for every clone of the client. Notifications sent
+    /// with `ClientProxy::notify` share the queue. Servers ignore this
+    /// setting. Defaults to `None`, which leaves the queue unbounded.
+    pub max_queued: Option<usize>,
+
+    /// The number of frames the connection buffers while the consuming ends
+    /// are not ready for them.
+    ///
+    /// Once the limit is reached, no further frames are read from the
+    /// transport until buffered ones have been consumed. Defaults to 128.
+    pub max_buffered_frames: usize,
+
+    /// The number of body chunks buffered for a single exchange while the
+    /// consumer of the body stream is not ready for them.
+    ///
+    /// The connection fails once a peer sends more chunks than that. Defaults
+    /// to 128.
+    pub max_buffered_chunks: usize,
+
+    /// The number of body chunks that can be queued in a body stream, in
+    /// addition to the one every stream holds, before chunks are buffered by
+    /// the connection. Defaults to 0.
+    pub body_channel_depth: usize,
+
+    /// The size of the flow control window of each body stream, in chunks.
+    ///
+    /// When set, the peer may only send as many body chunks for an exchange
+    /// as its window allows, or the connection fails. The window is
+    /// replenished through `Transport::send_window_update` as the chunks are
+    /// handed to the body stream. In the other direction, body chunks are
+    /// only written as long as the windows granted by the peer through
+    /// `Transport::poll_window_update` allow; this includes the end of the
+    /// stream. Defaults to `None`, which disables flow control.
+    pub window: Option<usize>,
+
+    /// The number of canceled exchanges the connection keeps track of, so
+    /// that the frames the peer still sends for them are dropped.
+    ///
+    /// Exchanges are forgotten once their last frame has been read, or, for
+    /// peers that never finish them, once more recent exchanges have been
+    /// canceled. Late frames for forgotten exchanges are handled like frames
+    /// for unknown ones. Defaults to 1024.
+    pub max_canceled: usize,
+}
 
 /// Task that drives multiplexed protocols
 ///
@@ -50,6 +109,9 @@ pub struct Multiplex<T> where T: Dispatch {
     // True when blocked on dispatch
     blocked_on_dispatch: bool,
 
+    // True when reading is blocked on a full frame buffer
+    blocked_on_frame_buf: bool,
+
     // True when blocked on flush
     blocked_on_flush: WriteState,
 
@@ -65,13 +127,28 @@ pub struct Multiplex<T> where T: Dispatch {
     // RequestIds of exchanges that have not yet been dispatched
     dispatch_deque: VecDeque<RequestId>,
 
+    // Exchanges that were canceled before the frames in the other direction
+    // were read in full. These frames are dropped.
+    canceled: Canceled,
+
     // Storage for buffered frames
     frame_buf: FrameBuf<Option<Result<T::BodyOut, T::Error>>>,
 
+    config: MultiplexConfig,
+
     // Temporary storage for RequestIds...
     scratch: Vec<RequestId>,
 }
 
+/// The most recently canceled exchanges.
+struct Canceled {
+    ids: HashSet<RequestId>,
+    // In the order the exchanges were canceled. May also hold exchanges that
+    // have been forgotten since.
+    order: VecDeque<RequestId>,
+    max: usize,
+}
+
 struct DispatchSink<T> {
     inner: T,
 }
@@ -109,8 +186,24 @@ struct Exchange<T: Dispatch> {
     // chunks must be dispatched first.
     out_is_ready: bool,
 
+    // True when the receiving end dropped interest in the outbound body
+    // stream before all of its frames were read.
+    out_body_dropped: bool,
+
     // The inbound body stream receiver
     in_body: Option<T::Stream>,
+
+    // With flow control, the number of outbound body chunks the peer may
+    // still send
+    recv_window: usize,
+
+    // With flow control, the number of outbound body chunks handed to the
+    // sender since the peer was last sent a window update
+    recv_consumed: usize,
+
+    // With flow control, the number of inbound body chunks that may still be
+    // written
+    send_window: usize,
 }
 
 enum Request<T: Dispatch> {
@@ -177,6 +270,40 @@ pub trait Dispatch {
 
     /// Cancel interest in the exchange identified by RequestId
     fn cancel(&mut self, request_id: RequestId) -> io::Result<()>;
+
+    /// Poll for an exchange the dispatch has lost interest in
+    ///
+    /// The multiplexer drops the state of the returned exchange, including
+    /// its body streams, and ignores any further frames read for it. The
+    /// dispatch is responsible for notifying the transport.
+    fn poll_canceled(&mut self) -> Poll<RequestId, io::Error> {
+        Ok(Async::NotReady)
+    }
+
+    /// Called once every message written so far has been flushed to the
+    /// transport
+    fn flushed(&mut self) {
+    }
+}
+
+/*
+ *
+ * ===== impl MultiplexConfig =====
+ *
+ */
+
+impl Default for MultiplexConfig {
+    fn default() -> MultiplexConfig {
+        MultiplexConfig {
+            max_in_flight: 32,
+            max_queued: None,
+            max_buffered_frames: 128,
+            max_buffered_chunks: 128,
+            body_channel_depth: 0,
+            window: None,
+            max_canceled: 1024,
+        }
+    }
 }
 
 /*
@@ -189,24 +316,37 @@ impl<T> Multiplex<T> where T: Dispatch {
     /// Create a new pipeline `Multiplex` dispatcher with the given service and
     /// transport
     pub fn new(dispatch: T) -> Multiplex<T> {
+        Multiplex::with_config(dispatch, MultiplexConfig::default())
+    }
+
+    /// Create a new `Multiplex` dispatcher with the given service and
+    /// transport, limited by `config`
+    pub fn with_config(dispatch: T, config: MultiplexConfig) -> Multiplex<T> {
+        assert!(config.max_buffered_frames > 0);
+        assert!(config.max_buffered_chunks > 0);
+        assert!(config.window != Some(0));
+
         // Add `Sink` impl for `Dispatch`
         let dispatch = DispatchSink { inner: dispatch };
 
         // Add a single slot buffer for the sink
         let dispatch = BufferOne::new(dispatch);
 
-        let frame_buf = FrameBuf::with_capacity(MAX_BUFFERED_FRAMES);
+        let frame_buf = FrameBuf::with_capacity(config.max_buffered_frames);
 
         Multiplex {
             run: true,
             made_progress: false,
             blocked_on_dispatch: false,
+            blocked_on_frame_buf: false,
             blocked_on_flush: WriteState::NoWrite,
             dispatch: dispatch,
             exchanges: HashMap::new(),
             is_flushed: true,
             dispatch_deque: VecDeque::new(),
+            canceled: Canceled::new(config.max_canceled),
             frame_buf: frame_buf,
+            config: config,
             scratch: vec![],
         }
     }
@@ -247,6 +387,40 @@ impl<T> Multiplex<T> where T: Dispatch {
         Ok(())
     }
 
+    /// Drop the exchanges the dispatch has lost interest in
+    fn purge_canceled(&mut self) -> io::Result<()> {
+        while let Async::Ready(id) = try!(self.dispatch.get_mut().inner.poll_canceled()) {
+            trace!("exchange canceled by dispatch; id={:?}", id);
+
+            self.exchanges.remove(&id);
+            self.canceled.insert(id);
+
+            // Canceling may have made room for more exchanges
+            self.made_progress = true;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the exchange. If the exchange is for one of our own requests,
+    /// and the response body has been dropped by the receiving end, interest
+    /// in the exchange is canceled.
+    fn remove_exchange(&mut self, id: RequestId) -> io::Result<()> {
+        let exchange = match self.exchanges.remove(&id) {
+            Some(exchange) => exchange,
+            None => return Ok(()),
+        };
+
+        if exchange.is_inbound() && exchange.is_out_body_dropped() {
+            trace!("   --> response body dropped; canceling exchange; id={:?}", id);
+
+            self.canceled.insert(id);
+            try!(self.dispatch.get_mut().inner.cancel(id));
+        }
+
+        Ok(())
+    }
+
     /// Dispatch any buffered outbound body frames to the sender
     fn flush_out_bodies(&mut self) -> io::Result<()> {
         trace!("flush out bodies");
@@ -257,16 +431,24 @@ impl<T> Multiplex<T> where T: Dispatch {
             trace!("   --> request={}", id);
             try!(exchange.flush_out_body());
 
-            // If the exchange is complete, track it for removal
-            if exchange.is_complete() {
+            if let Some(window) = self.config.window {
+                if let Some(increment) = exchange.window_update(window) {
+                    try!(self.dispatch.get_mut().inner.transport().send_window_update(*id, increment));
+                }
+            }
+
+            // If the exchange is complete, or the response body to one of our
+            // requests has been dropped, track it for removal
+            if exchange.is_complete() || (exchange.is_inbound() && exchange.is_out_body_dropped()) {
                 self.scratch.push(*id);
             }
         }
 
         // Purge the scratch
-        for id in &self.scratch {
+        for i in 0..self.scratch.len() {
+            let id = self.scratch[i];
             trace!("drop exchange; id={}", id);
-            self.exchanges.remove(id);
+            try!(self.remove_exchange(id));
         }
 
         Ok(())
@@ -275,8 +457,15 @@ impl<T> Multiplex<T> where T: Dispatch {
     /// Read and process frames from transport
     fn read_out_frames(&mut self) -> io::Result<()> {
         while self.run {
-            // TODO: Only read frames if there is available space in the frame
-            // buffer
+            // Only read frames if there is available space in the frame
+            // buffer. Otherwise, frames are left with the transport until the
+            // buffered ones are consumed.
+            if self.frame_buf.len() >= self.config.max_buffered_frames {
+                trace!("   --> frame buffer full");
+                self.blocked_on_frame_buf = true;
+                break;
+            }
+
             if let Async::Ready(frame) = try!(self.dispatch.get_mut().inner.transport().poll()) {
                 try!(self.process_out_frame(frame));
             } else {
@@ -293,11 +482,32 @@ impl<T> Multiplex<T> where T: Dispatch {
                          -> io::Result<()> {
         trace!("Multiplex::process_out_frame");
 
+        if let Some(ref frame) = frame {
+            let id = frame.request_id();
+
+            if self.canceled.contains(&id) {
+                trace!("   --> dropping frame of canceled exchange; id={:?}", id);
+
+                // Forget the exchange once the last frame is read
+                let last = match *frame {
+                    Frame::Message { body, .. } => !body,
+                    Frame::Body { ref chunk, .. } => chunk.is_none(),
+                    Frame::Error { .. } => true,
+                };
+
+                if last {
+                    self.canceled.remove(&id);
+                }
+
+                return Ok(());
+            }
+        }
+
         match frame {
             Some(Frame::Message { id, message, body, solo }) => {
                 if body {
-                    let (tx, rx) = Body::pair();
-                    let message = Message::WithBody(message, rx);
+                    let (tx, rx) = mpsc::channel(self.config.body_channel_depth);
+                    let message = Message::WithBody(message, Body::from(rx));
 
                     try!(self.process_out_message(id, message, Some(tx), solo));
                 } else {
@@ -308,7 +518,7 @@ impl<T> Multiplex<T> where T: Dispatch {
             }
             Some(Frame::Body { id, chunk }) => {
                 trace!("   --> read out body chunk");
-                self.process_out_body_chunk(id, Ok(chunk));
+                try!(self.process_out_body_chunk(id, Ok(chunk)));
             }
             Some(Frame::Error { id, error }) => {
                 try!(self.process_out_err(id, error));
@@ -369,7 +579,8 @@ impl<T> Multiplex<T> where T: Dispatch {
                     // Create the exchange state
                     let mut exchange = Exchange::new(
                         Request::Out(None),
-                        self.frame_buf.deque());
+                        self.frame_buf.deque(),
+                        self.config.window);
 
                     exchange.out_body = body;
 
@@ -395,7 +606,8 @@ impl<T> Multiplex<T> where T: Dispatch {
                     // Create the exchange state, including the buffered message
                     let mut exchange = Exchange::new(
                         Request::Out(Some(message)),
-                        self.frame_buf.deque());
+                        self.frame_buf.deque(),
+                        self.config.window);
 
                     exchange.out_body = body;
 
@@ -439,9 +651,13 @@ impl<T> Multiplex<T> where T: Dispatch {
                 // exchange, indicate that interest has been canceled.
                 if !exchange.responded {
                     try!(self.dispatch.get_mut().inner.cancel(id));
-                }
 
-                remove = exchange.is_complete();
+                    // No response will be written, so the exchange is done.
+                    // This also drops the body sender.
+                    remove = true;
+                } else {
+                    remove = exchange.is_complete();
+                }
             } else {
                 if !exchange.responded {
                     // A response has not been provided yet, send the error via
@@ -463,12 +679,15 @@ impl<T> Multiplex<T> where T: Dispatch {
 
         if remove {
             self.exchanges.remove(&id);
+
+            // Canceling may have made room for buffered messages
+            self.dispatch_made_progress();
         }
 
         Ok(())
     }
 
-    fn process_out_body_chunk(&mut self, id: RequestId, chunk: Result<Option<T::BodyOut>, T::Error>) {
+    fn process_out_body_chunk(&mut self, id: RequestId, chunk: Result<Option<T::BodyOut>, T::Error>) -> io::Result<()> {
         trace!("process out body chunk; id={:?}", id);
 
         {
@@ -476,19 +695,60 @@ impl<T> Multiplex<T> where T: Dispatch {
                 Some(v) => v,
                 _ => {
                     trace!("   --> exchange previously aborted; id={:?}", id);
-                    return;
+                    return Ok(());
                 }
             };
 
+            if exchange.out_deque.len() >= self.config.max_buffered_chunks {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "too many body chunks buffered for exchange"));
+            }
+
+            if let Ok(Some(ref chunk)) = chunk {
+                // Chunks for a body that is no longer wanted are dropped, and
+                // do not count against the window
+                if self.config.window.is_some() && exchange.out_body.is_some() {
+                    if exchange.recv_window == 0 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  "peer exceeded flow control window"));
+                    }
+
+                    exchange.recv_window -= 1;
+                }
+
+                self.dispatch.get_mut().inner.transport().dispatching_body(id, chunk);
+            }
+
             exchange.send_out_chunk(chunk);
 
-            if !exchange.is_complete() {
-                return;
+            if let Some(window) = self.config.window {
+                if let Some(increment) = exchange.window_update(window) {
+                    try!(self.dispatch.get_mut().inner.transport().send_window_update(id, increment));
+                }
+            }
+
+            if !exchange.is_complete() && !(exchange.is_inbound() && exchange.is_out_body_dropped()) {
+                return Ok(());
             }
         }
 
         trace!("dropping out body handle; id={:?}", id);
-        self.exchanges.remove(&id);
+        self.remove_exchange(id)
+    }
+
+    /// Apply the window updates sent by the peer
+    fn read_window_updates(&mut self) {
+        if self.config.window.is_none() {
+            return;
+        }
+
+        while let Async::Ready((id, increment)) = self.dispatch.get_mut().inner.transport().poll_window_update() {
+            trace!("window update; id={:?}; increment={}", id, increment);
+
+            if let Some(exchange) = self.exchanges.get_mut(&id) {
+                exchange.send_window += increment;
+            }
+        }
     }
 
     fn write_in_frames(&mut self) -> io::Result<()> {
@@ -586,7 +846,8 @@ impl<T> Multiplex<T> where T: Dispatch {
                 // Create the exchange state
                 let mut exchange = Exchange::new(
                     Request::In,
-                    self.frame_buf.deque());
+                    self.frame_buf.deque(),
+                    self.config.window);
 
                 // Set the body receiver
                 exchange.in_body = body;
@@ -649,6 +910,20 @@ impl<T> Multiplex<T> where T: Dispatch {
                     break 'outer;
                 }
 
+                if exchange.in_body.is_some() {
+                    // Hold back the body while the peer does not allow more
+                    // chunks
+                    if self.config.window.is_some() && exchange.send_window == 0 {
+                        trace!("   --> send window exhausted");
+                        continue 'outer;
+                    }
+
+                    if !self.dispatch.get_mut().inner.transport().poll_write_body(id).is_ready() {
+                        trace!("   --> transport not ready for body");
+                        continue 'outer;
+                    }
+                }
+
                 match exchange.try_poll_in_body() {
                     Ok(Async::Ready(Some(chunk))) => {
                         trace!("   --> got chunk");
@@ -656,6 +931,10 @@ impl<T> Multiplex<T> where T: Dispatch {
                         let frame = Frame::Body { id: id, chunk: Some(chunk) };
                         try!(assert_send(&mut self.dispatch, frame));
                         self.blocked_on_flush.wrote_frame();
+
+                        if self.config.window.is_some() {
+                            exchange.send_window -= 1;
+                        }
                     }
                     Ok(Async::Ready(None)) => {
                         trace!("   --> end of stream");
@@ -707,6 +986,10 @@ impl<T> Multiplex<T> where T: Dispatch {
     fn flush(&mut self) -> io::Result<()> {
         self.is_flushed = try!(self.dispatch.poll_complete()).is_ready();
 
+        if self.is_flushed {
+            self.dispatch.get_mut().inner.flushed();
+        }
+
         // TODO: Technically, poll_complete needs to be called on the exchange body senders.
         // However, mpsc::Sender doesn't actually need to have poll_complete called as it is
         // currently a no-op. So, I'm just going to punt on figuring out the best way to handle
@@ -722,6 +1005,7 @@ impl<T> Multiplex<T> where T: Dispatch {
     fn reset_flags(&mut self) {
         self.made_progress = false;
         self.blocked_on_dispatch = false;
+        self.blocked_on_frame_buf = false;
         self.blocked_on_flush = WriteState::NoWrite;
     }
 
@@ -730,6 +1014,12 @@ impl<T> Multiplex<T> where T: Dispatch {
             self.made_progress = true;
         }
     }
+
+    fn frame_buf_made_progress(&mut self) {
+        if self.blocked_on_frame_buf && self.frame_buf.len() < self.config.max_buffered_frames {
+            self.made_progress = true;
+        }
+    }
 }
 
 impl<T> Future for Multiplex<T>
@@ -767,9 +1057,18 @@ impl<T> Future for Multiplex<T>
             // First read off data from the socket
             try!(self.read_out_frames());
 
+            // Allow more body chunks to be written
+            self.read_window_updates();
+
             // Handle completed responses
             try!(self.write_in_frames());
 
+            // Drop the exchanges that are no longer wanted
+            try!(self.purge_canceled());
+
+            // Dropping exchanges may have freed up room for reading frames
+            self.frame_buf_made_progress();
+
             // Try flushing buffered writes
             try!(self.flush());
         }
@@ -808,14 +1107,22 @@ impl<T: Dispatch> Drop for Multiplex<T> {
 }
 
 impl<T: Dispatch> Exchange<T> {
-    fn new(request: Request<T>, deque: FrameDeque<Option<Result<T::BodyOut, T::Error>>>) -> Exchange<T> {
+    fn new(request: Request<T>,
+           deque: FrameDeque<Option<Result<T::BodyOut, T::Error>>>,
+           window: Option<usize>) -> Exchange<T> {
+        let window = window.unwrap_or(0);
+
         Exchange {
             request: request,
             responded: false,
             out_body: None,
             out_deque: deque,
             out_is_ready: true,
+            out_body_dropped: false,
             in_body: None,
+            recv_window: window,
+            recv_consumed: 0,
+            send_window: window,
         }
     }
 
@@ -830,6 +1137,31 @@ impl<T: Dispatch> Exchange<T> {
         !self.is_inbound()
     }
 
+    /// Returns true if the receiving end of the outbound body has been dropped
+    /// before all of its frames were read
+    fn is_out_body_dropped(&self) -> bool {
+        match self.out_body {
+            Some(ref sender) => sender.is_closed(),
+            None => self.out_body_dropped,
+        }
+    }
+
+    /// Returns the increment of the window update to send to the peer, if
+    /// enough of the outbound body has been handed to the sender
+    fn window_update(&mut self, window: usize) -> Option<usize> {
+        // Batch the updates, as in HTTP/2
+        if self.out_body.is_none() || self.recv_consumed < cmp::max(window / 2, 1) {
+            return None;
+        }
+
+        let increment = self.recv_consumed;
+
+        self.recv_window += increment;
+        self.recv_consumed = 0;
+
+        Some(increment)
+    }
+
     fn is_dispatched(&self) -> bool {
         match self.request {
             Request::Out(Some(_)) => false,
@@ -890,9 +1222,17 @@ impl<T: Dispatch> Exchange<T> {
                 // If there is a chunk (vs. None which represents end of
                 // stream)
                 if let Some(chunk) = chunk {
+                    // Errors represent the last message to send
+                    let done = chunk.is_err();
+
                     match sender.start_send(chunk) {
                         Ok(AsyncSink::Ready) => {
                             trace!("   --> ready for more");
+
+                            if !done {
+                                self.recv_consumed += 1;
+                            }
+
                             // The sender is ready for another message
                             return;
                         }
@@ -905,6 +1245,7 @@ impl<T: Dispatch> Exchange<T> {
                         }
                         Err(_) => {
                             // The sender is complete, it should be removed
+                            self.out_body_dropped = !done;
                         }
                     }
                 }
@@ -960,7 +1301,11 @@ impl<T: Dispatch> Exchange<T> {
                 let done = msg.is_err();
 
                 match sender.start_send(msg) {
-                    Ok(AsyncSink::Ready) => {}
+                    Ok(AsyncSink::Ready) => {
+                        if !done {
+                            self.recv_consumed += 1;
+                        }
+                    }
                     Ok(AsyncSink::NotReady(msg)) => {
                         trace!("   --> not ready");
 
@@ -976,8 +1321,7 @@ impl<T: Dispatch> Exchange<T> {
                         // buffer is dropped. If future body frames are
                         // received, the sender will be gone and the frames
                         // will be dropped.
-                        //
-                        // TODO: Notify transport
+                        self.out_body_dropped = !done;
                         break;
                     }
                 }
@@ -1033,6 +1377,53 @@ fn assert_send<T>(s: &mut T, item: T::SinkItem) -> Result<(), T::SinkError>
     }
 }
 
+/*
+ *
+ * ===== impl Canceled =====
+ *
+ */
+
+impl Canceled {
+    fn new(max: usize) -> Canceled {
+        Canceled {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            max: max,
+        }
+    }
+
+    fn contains(&self, id: &RequestId) -> bool {
+        self.ids.contains(id)
+    }
+
+    fn insert(&mut self, id: RequestId) {
+        if self.ids.insert(id) {
+            self.order.push_back(id);
+        }
+
+        // Forget the oldest exchanges, which the peer may never finish
+        while self.ids.len() > self.max {
+            match self.order.pop_front() {
+                Some(id) => {
+                    self.ids.remove(&id);
+                }
+                None => break,
+            }
+        }
+
+        // Drop the entries of exchanges that have been forgotten already,
+        // once they make up most of the queue
+        if self.order.len() > 2 * cmp::max(self.ids.len(), 16) {
+            let ids = &self.ids;
+            self.order.retain(|id| ids.contains(id));
+        }
+    }
+
+    fn remove(&mut self, id: &RequestId) {
+        self.ids.remove(id);
+    }
+}
+
 /*
  *
  * ===== impl MultiplexMessage =====
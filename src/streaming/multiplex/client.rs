@@ -1,14 +1,17 @@
-use super::{Frame, RequestId, StreamingMultiplex, Transport};
+use super::{Frame, MultiplexConfig, RequestId, StreamingMultiplex, Transport};
 use super::advanced::{Multiplex, MultiplexMessage};
 
 use BindClient;
 use streaming::{Body, Message};
-use util::client_proxy::{self, ClientProxy, Receiver};
+use util::client_proxy::{self, ClientProxy, Receiver, Request};
 use futures::{Future, IntoFuture, Complete, Poll, Async};
 use futures::stream::Stream;
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Timeout};
 use std::io;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::Duration;
 
 /// A streaming, multiplexed client protocol.
 ///
@@ -48,6 +51,24 @@ pub trait ClientProto<T: 'static>: 'static {
     /// Build a transport from the given I/O object, using `self` for any
     /// configuration.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// How long to wait for the response to a request.
+    ///
+    /// Requests that take longer fail with an `io::ErrorKind::TimedOut` error,
+    /// converted to `Self::Error`. Returns `None` by default, in which case
+    /// requests never time out.
+    fn request_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// The limits of each connection, such as the number of requests that
+    /// can be in flight at once.
+    ///
+    /// Requests beyond the in-flight limit wait in the client's queue until
+    /// a response arrives. Returns `MultiplexConfig::default()` by default.
+    fn multiplex_config(&self) -> MultiplexConfig {
+        MultiplexConfig::default()
+    }
 }
 
 impl<P, T, B> BindClient<StreamingMultiplex<B>, T> for P where
@@ -62,19 +83,43 @@ impl<P, T, B> BindClient<StreamingMultiplex<B>, T> for P where
     type BindClient = ClientProxy<Self::ServiceRequest, Self::ServiceResponse, Self::ServiceError>;
 
     fn bind_client(&self, handle: &Handle, io: T) -> Self::BindClient {
-        let (client, rx) = client_proxy::pair();
+        let config = self.multiplex_config();
+
+        let (client, rx) = match config.max_queued {
+            Some(capacity) => client_proxy::bounded(capacity),
+            None => client_proxy::pair(),
+        };
+        let error = rx.error_handle();
+        let notified = Rc::new(RefCell::new(vec![]));
+        let unflushed = notified.clone();
 
-        let task = self.bind_transport(io).into_future().and_then(|transport| {
+        let request_timeout = self.request_timeout();
+        let timer = handle.clone();
+
+        let task = self.bind_transport(io).into_future().and_then(move |transport| {
             let dispatch: Dispatch<P, T, B> = Dispatch {
                 transport: transport,
                 requests: rx,
+                notified: notified,
                 in_flight: HashMap::new(),
+                canceled: VecDeque::new(),
+                max_in_flight: config.max_in_flight,
                 next_request_id: 0,
+                handle: timer,
+                request_timeout: request_timeout,
             };
-            Multiplex::new(dispatch)
-        }).map_err(|e| {
-            // TODO: where to punt this error to?
+            Multiplex::with_config(dispatch, config)
+        }).map_err(move |e| {
             debug!("multiplex task failed with error; err={:?}", e);
+
+            // One-way messages that were not flushed before the connection died
+            // may never have reached the peer
+            for complete in unflushed.borrow_mut().drain(..) {
+                complete.complete(Err(io::Error::new(e.kind(), e.to_string()).into()));
+            }
+
+            // Fails the pending and future requests with the error
+            error.set(e);
         });
 
         // Spawn the task
@@ -92,8 +137,24 @@ struct Dispatch<P, T, B> where
 {
     transport: P::Transport,
     requests: Receiver<P::ServiceRequest, P::ServiceResponse, P::Error>,
-    in_flight: HashMap<RequestId, Complete<Result<P::ServiceResponse, P::Error>>>,
+    // One-way messages that have been handed to the multiplexer, but not yet
+    // flushed. Shared with the task, which fails them if the connection dies.
+    notified: Rc<RefCell<Vec<Complete<Result<(), P::Error>>>>>,
+    in_flight: HashMap<RequestId, InFlight<P::ServiceResponse, P::Error>>,
+    // Requests that timed out, or whose response future was dropped, before
+    // their response arrived. They are yet to be reported to the multiplexer.
+    canceled: VecDeque<RequestId>,
+    // The total number of requests that can be in flight at once. Further
+    // requests wait in the client's queue until a response arrives.
+    max_in_flight: usize,
     next_request_id: u64,
+    handle: Handle,
+    request_timeout: Option<Duration>,
+}
+
+struct InFlight<T, E> {
+    complete: Complete<Result<T, E>>,
+    timeout: Option<Timeout>,
 }
 
 impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
@@ -117,12 +178,23 @@ impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
     fn dispatch(&mut self, message: MultiplexMessage<Self::Out, Body<Self::BodyOut, Self::Error>, Self::Error>) -> io::Result<()> {
         let MultiplexMessage { id, message, solo } = message;
 
-        assert!(!solo);
+        if solo {
+            // There is nobody to hand one-way messages from the server to
+            trace!("   --> dropping solo message; request-id={:?}", id);
+            return Ok(());
+        }
 
-        if let Some(complete) = self.in_flight.remove(&id) {
-            complete.complete(message);
+        if let Some(in_flight) = self.in_flight.remove(&id) {
+            in_flight.complete.complete(message);
+        } else if id < self.next_request_id {
+            // A late response to a request that was canceled, and has since
+            // been forgotten by the multiplexer
+            trace!("   --> dropping stale response; request-id={:?}", id);
         } else {
-            return Err(io::Error::new(io::ErrorKind::Other, "request / response mismatch"));
+            // The connection can no longer be trusted to match responses up
+            // with their requests. Failing the task fails all outstanding
+            // requests with the error.
+            return Err(unexpected_response());
         }
 
         Ok(())
@@ -130,9 +202,29 @@ impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
 
     fn poll(&mut self) -> Poll<Option<MultiplexMessage<Self::In, B, Self::Error>>, io::Error> {
         trace!("Dispatch::poll");
+
+        if !self.poll_ready().is_ready() {
+            trace!("   --> in-flight limit reached");
+            return Ok(Async::NotReady);
+        }
+
         // Try to get a new request frame
-        match self.requests.poll() {
-            Ok(Async::Ready(Some(Ok((request, complete))))) => {
+        match self.requests.poll_request() {
+            Ok(Async::Ready(Some(Ok(Request::Notify(message, complete))))) => {
+                let request_id = self.next_request_id;
+                self.next_request_id += 1;
+
+                trace!("   --> received notification; request-id={:?}", request_id);
+
+                self.notified.borrow_mut().push(complete);
+
+                Ok(Async::Ready(Some(MultiplexMessage {
+                    id: request_id,
+                    message: Ok(message),
+                    solo: true,
+                })))
+            }
+            Ok(Async::Ready(Some(Ok(Request::Call(request, complete))))) => {
                 trace!("   --> received request");
 
                 let request_id = self.next_request_id;
@@ -140,8 +232,16 @@ impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
 
                 trace!("   --> assigning request-id={:?}", request_id);
 
+                let timeout = match self.request_timeout {
+                    Some(dur) => Some(try!(Timeout::new(dur, &self.handle))),
+                    None => None,
+                };
+
                 // Track complete handle
-                self.in_flight.insert(request_id, complete);
+                self.in_flight.insert(request_id, InFlight {
+                    complete: complete,
+                    timeout: timeout,
+                });
 
                 Ok(Async::Ready(Some(MultiplexMessage::new(request_id, request))))
 
@@ -152,26 +252,95 @@ impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
             }
             Ok(Async::Ready(Some(Err(e)))) => {
                 trace!("   --> error");
-                // An error on receive can only happen when the other half
-                // disconnected. In this case, the client needs to be
-                // shutdown
-                panic!("unimplemented error handling: {:?}", e);
+                // The client asked for the connection to be shut down. Failing
+                // the task fails all outstanding requests with the error.
+                Err(e)
             }
             Ok(Async::NotReady) => {
                 trace!("   --> not ready");
                 Ok(Async::NotReady)
             }
-            Err(()) => panic!(),
+            Err(()) => {
+                // The request channel never fails, but should it happen, the
+                // connection is shut down
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "request channel failed"))
+            }
         }
     }
 
     fn poll_ready(&self) -> Async<()> {
-        // Not capping the client yet
-        Async::Ready(())
+        if self.in_flight.len() < self.max_in_flight {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+
+    fn cancel(&mut self, request_id: RequestId) -> io::Result<()> {
+        trace!("Dispatch::cancel; request-id={:?}", request_id);
+
+        // The response body has been dropped
+        self.in_flight.remove(&request_id);
+        self.transport.cancel(request_id)
+    }
+
+    fn poll_canceled(&mut self) -> Poll<RequestId, io::Error> {
+        if self.canceled.is_empty() {
+            try!(self.poll_in_flight());
+        }
+
+        match self.canceled.pop_front() {
+            Some(request_id) => Ok(Async::Ready(request_id)),
+            None => Ok(Async::NotReady),
+        }
     }
 
-    fn cancel(&mut self, _request_id: RequestId) -> io::Result<()> {
-        // TODO: implement
+    fn flushed(&mut self) {
+        for complete in self.notified.borrow_mut().drain(..) {
+            complete.complete(Ok(()));
+        }
+    }
+}
+
+impl<P, T, B> Dispatch<P, T, B> where
+    P: ClientProto<T>,
+    T: 'static,
+    B: Stream<Item = P::RequestBody, Error = P::Error> + 'static,
+{
+    // Cancels the requests whose response future has been dropped, and fails
+    // the ones whose timeout fired
+    fn poll_in_flight(&mut self) -> io::Result<()> {
+        let mut dropped = vec![];
+        let mut expired = vec![];
+
+        for (&request_id, in_flight) in self.in_flight.iter_mut() {
+            if let Ok(Async::Ready(())) = in_flight.complete.poll_cancel() {
+                dropped.push(request_id);
+            } else if let Some(ref mut timeout) = in_flight.timeout {
+                if try!(timeout.poll()).is_ready() {
+                    expired.push(request_id);
+                }
+            }
+        }
+
+        for request_id in dropped {
+            trace!("   --> response dropped; request-id={:?}", request_id);
+
+            try!(self.transport.cancel(request_id));
+            self.in_flight.remove(&request_id);
+            self.canceled.push_back(request_id);
+        }
+
+        for request_id in expired {
+            trace!("   --> request timed out; request-id={:?}", request_id);
+
+            try!(self.transport.cancel(request_id));
+            self.canceled.push_back(request_id);
+
+            let in_flight = self.in_flight.remove(&request_id).unwrap();
+            in_flight.complete.complete(Err(timed_out().into()));
+        }
+
         Ok(())
     }
 }
@@ -186,13 +355,16 @@ impl<P, T, B> Drop for Dispatch<P, T, B> where
             warn!("multiplex client dropping with in-flight exchanges");
         }
 
-        // Complete any pending requests with an error
-        for (_, complete) in self.in_flight.drain() {
-            complete.complete(Err(broken_pipe().into()));
-        }
+        // Dropping the complete handles fails any pending requests with the
+        // error that ended the connection
+        self.in_flight.clear();
     }
 }
 
-fn broken_pipe() -> io::Error {
-    io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe")
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "request timed out")
+}
+
+fn unexpected_response() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "response does not match any request")
 }
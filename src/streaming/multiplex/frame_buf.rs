@@ -29,6 +29,8 @@ struct Inner<T> {
     max_capacity: usize,
     // Number of allocated elements
     allocated: usize,
+    // Number of elements in use
+    used: usize,
     // Free slot stack
     free: *mut Slot<T>,
     // All blocks
@@ -61,6 +63,11 @@ impl<T> FrameBuf<T> {
         unsafe { &*self.inner.get() }.allocated
     }
 
+    /// Returns the number of frames currently buffered
+    pub fn len(&self) -> usize {
+        unsafe { &*self.inner.get() }.used
+    }
+
     pub fn deque(&self) -> FrameDeque<T> {
         FrameDeque {
             inner: self.inner.clone(),
@@ -138,6 +145,7 @@ impl<T> FrameDeque<T> {
 
                 head.next = inner.free;
                 inner.free = ptr;
+                inner.used -= 1;
 
                 if val.is_none() {
                     assert!(self.len() == 0);
@@ -164,6 +172,13 @@ impl<T> FrameDeque<T> {
     }
 }
 
+impl<T> Drop for FrameDeque<T> {
+    fn drop(&mut self) {
+        // Return the slots to the buffer
+        self.clear();
+    }
+}
+
 impl<T> Inner<T> {
     fn with_capacity(mut capacity: usize) -> Inner<T> {
         capacity = cmp::max(INITIAL_BLOCK_SIZE, capacity.next_power_of_two());
@@ -172,6 +187,7 @@ impl<T> Inner<T> {
         Inner {
             max_capacity: capacity,
             allocated: 0,
+            used: 0,
             free: ptr::null_mut(),
             blocks: SmallVec::new(),
         }
@@ -183,6 +199,7 @@ impl<T> Inner<T> {
             if let Some(slot) = self.free.as_mut() {
                 self.free = slot.next;
                 slot.next = ptr::null_mut();
+                self.used += 1;
                 return Some(slot);
             }
 
@@ -207,6 +224,8 @@ impl<T> Inner<T> {
                 val: None,
             });
 
+            self.used += 1;
+
             Some(&mut block[idx])
         }
     }
@@ -271,6 +290,31 @@ mod test {
         assert!(d.pop().is_none());
     }
 
+    #[test]
+    fn test_len() {
+        let fb = FrameBuf::with_capacity(32);
+        let d1 = fb.deque();
+        let d2 = fb.deque();
+
+        d1.push(1);
+        d1.push(2);
+        d2.push(3);
+        assert_eq!(3, fb.len());
+
+        assert_eq!(Some(1), d1.pop());
+        assert_eq!(2, fb.len());
+
+        // Dropping a deque frees its slots
+        drop(d1);
+        assert_eq!(1, fb.len());
+
+        for i in 0..31 {
+            d2.push(i);
+        }
+
+        assert_eq!(32, fb.len());
+    }
+
     #[test]
     fn test_growing_buffer() {
         let fb = FrameBuf::with_capacity(128);
@@ -17,8 +17,11 @@ pub use self::server::ServerProto;
 mod frame;
 pub use self::frame::Frame;
 
+mod peer;
+pub use self::peer::{Peer, Side};
 
 pub mod advanced;
+pub use self::advanced::MultiplexConfig;
 
 /// Identifies a request / response thread
 pub type RequestId = u64;
@@ -62,6 +65,37 @@ pub trait Transport<ReadBody>: 'static +
         drop(id);
         drop(body);
     }
+
+    /// Tell the peer that it may send `increment` more body chunks for the
+    /// given request ID.
+    ///
+    /// Only invoked when flow control is enabled with
+    /// `MultiplexConfig::window`, as the chunks read for the request are
+    /// handed to its body stream.
+    fn send_window_update(&mut self, id: RequestId, increment: usize) -> io::Result<()> {
+        let _ = (id, increment);
+        Ok(())
+    }
+
+    /// Poll for a window update sent by the peer, which allows `increment`
+    /// more body chunks to be written for the given request ID.
+    ///
+    /// Only polled when flow control is enabled with
+    /// `MultiplexConfig::window`. The transport is responsible for waking up
+    /// the task once an update is available.
+    fn poll_window_update(&mut self) -> Async<(RequestId, usize)> {
+        Async::NotReady
+    }
+
+    /// Poll for an exchange canceled by the peer, for example with a reset
+    /// frame.
+    ///
+    /// Servers stop working on the request, drop its body stream and write
+    /// nothing further for it. The transport is responsible for waking up the
+    /// task once a cancellation is available.
+    fn poll_canceled(&mut self) -> Async<RequestId> {
+        Async::NotReady
+    }
 }
 
 impl<T:Io + 'static, C: Codec + 'static, ReadBody> Transport<ReadBody> for Framed<T,C> {}
@@ -0,0 +1,367 @@
+use super::{Frame, MultiplexConfig, RequestId, Transport};
+use super::advanced::{Multiplex, MultiplexMessage};
+
+use streaming::{Body, Message};
+use util::client_proxy::{self, ClientProxy, Closed, Receiver, Response};
+use futures::{Future, Complete, Poll, Async};
+use futures::stream::Stream;
+use futures::task;
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+use std::io;
+use std::collections::{HashMap, VecDeque};
+
+/// One end of a connection on which both sides issue requests.
+///
+/// A `Peer` multiplexes the requests it is called with, and the requests
+/// issued by the other end of the connection, over a single transport. The
+/// requests from the other end are handled by the service given to `bind`.
+///
+/// The request IDs are split between the two ends, so that they do not
+/// collide: one end uses the odd IDs for its requests and the other one the
+/// even IDs, as set by `Side`.
+///
+/// A `Peer` is a client `Service` itself. The connection is driven by a task
+/// on the event loop given to `bind`, and is closed once the transport is
+/// done. Dropping the `Peer` only stops new requests from being issued.
+///
+/// Requests of the other end that arrive in solo frames are handled by the
+/// service as well, but are not responded to.
+pub struct Peer<Req, Resp, E> {
+    client: ClientProxy<Req, Resp, E>,
+}
+
+/// The half of the request IDs that a `Peer` uses for its requests.
+///
+/// The two ends of a connection must be on different sides. By convention,
+/// the end that established the connection uses the odd IDs, as in HTTP/2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// Requests use the odd IDs, starting at 1.
+    Odd,
+    /// Requests use the even IDs, starting at 0.
+    Even,
+}
+
+impl<Out, In, InBody, E, B> Peer<Message<Out, B>, Message<In, Body<InBody, E>>, E> where
+    Out: 'static,
+    In: 'static,
+    InBody: 'static,
+    E: From<io::Error> + 'static,
+    B: Stream<Error = E> + 'static,
+{
+    /// Start multiplexing over `transport`, answering the requests of the
+    /// other end with `service`.
+    ///
+    /// The connection is driven by a task spawned on the event loop behind
+    /// `handle`.
+    pub fn bind<T, S>(handle: &Handle, transport: T, side: Side, service: S) -> Self
+        where T: Transport<InBody,
+                           Item = Frame<In, InBody, E>,
+                           SinkItem = Frame<Out, B::Item, E>>,
+              S: Service<Request = Message<In, Body<InBody, E>>,
+                         Response = Message<Out, B>,
+                         Error = E> + 'static,
+    {
+        Peer::bind_with_config(handle, transport, side, service, MultiplexConfig::default())
+    }
+
+    /// Start multiplexing over `transport`, limited by `config`.
+    ///
+    /// The in-flight limit of `config` applies to the requests of each end
+    /// separately. See `bind` for details.
+    pub fn bind_with_config<T, S>(handle: &Handle,
+                                  transport: T,
+                                  side: Side,
+                                  service: S,
+                                  config: MultiplexConfig) -> Self
+        where T: Transport<InBody,
+                           Item = Frame<In, InBody, E>,
+                           SinkItem = Frame<Out, B::Item, E>>,
+              S: Service<Request = Message<In, Body<InBody, E>>,
+                         Response = Message<Out, B>,
+                         Error = E> + 'static,
+    {
+        let (client, rx) = client_proxy::pair();
+        let error = rx.error_handle();
+
+        let next_request_id = match side {
+            Side::Odd => 1,
+            Side::Even => 0,
+        };
+
+        let dispatch = Dispatch {
+            transport: transport,
+            service: service,
+            requests: Some(rx),
+            calls: HashMap::new(),
+            canceled: VecDeque::new(),
+            next_request_id: next_request_id,
+            in_flight: vec![],
+            notifications: vec![],
+            max_in_flight: config.max_in_flight,
+        };
+
+        let task = Multiplex::with_config(dispatch, config).map_err(move |e| {
+            debug!("multiplex peer task failed with error; err={:?}", e);
+
+            // Fails the pending and future requests with the error
+            error.set(e);
+        });
+
+        handle.spawn(task);
+
+        Peer { client: client }
+    }
+}
+
+impl<Req, Resp, E: From<io::Error>> Peer<Req, Resp, E> {
+    /// Returns a future that completes once the connection has been closed.
+    ///
+    /// See `ClientProxy::closed`.
+    pub fn closed(&self) -> Closed {
+        self.client.closed()
+    }
+}
+
+impl<Req, Resp, E> Clone for Peer<Req, Resp, E> {
+    fn clone(&self) -> Self {
+        Peer { client: self.client.clone() }
+    }
+}
+
+impl<Req, Resp, E: From<io::Error>> Service for Peer<Req, Resp, E> {
+    type Request = Req;
+    type Response = Resp;
+    type Error = E;
+    type Future = Response<Resp, E>;
+
+    fn call(&self, request: Req) -> Self::Future {
+        self.client.call(request)
+    }
+}
+
+struct Dispatch<T, S, Out, In, InBody, E, B> where
+    S: Service,
+{
+    transport: T,
+    // The service handling the requests of the other end
+    service: S,
+    // The requests of this end. Set to `None` once every `Peer` handle has
+    // been dropped.
+    requests: Option<Receiver<Message<Out, B>, Message<In, Body<InBody, E>>, E>>,
+    // The requests of this end that are waiting for a response
+    calls: HashMap<RequestId, Complete<Result<Message<In, Body<InBody, E>>, E>>>,
+    // Requests of this end whose response future was dropped. They are yet
+    // to be reported to the multiplexer.
+    canceled: VecDeque<RequestId>,
+    next_request_id: RequestId,
+    // The requests of the other end that are being handled by the service
+    in_flight: Vec<(RequestId, S::Future)>,
+    // The solo requests of the other end that are being handled by the
+    // service. Nothing is written once they are done.
+    notifications: Vec<(RequestId, S::Future)>,
+    max_in_flight: usize,
+}
+
+impl<T, S, Out, In, InBody, E, B> Dispatch<T, S, Out, In, InBody, E, B> where
+    S: Service,
+{
+    // Returns true if the request ID belongs to a request of this end
+    fn is_call(&self, id: RequestId) -> bool {
+        id % 2 == self.next_request_id % 2
+    }
+}
+
+impl<T, S, Out, In, InBody, E, B> super::advanced::Dispatch for Dispatch<T, S, Out, In, InBody, E, B> where
+    T: Transport<InBody,
+                 Item = Frame<In, InBody, E>,
+                 SinkItem = Frame<Out, B::Item, E>>,
+    S: Service<Request = Message<In, Body<InBody, E>>,
+               Response = Message<Out, B>,
+               Error = E>,
+    E: From<io::Error>,
+    B: Stream<Error = E>,
+{
+    type Io = ();
+    type In = Out;
+    type BodyIn = B::Item;
+    type Out = In;
+    type BodyOut = InBody;
+    type Error = E;
+    type Stream = B;
+    type Transport = T;
+
+    fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    fn poll(&mut self) -> Poll<Option<MultiplexMessage<Out, B, E>>, io::Error> {
+        trace!("Peer::poll");
+
+        let mut notified = false;
+        let mut i = 0;
+
+        while i < self.notifications.len() {
+            match self.notifications[i].1.poll() {
+                Ok(Async::NotReady) => i += 1,
+                _ => {
+                    let (request_id, _) = self.notifications.remove(i);
+                    trace!("   --> notification done; request_id={:?}", request_id);
+                    notified = true;
+                }
+            }
+        }
+
+        // Write the responses to the other end first
+        let mut idx = None;
+
+        for (i, &mut (request_id, ref mut response)) in self.in_flight.iter_mut().enumerate() {
+            trace!("   --> poll; request_id={:?}", request_id);
+
+            match response.poll() {
+                Ok(Async::Ready(message)) => idx = Some((i, Ok(message))),
+                Err(e) => idx = Some((i, Err(e))),
+                Ok(Async::NotReady) => continue,
+            }
+
+            break;
+        }
+
+        if let Some((idx, message)) = idx {
+            let (request_id, _) = self.in_flight.remove(idx);
+
+            return Ok(Async::Ready(Some(MultiplexMessage {
+                id: request_id,
+                message: message,
+                solo: false,
+            })));
+        }
+
+        if notified {
+            // A slot has been freed, which the multiplexer only notices on
+            // its next tick
+            task::park().unpark();
+        }
+
+        if self.calls.len() >= self.max_in_flight {
+            trace!("   --> in-flight limit reached");
+            return Ok(Async::NotReady);
+        }
+
+        let res = match self.requests {
+            Some(ref mut requests) => requests.poll(),
+            None => return Ok(Async::NotReady),
+        };
+
+        match res {
+            Ok(Async::Ready(Some(Ok((request, complete))))) => {
+                let request_id = self.next_request_id;
+                self.next_request_id += 2;
+
+                trace!("   --> assigning request-id={:?}", request_id);
+
+                self.calls.insert(request_id, complete);
+
+                Ok(Async::Ready(Some(MultiplexMessage::new(request_id, request))))
+            }
+            Ok(Async::Ready(None)) => {
+                trace!("   --> peer dropped");
+
+                // Keep serving the other end
+                self.requests = None;
+                Ok(Async::NotReady)
+            }
+            Ok(Async::Ready(Some(Err(e)))) => {
+                // The peer asked for the connection to be shut down
+                Err(e)
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(()) => {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "request channel failed"))
+            }
+        }
+    }
+
+    fn poll_ready(&self) -> Async<()> {
+        if self.in_flight.len() + self.notifications.len() < self.max_in_flight {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+
+    fn dispatch(&mut self, message: MultiplexMessage<In, Body<InBody, E>, E>) -> io::Result<()> {
+        let MultiplexMessage { id, message, solo } = message;
+
+        if self.is_call(id) {
+            if solo {
+                trace!("   --> dropping solo response; request-id={:?}", id);
+                return Ok(());
+            }
+
+            match self.calls.remove(&id) {
+                Some(complete) => complete.complete(message),
+                None if id < self.next_request_id => {
+                    // A late response to a canceled call that has since been
+                    // forgotten by the multiplexer
+                    trace!("   --> dropping stale response; request-id={:?}", id);
+                }
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "response does not match any request"));
+                }
+            }
+        } else if let Ok(request) = message {
+            assert!(self.poll_ready().is_ready());
+
+            let response = self.service.call(request);
+
+            if solo {
+                self.notifications.push((id, response));
+            } else {
+                self.in_flight.push((id, response));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cancel(&mut self, request_id: RequestId) -> io::Result<()> {
+        trace!("Peer::cancel; request_id={:?}", request_id);
+
+        if self.is_call(request_id) {
+            self.calls.remove(&request_id);
+        } else {
+            self.in_flight.retain(|&(id, _)| id != request_id);
+            self.notifications.retain(|&(id, _)| id != request_id);
+        }
+
+        self.transport.cancel(request_id)
+    }
+
+    fn poll_canceled(&mut self) -> Poll<RequestId, io::Error> {
+        if self.canceled.is_empty() {
+            let mut dropped = vec![];
+
+            for (&request_id, complete) in self.calls.iter_mut() {
+                if let Ok(Async::Ready(())) = complete.poll_cancel() {
+                    dropped.push(request_id);
+                }
+            }
+
+            for request_id in dropped {
+                trace!("   --> response dropped; request-id={:?}", request_id);
+
+                try!(self.transport.cancel(request_id));
+                self.calls.remove(&request_id);
+                self.canceled.push_back(request_id);
+            }
+        }
+
+        match self.canceled.pop_front() {
+            Some(request_id) => Ok(Async::Ready(request_id)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
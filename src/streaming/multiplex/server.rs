@@ -1,13 +1,15 @@
-use super::{Frame, RequestId, Transport};
+use super::{Frame, MultiplexConfig, RequestId, Transport};
 use super::advanced::{Multiplex, MultiplexMessage};
 
 use BindServer;
 use streaming::{Message, Body};
 use tokio_service::Service;
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Timeout};
 use futures::{Future, Poll, Async};
+use futures::task;
 use futures::{IntoFuture, Stream};
 use std::io;
+use std::time::Duration;
 
 /// A streaming, multiplexed server protocol.
 ///
@@ -35,6 +37,13 @@ use std::io;
 /// To deal with this, once the connection's frame buffer is filled, a timeout
 /// is set. If no further frames are able to be read before the timeout expires,
 /// the connection is killed.
+///
+/// ## Notifications
+///
+/// Requests received in solo frames are one-way notifications. They are
+/// handled by the same service as the other requests, and count toward the
+/// in-flight limit while the service works on them, but no response frame is
+/// written for them: the service's response, or error, is dropped.
 pub trait ServerProto<T: 'static>: 'static {
     /// Request headers.
     type Request: 'static;
@@ -65,6 +74,22 @@ pub trait ServerProto<T: 'static>: 'static {
     /// Build a transport from the given I/O object, using `self` for any
     /// configuration.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// How long the service may take to respond to a request.
+    ///
+    /// Requests that take longer are answered with an error instead. Returns
+    /// `None` by default, in which case requests never time out.
+    fn request_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// The limits of each connection, such as the number of requests that
+    /// can be in flight at once.
+    ///
+    /// Returns `MultiplexConfig::default()` by default.
+    fn multiplex_config(&self) -> MultiplexConfig {
+        MultiplexConfig::default()
+    }
 }
 
 impl<P, T, B> BindServer<super::StreamingMultiplex<B>, T> for P where
@@ -81,13 +106,21 @@ impl<P, T, B> BindServer<super::StreamingMultiplex<B>, T> for P where
                          Response = Self::ServiceResponse,
                          Error = Self::ServiceError> + 'static
     {
-        let task = self.bind_transport(io).into_future().and_then(|transport| {
+        let request_timeout = self.request_timeout();
+        let timer = handle.clone();
+        let config = self.multiplex_config();
+
+        let task = self.bind_transport(io).into_future().and_then(move |transport| {
             let dispatch: Dispatch<S, T, P> = Dispatch {
                 service: service,
                 transport: transport,
                 in_flight: vec![],
+                notifications: vec![],
+                max_in_flight: config.max_in_flight,
+                handle: timer,
+                request_timeout: request_timeout,
             };
-            Multiplex::new(dispatch)
+            Multiplex::with_config(dispatch, config)
         }).map_err(|_| ());
 
         // Spawn the multiplex dispatcher
@@ -102,16 +135,21 @@ struct Dispatch<S, T, P> where
     service: S,
     transport: P::Transport,
     in_flight: Vec<(RequestId, InFlight<S::Future>)>,
+    // Solo requests that the service is working on. Nothing is written once
+    // they are done.
+    notifications: Vec<(RequestId, InFlight<S::Future>)>,
+    // The total number of requests that can be in flight at once
+    max_in_flight: usize,
+    handle: Handle,
+    request_timeout: Option<Duration>,
 }
 
 enum InFlight<F: Future> {
-    Active(F),
+    Active(F, Option<Timeout>),
     Done(Result<F::Item, F::Error>),
+    TimedOut,
 }
 
-/// The total number of requests that can be in flight at once.
-const MAX_IN_FLIGHT_REQUESTS: usize = 32;
-
 impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
     P: ServerProto<T>,
     B: Stream<Item = P::ResponseBody, Error = P::Error>,
@@ -135,6 +173,19 @@ impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
     fn poll(&mut self) -> Poll<Option<MultiplexMessage<Self::In, B, Self::Error>>, io::Error> {
         trace!("Dispatch::poll");
 
+        let mut notified = false;
+        let mut i = 0;
+
+        while i < self.notifications.len() {
+            if self.notifications[i].1.poll() {
+                let (request_id, _) = self.notifications.remove(i);
+                trace!("   --> notification done; request_id={:?}", request_id);
+                notified = true;
+            } else {
+                i += 1;
+            }
+        }
+
         let mut idx = None;
 
         for (i, &mut (request_id, ref mut slot)) in self.in_flight.iter_mut().enumerate() {
@@ -146,6 +197,12 @@ impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
 
         if let Some(idx) = idx {
             let (request_id, message) = self.in_flight.remove(idx);
+
+            if let InFlight::TimedOut = message {
+                trace!("   --> request timed out; request_id={:?}", request_id);
+                try!(self.transport.cancel(request_id));
+            }
+
             let message = MultiplexMessage {
                 id: request_id,
                 message: message.unwrap_done(),
@@ -154,6 +211,12 @@ impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
 
             Ok(Async::Ready(Some(message)))
         } else {
+            if notified {
+                // A slot has been freed, which the multiplexer only notices
+                // on its next tick
+                task::park().unpark();
+            }
+
             Ok(Async::NotReady)
         }
     }
@@ -163,11 +226,19 @@ impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
 
         let MultiplexMessage { id, message, solo } = message;
 
-        assert!(!solo);
-
         if let Ok(request) = message {
+            let timeout = match self.request_timeout {
+                Some(dur) => Some(try!(Timeout::new(dur, &self.handle))),
+                None => None,
+            };
+
             let response = self.service.call(request);
-            self.in_flight.push((id, InFlight::Active(response)));
+
+            if solo {
+                self.notifications.push((id, InFlight::Active(response, timeout)));
+            } else {
+                self.in_flight.push((id, InFlight::Active(response, timeout)));
+            }
         }
 
         // TODO: Should the error be handled differently?
@@ -176,16 +247,36 @@ impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
     }
 
     fn poll_ready(&self) -> Async<()> {
-        if self.in_flight.len() < MAX_IN_FLIGHT_REQUESTS {
+        if self.in_flight.len() + self.notifications.len() < self.max_in_flight {
             Async::Ready(())
         } else {
             Async::NotReady
         }
     }
 
-    fn cancel(&mut self, _request_id: RequestId) -> io::Result<()> {
-        // TODO: implement
-        Ok(())
+    fn cancel(&mut self, request_id: RequestId) -> io::Result<()> {
+        trace!("Dispatch::cancel; request_id={:?}", request_id);
+
+        // Dropping the response future stops the work on the request, and
+        // frees its slot
+        self.in_flight.retain(|&(id, _)| id != request_id);
+        self.notifications.retain(|&(id, _)| id != request_id);
+        self.transport.cancel(request_id)
+    }
+
+    fn poll_canceled(&mut self) -> Poll<RequestId, io::Error> {
+        match self.transport.poll_canceled() {
+            Async::Ready(request_id) => {
+                trace!("   --> canceled by peer; request_id={:?}", request_id);
+
+                // The peer has already given up on the exchange, so there is
+                // nobody to tell
+                self.in_flight.retain(|&(id, _)| id != request_id);
+                self.notifications.retain(|&(id, _)| id != request_id);
+                Ok(Async::Ready(request_id))
+            }
+            Async::NotReady => Ok(Async::NotReady),
+        }
     }
 }
 
@@ -197,16 +288,26 @@ impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
 
 impl<F> InFlight<F>
     where F: Future,
+          F::Error: From<io::Error>,
 {
     // Returns true if done
     fn poll(&mut self) -> bool {
         let res = match *self {
-            InFlight::Active(ref mut f) => {
+            InFlight::Active(ref mut f, ref mut timeout) => {
                 trace!("   --> polling future");
                 match f.poll() {
                     Ok(Async::Ready(e)) => Ok(e),
                     Err(e) => Err(e),
-                    Ok(Async::NotReady) => return false,
+                    Ok(Async::NotReady) => {
+                        match poll_timeout(timeout) {
+                            Ok(Async::Ready(())) => {
+                                *self = InFlight::TimedOut;
+                                return true;
+                            }
+                            Err(e) => Err(e.into()),
+                            Ok(Async::NotReady) => return false,
+                        }
+                    }
                 }
             }
             _ => return true,
@@ -219,7 +320,19 @@ impl<F> InFlight<F>
     fn unwrap_done(self) -> Result<F::Item, F::Error> {
         match self {
             InFlight::Done(res) => res,
+            InFlight::TimedOut => Err(timed_out().into()),
             _ => panic!("future is not ready"),
         }
     }
 }
+
+fn poll_timeout(timeout: &mut Option<Timeout>) -> Poll<(), io::Error> {
+    match *timeout {
+        Some(ref mut timeout) => timeout.poll(),
+        None => Ok(Async::NotReady),
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "request timed out")
+}
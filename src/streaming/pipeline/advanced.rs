@@ -155,10 +155,8 @@ impl<T> Pipeline<T> where T: Dispatch {
                     // will get dropped. This terminates the stream.
                     self.out_body = Some(BufferOne::new(tx));
 
-                    if let Err(_) = self.dispatch.get_mut().inner.dispatch(Ok(message)) {
-                        // TODO: Should dispatch be infallible
-                        unimplemented!();
-                    }
+                    // An error shuts the connection down
+                    try!(self.dispatch.get_mut().inner.dispatch(Ok(message)));
                 } else {
                     trace!("read out message");
 
@@ -168,10 +166,8 @@ impl<T> Pipeline<T> where T: Dispatch {
                     // the previous body stream is dropped.
                     self.out_body = None;
 
-                    if let Err(_) = self.dispatch.get_mut().inner.dispatch(Ok(message)) {
-                        // TODO: Should dispatch be infalliable
-                        unimplemented!();
-                    }
+                    // An error shuts the connection down
+                    try!(self.dispatch.get_mut().inner.dispatch(Ok(message)));
                 }
             }
             Some(Frame::Body { chunk }) => {
@@ -326,7 +322,10 @@ impl<T> Pipeline<T> where T: Dispatch {
                         break;
                     }
                     Err(_) => {
-                        unimplemented!();
+                        // The peer cannot tell a body that is cut short from
+                        // the next message, so the connection is shut down.
+                        // On clients, this fails the outstanding requests.
+                        return Err(io::Error::new(io::ErrorKind::Other, "body stream failed"));
                     }
                     Ok(Async::NotReady) => {
                         debug!("not ready");
@@ -5,9 +5,10 @@ use super::advanced::{Pipeline, PipelineMessage};
 use util::client_proxy::{self, ClientProxy, Receiver};
 use futures::stream::Stream;
 use futures::{Future, IntoFuture, Complete, Poll, Async};
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Timeout};
 use std::collections::VecDeque;
 use std::io;
+use std::time::Duration;
 
 /// A streaming, pipelined client protocol.
 ///
@@ -46,6 +47,15 @@ pub trait ClientProto<T: 'static>: 'static {
     /// Build a transport from the given I/O object, using `self` for any
     /// configuration.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// How long to wait for the response to a request.
+    ///
+    /// Requests that take longer fail with an `io::ErrorKind::TimedOut` error,
+    /// converted to `Self::Error`. Returns `None` by default, in which case
+    /// requests never time out.
+    fn request_timeout(&self) -> Option<Duration> {
+        None
+    }
 }
 
 impl<P, T, B> BindClient<StreamingPipeline<B>, T> for P where
@@ -61,17 +71,25 @@ impl<P, T, B> BindClient<StreamingPipeline<B>, T> for P where
 
     fn bind_client(&self, handle: &Handle, io: T) -> Self::BindClient {
         let (client, rx) = client_proxy::pair();
+        let error = rx.error_handle();
 
-        let task = self.bind_transport(io).into_future().and_then(|transport| {
+        let request_timeout = self.request_timeout();
+        let timer = handle.clone();
+
+        let task = self.bind_transport(io).into_future().and_then(move |transport| {
             let dispatch: Dispatch<P, T, B> = Dispatch {
                 transport: transport,
                 requests: rx,
                 in_flight: VecDeque::with_capacity(32),
+                handle: timer,
+                request_timeout: request_timeout,
             };
             Pipeline::new(dispatch)
-        }).map_err(|e| {
-            // TODO: where to punt this error to?
+        }).map_err(move |e| {
             error!("pipeline error: {}", e);
+
+            // Fails the pending and future requests with the error
+            error.set(e);
         });
 
         // Spawn the task
@@ -89,7 +107,18 @@ struct Dispatch<P, T, B> where
 {
     transport: P::Transport,
     requests: Receiver<P::ServiceRequest, P::ServiceResponse, P::Error>,
-    in_flight: VecDeque<Complete<Result<P::ServiceResponse, P::Error>>>,
+    in_flight: VecDeque<InFlight<P::ServiceResponse, P::Error>>,
+    handle: Handle,
+    request_timeout: Option<Duration>,
+}
+
+// Dropping the complete handle of a pending request fails it with the error
+// that ended the connection, see `client_proxy::Response`
+struct InFlight<T, E> {
+    // Taken when the request times out. The slot is kept until the response
+    // arrives in order to keep responses matched up with their requests.
+    complete: Option<Complete<Result<T, E>>>,
+    timeout: Option<Timeout>,
 }
 
 impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
@@ -113,10 +142,17 @@ impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
                 response: PipelineMessage<Self::Out, Body<Self::BodyOut, Self::Error>, Self::Error>)
                 -> io::Result<()>
     {
-        if let Some(complete) = self.in_flight.pop_front() {
-            complete.complete(response);
+        if let Some(in_flight) = self.in_flight.pop_front() {
+            if let Some(complete) = in_flight.complete {
+                complete.complete(response);
+            } else {
+                trace!("   --> dropping response to timed out request");
+            }
         } else {
-            return Err(io::Error::new(io::ErrorKind::Other, "request / response mismatch"));
+            // The connection can no longer be trusted to match responses up
+            // with their requests. Failing the task fails all outstanding
+            // requests with the error.
+            return Err(unexpected_response());
         }
 
         Ok(())
@@ -126,13 +162,26 @@ impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
                                io::Error>
     {
         trace!("Dispatch::poll");
+
+        for in_flight in self.in_flight.iter_mut() {
+            try!(in_flight.poll_timeout());
+        }
+
         // Try to get a new request frame
         match self.requests.poll() {
             Ok(Async::Ready(Some(Ok((request, complete))))) => {
                 trace!("   --> received request");
 
+                let timeout = match self.request_timeout {
+                    Some(dur) => Some(try!(Timeout::new(dur, &self.handle))),
+                    None => None,
+                };
+
                 // Track complete handle
-                self.in_flight.push_back(complete);
+                self.in_flight.push_back(InFlight {
+                    complete: Some(complete),
+                    timeout: timeout,
+                });
 
                 Ok(Async::Ready(Some(Ok(request))))
 
@@ -143,16 +192,19 @@ impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
             }
             Ok(Async::Ready(Some(Err(e)))) => {
                 trace!("   --> error");
-                // An error on receive can only happen when the other half
-                // disconnected. In this case, the client needs to be
-                // shutdown
-                panic!("unimplemented error handling: {:?}", e);
+                // The client asked for the connection to be shut down. Failing
+                // the task fails all outstanding requests with the error.
+                Err(e)
             }
             Ok(Async::NotReady) => {
                 trace!("   --> not ready");
                 Ok(Async::NotReady)
             }
-            Err(()) => panic!(),
+            Err(()) => {
+                // The request channel never fails, but should it happen, the
+                // connection is shut down
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "request channel failed"))
+            }
         }
     }
 
@@ -161,19 +213,31 @@ impl<P, T, B> super::advanced::Dispatch for Dispatch<P, T, B> where
     }
 }
 
-impl<P, T, B> Drop for Dispatch<P, T, B> where
-    P: ClientProto<T> + BindClient<StreamingPipeline<B>, T>,
-    T: 'static,
-    B: Stream<Item = P::RequestBody, Error = P::Error> + 'static,
-{
-    fn drop(&mut self) {
-        // Complete any pending requests with an error
-        while let Some(complete) = self.in_flight.pop_front() {
-            complete.complete(Err(broken_pipe().into()));
+impl<T, E: From<io::Error>> InFlight<T, E> {
+    // Fails the request if its timeout fired
+    fn poll_timeout(&mut self) -> io::Result<()> {
+        let fired = match self.timeout {
+            Some(ref mut timeout) => try!(timeout.poll()).is_ready(),
+            None => false,
+        };
+
+        if fired {
+            trace!("   --> request timed out");
+            self.timeout = None;
+
+            if let Some(complete) = self.complete.take() {
+                complete.complete(Err(timed_out().into()));
+            }
         }
+
+        Ok(())
     }
 }
 
-fn broken_pipe() -> io::Error {
-    io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe")
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "request timed out")
+}
+
+fn unexpected_response() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "response does not match any request")
 }
@@ -6,8 +6,9 @@ use std::io;
 use streaming::{Message, Body};
 use super::advanced::{Pipeline, PipelineMessage};
 use super::{Frame, Transport};
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_service::Service;
+use std::time::Duration;
 
 // TODO:
 //
@@ -51,6 +52,14 @@ pub trait ServerProto<T: 'static>: 'static {
     /// Build a transport from the given I/O object, using `self` for any
     /// configuration.
     fn bind_transport(&self, io: T) -> Self::BindTransport;
+
+    /// How long the service may take to respond to a request.
+    ///
+    /// Requests that take longer are answered with an error instead. Returns
+    /// `None` by default, in which case requests never time out.
+    fn request_timeout(&self) -> Option<Duration> {
+        None
+    }
 }
 
 impl<P, T, B> BindServer<super::StreamingPipeline<B>, T> for P where
@@ -67,11 +76,16 @@ impl<P, T, B> BindServer<super::StreamingPipeline<B>, T> for P where
                          Response = Self::ServiceResponse,
                          Error = Self::ServiceError> + 'static
     {
-        let task = self.bind_transport(io).into_future().and_then(|transport| {
+        let request_timeout = self.request_timeout();
+        let timer = handle.clone();
+
+        let task = self.bind_transport(io).into_future().and_then(move |transport| {
             let dispatch: Dispatch<S, T, P> = Dispatch {
                 service: service,
                 transport: transport,
                 in_flight: VecDeque::with_capacity(32),
+                handle: timer,
+                request_timeout: request_timeout,
             };
             Pipeline::new(dispatch)
         });
@@ -88,10 +102,12 @@ struct Dispatch<S, T, P> where
     service: S,
     transport: P::Transport,
     in_flight: VecDeque<InFlight<S::Future>>,
+    handle: Handle,
+    request_timeout: Option<Duration>,
 }
 
 enum InFlight<F: Future> {
-    Active(F),
+    Active(F, Option<Timeout>),
     Done(Result<F::Item, F::Error>),
 }
 
@@ -121,8 +137,13 @@ impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
                 -> io::Result<()>
     {
         if let Ok(request) = request {
+            let timeout = match self.request_timeout {
+                Some(dur) => Some(try!(Timeout::new(dur, &self.handle))),
+                None => None,
+            };
+
             let response = self.service.call(request);
-            self.in_flight.push_back(InFlight::Active(response));
+            self.in_flight.push_back(InFlight::Active(response, timeout));
         }
 
         // TODO: Should the error be handled differently?
@@ -151,14 +172,20 @@ impl<P, T, B, S> super::advanced::Dispatch for Dispatch<S, T, P> where
     }
 }
 
-impl<F: Future> InFlight<F> {
+impl<F: Future> InFlight<F> where F::Error: From<io::Error> {
     fn poll(&mut self) {
         let res = match *self {
-            InFlight::Active(ref mut f) => {
+            InFlight::Active(ref mut f, ref mut timeout) => {
                 match f.poll() {
                     Ok(Async::Ready(e)) => Ok(e),
                     Err(e) => Err(e),
-                    Ok(Async::NotReady) => return,
+                    Ok(Async::NotReady) => {
+                        match poll_timeout(timeout) {
+                            Ok(Async::Ready(())) => Err(timed_out().into()),
+                            Err(e) => Err(e.into()),
+                            Ok(Async::NotReady) => return,
+                        }
+                    }
                 }
             }
             _ => return,
@@ -166,3 +193,14 @@ impl<F: Future> InFlight<F> {
         *self = InFlight::Done(res);
     }
 }
+
+fn poll_timeout(timeout: &mut Option<Timeout>) -> Poll<(), io::Error> {
+    match *timeout {
+        Some(ref mut timeout) => timeout.poll(),
+        None => Ok(Async::NotReady),
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "request timed out")
+}
@@ -1,18 +1,15 @@
+use std::collections::VecDeque;
 use std::io;
 use std::sync::Arc;
 use std::net::SocketAddr;
 use std::marker::PhantomData;
+use std::time::{Duration, Instant};
 
 use BindClient;
-use tokio_core::reactor::Handle;
+use tokio_core::reactor::{Handle, Timeout};
 use tokio_core::net::{TcpStream, TcpStreamNew};
 use futures::{Future, Poll, Async};
 
-// TODO: add configuration, e.g.:
-// - connection timeout
-// - multiple addresses
-// - request timeout
-
 // TODO: consider global event loop handle, so that providing one in the builder
 // is optional
 
@@ -26,6 +23,8 @@ use futures::{Future, Poll, Async};
 pub struct TcpClient<Kind, P> {
     _kind: PhantomData<Kind>,
     proto: Arc<P>,
+    connect_timeout: Option<Duration>,
+    attempt_delay: Option<Duration>,
 }
 
 /// A future for establishing a client connection.
@@ -34,8 +33,171 @@ pub struct TcpClient<Kind, P> {
 pub struct Connect<Kind, P> {
     _kind: PhantomData<Kind>,
     proto: Arc<P>,
-    socket: TcpStreamNew,
+    socket: ConnectSocket,
+}
+
+/// A future for establishing a client connection, which also yields the
+/// address of the server that was reached.
+///
+/// Returned by `Connect::with_addr`.
+pub struct ConnectWithAddr<Kind, P> {
+    inner: Connect<Kind, P>,
+}
+
+/// Establishes the socket behind a `Connect`, trying each address in turn.
+pub struct ConnectSocket {
     handle: Handle,
+    // Addresses that have not been tried yet
+    addrs: VecDeque<SocketAddr>,
+    // Connection attempts that are in progress
+    attempts: Vec<(SocketAddr, TcpStreamNew)>,
+    attempt_delay: Option<Duration>,
+    // Fires when the next address should be tried, even though the previous
+    // attempts are still in progress
+    next_attempt: Option<Timeout>,
+    deadline: Option<Instant>,
+    timeout: Option<Timeout>,
+    // The error of the last attempt that failed
+    error: Option<io::Error>,
+}
+
+impl<Kind, P> Connect<Kind, P> {
+    /// Yield the address the connection was established with, along with the
+    /// service.
+    pub fn with_addr(self) -> ConnectWithAddr<Kind, P> {
+        ConnectWithAddr { inner: self }
+    }
+}
+
+/// Gives the clients built on top of a `TcpClient` access to its protocol and
+/// configuration.
+pub trait Connector<P> {
+    /// Returns the protocol of the client.
+    fn protocol(&self) -> &P;
+
+    /// Returns a future for a socket connected to one of `addrs`, set up
+    /// according to the configuration of the client.
+    fn connect_socket(&self, addrs: &[SocketAddr], handle: &Handle) -> ConnectSocket;
+}
+
+impl<Kind, P> Connector<P> for TcpClient<Kind, P> {
+    fn protocol(&self) -> &P {
+        &self.proto
+    }
+
+    fn connect_socket(&self, addrs: &[SocketAddr], handle: &Handle) -> ConnectSocket {
+        ConnectSocket {
+            handle: handle.clone(),
+            addrs: addrs.iter().cloned().collect(),
+            attempts: Vec::new(),
+            attempt_delay: self.attempt_delay,
+            next_attempt: None,
+            deadline: self.connect_timeout.map(|timeout| Instant::now() + timeout),
+            timeout: None,
+            error: None,
+        }
+    }
+}
+
+impl ConnectSocket {
+    fn start(&mut self, addr: SocketAddr) -> io::Result<()> {
+        trace!("connecting; addr={}", addr);
+
+        let socket = TcpStream::connect(&addr, &self.handle);
+        self.attempts.push((addr, socket));
+
+        if let Some(delay) = self.attempt_delay {
+            if !self.addrs.is_empty() {
+                self.next_attempt = Some(try!(Timeout::new(delay, &self.handle)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Future for ConnectSocket {
+    type Item = (TcpStream, SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(TcpStream, SocketAddr), io::Error> {
+        if self.timeout.is_none() {
+            if let Some(deadline) = self.deadline {
+                self.timeout = Some(try!(Timeout::new_at(deadline, &self.handle)));
+            }
+        }
+
+        if let Some(ref mut timeout) = self.timeout {
+            if let Async::Ready(()) = try!(timeout.poll()) {
+                self.attempts.clear();
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "connection timed out"));
+            }
+        }
+
+        let mut start = self.attempts.is_empty();
+
+        loop {
+            if let Some(ref mut next_attempt) = self.next_attempt {
+                start = start || try!(next_attempt.poll()).is_ready();
+            }
+
+            if start {
+                start = false;
+                self.next_attempt = None;
+
+                match self.addrs.pop_front() {
+                    Some(addr) => {
+                        try!(self.start(addr));
+
+                        // Poll the new timer, so the task is woken up by it
+                        continue;
+                    }
+                    None if self.attempts.is_empty() => {
+                        return Err(self.error.take().unwrap_or_else(|| {
+                            io::Error::new(io::ErrorKind::InvalidInput,
+                                           "no addresses to connect to")
+                        }));
+                    }
+                    None => {}
+                }
+            }
+
+            let mut i = 0;
+
+            while i < self.attempts.len() {
+                match self.attempts[i].1.poll() {
+                    Ok(Async::Ready(socket)) => {
+                        let addr = self.attempts[i].0;
+
+                        // Drop the attempts that lost the race
+                        self.attempts.clear();
+                        return Ok(Async::Ready((socket, addr)));
+                    }
+                    Ok(Async::NotReady) => i += 1,
+                    Err(e) => {
+                        debug!("failed to connect; addr={}; err={}", self.attempts[i].0, e);
+                        drop(self.attempts.remove(i));
+                        self.error = Some(e);
+
+                        // Move on to the next address right away
+                        start = true;
+                    }
+                }
+            }
+
+            if !start {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+impl<Kind, P> Connect<Kind, P> where P: BindClient<Kind, TcpStream> {
+    fn poll_with_addr(&mut self) -> Poll<(P::BindClient, SocketAddr), io::Error> {
+        let (socket, addr) = try_ready!(self.socket.poll());
+        let client = self.proto.bind_client(&self.socket.handle, socket);
+        Ok(Async::Ready((client, addr)))
+    }
 }
 
 impl<Kind, P> Future for Connect<Kind, P> where P: BindClient<Kind, TcpStream> {
@@ -43,8 +205,17 @@ impl<Kind, P> Future for Connect<Kind, P> where P: BindClient<Kind, TcpStream> {
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<P::BindClient, io::Error> {
-        let socket = try_ready!(self.socket.poll());
-        Ok(Async::Ready(self.proto.bind_client(&self.handle, socket)))
+        let (client, _) = try_ready!(self.poll_with_addr());
+        Ok(Async::Ready(client))
+    }
+}
+
+impl<Kind, P> Future for ConnectWithAddr<Kind, P> where P: BindClient<Kind, TcpStream> {
+    type Item = (P::BindClient, SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(P::BindClient, SocketAddr), io::Error> {
+        self.inner.poll_with_addr()
     }
 }
 
@@ -56,10 +227,32 @@ impl<Kind, P> TcpClient<Kind, P> where P: BindClient<Kind, TcpStream> {
     pub fn new(protocol: P) -> TcpClient<Kind, P> {
         TcpClient {
             _kind: PhantomData,
-            proto: Arc::new(protocol)
+            proto: Arc::new(protocol),
+            connect_timeout: None,
+            attempt_delay: None,
         }
     }
 
+    /// Set the maximum amount of time spent establishing a connection.
+    ///
+    /// The timeout covers every address tried by `connect_any`. Once it
+    /// elapses, the `Connect` future fails with `ErrorKind::TimedOut`. By
+    /// default, connecting takes as long as the operating system allows.
+    pub fn connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = Some(timeout);
+    }
+
+    /// Set the delay after which `connect_any` tries the next address while
+    /// the previous attempts are still in progress.
+    ///
+    /// This staggers the attempts in the style of "Happy Eyeballs" (RFC
+    /// 8305): the first connection to be established is used, and the other
+    /// attempts are dropped. By default, an address is only tried once the
+    /// attempt on the previous one has failed.
+    pub fn attempt_delay(&mut self, delay: Duration) {
+        self.attempt_delay = Some(delay);
+    }
+
     /// Establish a connection to the given address.
     ///
     /// # Return value
@@ -68,11 +261,25 @@ impl<Kind, P> TcpClient<Kind, P> where P: BindClient<Kind, TcpStream> {
     /// future completes, it yields an instance of `Service` for interacting
     /// with the server.
     pub fn connect(&self, addr: &SocketAddr, handle: &Handle) -> Connect<Kind, P> {
+        self.connect_any(&[*addr], handle)
+    }
+
+    /// Establish a connection to the first reachable address out of `addrs`.
+    ///
+    /// The addresses are tried in order. See `attempt_delay` for trying them
+    /// in parallel. If no connection can be established, the future fails
+    /// with the error of the last attempt.
+    ///
+    /// # Return value
+    ///
+    /// Returns a future for the establishment of the connection, see
+    /// `connect`. Use `Connect::with_addr` to find out which address was
+    /// reached.
+    pub fn connect_any(&self, addrs: &[SocketAddr], handle: &Handle) -> Connect<Kind, P> {
         Connect {
             _kind: PhantomData,
             proto: self.proto.clone(),
-            socket: TcpStream::connect(addr, handle),
-            handle: handle.clone(),
+            socket: self.connect_socket(addrs, handle),
         }
     }
 }
@@ -1,23 +1,27 @@
+use std::cmp;
 use std::io;
 use std::marker::PhantomData;
-use std::net::SocketAddr;
+use std::net::{self, SocketAddr};
+#[cfg(unix)]
+use std::process::{Child, Command};
 use std::sync::Arc;
-use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use BindServer;
-use futures::stream::Stream;
+use {multiplex, streaming};
+use accept::{Listen, LimitPolicy};
+#[cfg(unix)]
+use listen_fds;
 use net2;
+use streaming::multiplex::MultiplexConfig;
+use server::{self, Config, Conn, FromNewService, MakeService, Socket, StdListener, WithInfo, Workers};
 use tokio_core::net::{TcpStream, TcpListener};
-use tokio_core::reactor::{Core, Handle};
-use tokio_service::NewService;
+use tokio_core::reactor::Handle;
+use tokio_service::{Service, NewService};
 
 // TODO: Add more options, e.g.:
 // - max concurrent requests
-// - request timeout
-// - read timeout
-// - write timeout
-// - max idle time
-// - max lifetime
 
 /// A builder for TCP servers.
 ///
@@ -36,8 +40,32 @@ pub struct TcpServer<Kind, P> {
     proto: Arc<P>,
     threads: usize,
     addr: SocketAddr,
+    // Listeners given to `from_listeners`, used instead of binding `addr`
+    listeners: Vec<net::TcpListener>,
+    config: Config,
+}
+
+/// A protocol served with the connection limits set by
+/// `TcpServer::multiplex_config`.
+pub struct WithMultiplexConfig<P> {
+    proto: Arc<P>,
+    config: MultiplexConfig,
 }
 
+/// Details about an accepted connection.
+///
+/// See `TcpServer::with_connection_info`.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    id: usize,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    accepted_at: Instant,
+}
+
+/// Source of the connection IDs handed out in `ConnectionInfo`.
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(0);
+
 impl<Kind, P> TcpServer<Kind, P> where
     P: BindServer<Kind, TcpStream> + Send + Sync + 'static
 {
@@ -60,12 +88,51 @@ impl<Kind, P> TcpServer<Kind, P> where
             proto: Arc::new(protocol),
             threads: 1,
             addr: addr,
+            listeners: Vec::new(),
+            config: Config::new(),
         }
     }
 
+    /// Starts building a server for the given protocol that accepts
+    /// connections from an existing listener, with default configuration.
+    ///
+    /// This is useful when the listening socket is set up by someone else,
+    /// such as a supervisor passing it down to the process (see
+    /// `listen_fds`). Every worker thread accepts connections from its own
+    /// clone of the listener.
+    pub fn from_listener(protocol: P, listener: net::TcpListener) -> io::Result<TcpServer<Kind, P>> {
+        TcpServer::from_listeners(protocol, vec![listener])
+    }
+
+    /// Starts building a server for the given protocol that accepts
+    /// connections from several existing listeners, with default
+    /// configuration.
+    ///
+    /// This is what a server started by `ServerHandle::spawn_successor`
+    /// receives from `listen_fds`. The listeners are spread over the worker
+    /// threads, and there is at least one worker per listener regardless of
+    /// the `threads` setting.
+    pub fn from_listeners(protocol: P, listeners: Vec<net::TcpListener>) -> io::Result<TcpServer<Kind, P>> {
+        let addr = match listeners.first() {
+            Some(listener) => try!(listener.local_addr()),
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "at least one listener is required"));
+            }
+        };
+
+        let mut server = TcpServer::new(protocol, addr);
+        server.listeners = listeners;
+        Ok(server)
+    }
+
     /// Set the address for the server.
+    ///
+    /// The server binds to the address instead of using the listeners it was
+    /// given in `from_listeners`, if any.
     pub fn addr(&mut self, addr: SocketAddr) {
         self.addr = addr;
+        self.listeners.clear();
     }
 
     /// Set the number of threads running simultaneous event loops (Unix only).
@@ -76,6 +143,112 @@ impl<Kind, P> TcpServer<Kind, P> where
         }
     }
 
+    /// Set how long to stop accepting connections after the listener fails
+    /// with an error such as running out of file descriptors.
+    ///
+    /// Errors that only concern the connection being accepted, such as the
+    /// peer resetting it, do not trigger a back off. The default is one second.
+    pub fn accept_backoff(&mut self, delay: Duration) {
+        self.config.accept.backoff = delay;
+    }
+
+    /// Set a function to be called with every error returned when accepting a
+    /// connection, for example to count them.
+    ///
+    /// The server keeps running regardless of the errors; see
+    /// `accept_backoff`.
+    pub fn on_accept_error<F>(&mut self, f: F) where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        self.config.accept.on_error = Some(Arc::new(f));
+    }
+
+    /// Set the maximum number of connections open at once, across all worker
+    /// threads.
+    ///
+    /// A connection counts toward the limit until the task serving it
+    /// completes. What happens to connections beyond the limit is set with
+    /// `limit_policy`. There is no limit by default.
+    pub fn max_connections(&mut self, max: usize) {
+        self.config.accept.max_connections = Some(max);
+    }
+
+    /// Set the maximum number of connections open at once on each worker
+    /// thread.
+    ///
+    /// This applies in addition to `max_connections`. There is no limit by
+    /// default.
+    pub fn max_connections_per_worker(&mut self, max: usize) {
+        self.config.accept.max_connections_per_worker = Some(max);
+    }
+
+    /// Set what happens to new connections once a connection limit has been
+    /// reached. The default is `LimitPolicy::Pause`.
+    pub fn limit_policy(&mut self, policy: LimitPolicy) {
+        self.config.accept.limit_policy = policy;
+    }
+
+    /// Set how long a connection may stay idle before it is closed.
+    ///
+    /// A connection is idle while no request is read from it and no response
+    /// is ready to be written to it, regardless of whether the service is
+    /// still working on requests. The time is measured from when the
+    /// connection was accepted or from when a request or response last moved,
+    /// whichever is later. There is no limit by default.
+    pub fn max_idle_time(&mut self, dur: Duration) {
+        self.config.max_idle_time = Some(dur);
+    }
+
+    /// Set how long the peer may go without sending a request before the
+    /// connection is closed.
+    ///
+    /// Unlike `max_idle_time`, responses do not count as activity. There is
+    /// no limit by default.
+    pub fn read_timeout(&mut self, dur: Duration) {
+        self.config.read_timeout = Some(dur);
+    }
+
+    /// Set how long writing to a connection may be stuck before the
+    /// connection is closed (Unix only).
+    ///
+    /// Writing is stuck while the send buffer of the socket is full, usually
+    /// because the peer does not read its responses. The socket is checked a
+    /// few times per `dur`. There is no limit by default.
+    pub fn write_timeout(&mut self, dur: Duration) {
+        self.config.write_timeout = Some(dur);
+    }
+
+    /// Set how long a connection may stay open.
+    ///
+    /// Once the time is up, the server stops reading requests from the
+    /// connection, answers the requests that are in flight and then closes
+    /// it. There is no limit by default.
+    pub fn max_lifetime(&mut self, dur: Duration) {
+        self.config.max_lifetime = Some(dur);
+    }
+
+    /// Set the limits of each connection for multiplexed protocols, such as
+    /// the number of requests that can be in flight at once.
+    ///
+    /// This takes precedence over the protocol's own `multiplex_config`.
+    /// Unlike the other settings, it wraps the protocol, so the builder is
+    /// consumed and returned with the wrapped protocol; the returned server
+    /// only serves `multiplex::ServerProto` and
+    /// `streaming::multiplex::ServerProto` protocols.
+    pub fn multiplex_config(self, config: MultiplexConfig) -> TcpServer<Kind, WithMultiplexConfig<P>> {
+        TcpServer {
+            _kind: PhantomData,
+            proto: Arc::new(WithMultiplexConfig {
+                proto: self.proto,
+                config: config,
+            }),
+            threads: self.threads,
+            addr: self.addr,
+            listeners: self.listeners,
+            config: self.config,
+        }
+    }
+
     /// Start up the server, providing the given service on it.
     ///
     /// This method will block the current thread until the server is shut down.
@@ -102,56 +275,227 @@ impl<Kind, P> TcpServer<Kind, P> where
                       Response = P::ServiceResponse,
                       Error = P::ServiceError> + Send + Sync + 'static,
     {
-        let proto = self.proto.clone();
+        self.start_with_handle(new_service).unwrap().wait()
+    }
+
+    /// Start up the server in the background, providing the given service on
+    /// it.
+    ///
+    /// Unlike `serve`, this method returns as soon as the listening sockets are
+    /// bound. The returned `ServerHandle` is used to shut the server down.
+    pub fn start<S>(&self, new_service: S) -> io::Result<ServerHandle> where
+        S: NewService<Request = P::ServiceRequest,
+                      Response = P::ServiceResponse,
+                      Error = P::ServiceError> + Send + Sync + 'static,
+    {
+        let new_service = Arc::new(new_service);
+        self.start_with_handle(move |_| new_service.clone())
+    }
+
+    /// Start up the server in the background, providing the given service on
+    /// it, and providing access to the event loop handle.
+    ///
+    /// See `with_handle` for details on the `new_service` argument, and `start`
+    /// for details on the return value.
+    pub fn start_with_handle<F, S>(&self, new_service: F) -> io::Result<ServerHandle> where
+        F: Fn(&Handle) -> S + Send + Sync + 'static,
+        S: NewService<Request = P::ServiceRequest,
+                      Response = P::ServiceResponse,
+                      Error = P::ServiceError> + Send + Sync + 'static,
+    {
+        self.start_workers(move |handle| FromNewService(new_service(handle)))
+    }
+
+    /// Start up the server, making a service for each connection out of the
+    /// details of the connection.
+    ///
+    /// The `new_service` argument is a closure that is given a
+    /// `ConnectionInfo` for each incoming connection, and produces the service
+    /// for it. Like with `NewService`, returning an error closes the
+    /// connection.
+    ///
+    /// This method will block the current thread until the server is shut down.
+    pub fn with_connection_info<F, S>(&self, new_service: F) where
+        F: Fn(&ConnectionInfo) -> io::Result<S> + Send + Sync + 'static,
+        S: Service<Request = P::ServiceRequest,
+                   Response = P::ServiceResponse,
+                   Error = P::ServiceError> + 'static,
+    {
+        self.start_with_connection_info(new_service).unwrap().wait()
+    }
+
+    /// Start up the server in the background, making a service for each
+    /// connection out of the details of the connection.
+    ///
+    /// See `with_connection_info` for details on the `new_service` argument,
+    /// and `start` for details on the return value.
+    pub fn start_with_connection_info<F, S>(&self, new_service: F) -> io::Result<ServerHandle> where
+        F: Fn(&ConnectionInfo) -> io::Result<S> + Send + Sync + 'static,
+        S: Service<Request = P::ServiceRequest,
+                   Response = P::ServiceResponse,
+                   Error = P::ServiceError> + 'static,
+    {
         let new_service = Arc::new(new_service);
-        let addr = self.addr;
-        let workers = self.threads;
+        self.start_workers(move |_| WithInfo(new_service.clone()))
+    }
+
+    fn start_workers<F, M>(&self, make_service: F) -> io::Result<ServerHandle> where
+        F: Fn(&Handle) -> M + Send + Sync + 'static,
+        M: MakeService<ConnectionInfo>,
+        M::Service: Service<Request = P::ServiceRequest,
+                            Response = P::ServiceResponse,
+                            Error = P::ServiceError> + 'static,
+    {
+        let (addr, listeners) = try!(self.listeners(self.threads));
+
+        // Kept around to be handed to a successor
+        let mut sockets = Vec::new();
+
+        for listener in if self.listeners.is_empty() { &listeners } else { &self.listeners } {
+            sockets.push(try!(listener.try_clone()));
+        }
+
+        let workers = try!(Workers::start(&self.proto, &self.config, listeners, make_service));
+
+        Ok(ServerHandle {
+            addr: addr,
+            workers: workers,
+            listeners: sockets,
+        })
+    }
 
-        let threads = (0..self.threads - 1).map(|i| {
-            let proto = proto.clone();
-            let new_service = new_service.clone();
+    /// Start up the server on an existing event loop, providing the given
+    /// service on it.
+    ///
+    /// Connections are accepted on the event loop behind `handle`, alongside
+    /// any other tasks running on it, and the `threads` setting is ignored.
+    /// The server runs for as long as the event loop does.
+    ///
+    /// Returns the local address that the server is bound to, which is useful
+    /// when the server was configured to bind to port 0.
+    pub fn spawn<S>(&self, handle: &Handle, new_service: S) -> io::Result<SocketAddr> where
+        Kind: 'static,
+        S: NewService<Request = P::ServiceRequest,
+                      Response = P::ServiceResponse,
+                      Error = P::ServiceError> + 'static,
+    {
+        let (addr, mut listeners) = try!(self.listeners(1));
+
+        try!(server::spawn(self.proto.clone(),
+                           self.config.clone(),
+                           listeners.remove(0),
+                           FromNewService(new_service),
+                           handle));
 
-            thread::Builder::new().name(format!("worker{}", i)).spawn(move || {
-                serve(proto, addr, workers, &*new_service)
-            }).unwrap()
-        }).collect::<Vec<_>>();
+        Ok(addr)
+    }
 
-        serve(proto, addr, workers, &*new_service);
+    /// Returns a listener for each of at least `workers` workers, along with
+    /// the address they are bound to.
+    fn listeners(&self, workers: usize) -> io::Result<(SocketAddr, Vec<net::TcpListener>)> {
+        if !self.listeners.is_empty() {
+            let workers = cmp::max(workers, self.listeners.len());
+            let mut listeners = Vec::with_capacity(workers);
 
-        for thread in threads {
-            thread.join().unwrap();
+            for i in 0..workers {
+                let inherited = &self.listeners[i % self.listeners.len()];
+                listeners.push(try!(inherited.try_clone()));
+            }
+
+            return Ok((self.addr, listeners));
         }
+
+        let mut listeners = Vec::with_capacity(workers);
+
+        let mut addr = self.addr;
+
+        for _ in 0..workers {
+            let listener = try!(listener(&addr, workers));
+
+            // When binding to port 0, the remaining listeners must share the
+            // port picked for the first one.
+            addr = try!(listener.local_addr());
+            listeners.push(listener);
+        }
+
+        Ok((addr, listeners))
     }
 }
 
-fn serve<P, Kind, F, S>(binder: Arc<P>, addr: SocketAddr, workers: usize, new_service: &F)
-    where P: BindServer<Kind, TcpStream>,
-          F: Fn(&Handle) -> S,
-          S: NewService<Request = P::ServiceRequest,
-                        Response = P::ServiceResponse,
-                        Error = P::ServiceError> + 'static,
-{
-    let mut core = Core::new().unwrap();
-    let handle = core.handle();
-    let new_service = new_service(&handle);
-    let listener = listener(&addr, workers, &handle).unwrap();
+/// A handle to a server running in the background.
+///
+/// Returned by `TcpServer::start`. Dropping the handle leaves the server
+/// running.
+pub struct ServerHandle {
+    addr: SocketAddr,
+    workers: Workers,
+    // Clones of the listening sockets
+    listeners: Vec<net::TcpListener>,
+}
+
+/// Accepts connections as `std` sockets, so that they can be cloned before
+/// they are registered with the event loop.
+pub struct Listener(TcpListener);
 
-    let server = listener.incoming().for_each(move |(socket, _)| {
-        // Create the service
-        let service = try!(new_service.new_service());
+/// A connection accepted by a `Listener`.
+pub struct Accepted {
+    socket: net::TcpStream,
+    info: ConnectionInfo,
+}
 
-        // Bind it!
-        binder.bind_server(&handle, socket, service);
+impl ServerHandle {
+    /// Returns the local address that the server is bound to.
+    ///
+    /// This is useful when the server was configured to bind to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
 
-        Ok(())
-    });
+    /// Stop the server immediately.
+    ///
+    /// All open connections are closed, dropping any requests that are still
+    /// in flight. This method blocks until every worker thread has exited.
+    pub fn shutdown(self) {
+        self.workers.shutdown()
+    }
 
-    core.run(server).unwrap();
+    /// Stop the server gracefully.
+    ///
+    /// Every worker stops accepting connections and stops reading new requests
+    /// from the connections that are open. Requests that are already in flight
+    /// are answered, after which the connection is closed. Connections that
+    /// are still open once `timeout` has elapsed are closed forcefully.
+    ///
+    /// This method blocks until every worker thread has exited.
+    pub fn shutdown_graceful(self, timeout: Duration) {
+        self.workers.shutdown_graceful(timeout)
+    }
+
+    /// Start a new process that takes over the listening sockets of the
+    /// server.
+    ///
+    /// The sockets are passed to `command` following the systemd socket
+    /// activation protocol, so the new process picks them up with
+    /// `listen_fds` and serves them with `TcpServer::from_listeners`. Both
+    /// processes accept connections from the sockets until this server is
+    /// shut down, usually with `shutdown_graceful` right after the new process
+    /// has been started. Connections that arrive in the meantime wait in the
+    /// listen backlog, so none are refused during the upgrade.
+    ///
+    /// The new process inherits the environment of this one. An error is
+    /// returned if variables have been set on `command`.
+    #[cfg(unix)]
+    pub fn spawn_successor(&self, command: &mut Command) -> io::Result<Child> {
+        listen_fds::spawn_with_fds(command, &self.listeners)
+    }
+
+    /// Block the current thread until the server stops running.
+    pub fn wait(self) {
+        self.workers.wait()
+    }
 }
 
-fn listener(addr: &SocketAddr,
-            workers: usize,
-            handle: &Handle) -> io::Result<TcpListener> {
+fn listener(addr: &SocketAddr, workers: usize) -> io::Result<net::TcpListener> {
     let listener = match *addr {
         SocketAddr::V4(_) => try!(net2::TcpBuilder::new_v4()),
         SocketAddr::V6(_) => try!(net2::TcpBuilder::new_v6()),
@@ -159,9 +503,7 @@ fn listener(addr: &SocketAddr,
     try!(configure_tcp(workers, &listener));
     try!(listener.reuse_address(true));
     try!(listener.bind(addr));
-    listener.listen(1024).and_then(|l| {
-        TcpListener::from_listener(l, addr, handle)
-    })
+    listener.listen(1024)
 }
 
 #[cfg(unix)]
@@ -179,3 +521,138 @@ fn configure_tcp(workers: usize, tcp: &net2::TcpBuilder) -> io::Result<()> {
 fn configure_tcp(_workers: usize, _tcp: &net2::TcpBuilder) -> io::Result<()> {
     Ok(())
 }
+
+impl StdListener for net::TcpListener {
+    type Listener = Listener;
+    type Conn = Accepted;
+
+    fn register(self, handle: &Handle) -> io::Result<Listener> {
+        let addr = try!(self.local_addr());
+        TcpListener::from_listener(self, &addr, handle).map(Listener)
+    }
+}
+
+impl Listen for Listener {
+    type Conn = Accepted;
+
+    fn accept(&mut self) -> io::Result<Accepted> {
+        loop {
+            let (socket, peer_addr) = try!(self.0.accept_std());
+
+            match socket.local_addr() {
+                Ok(local_addr) => {
+                    let info = ConnectionInfo {
+                        id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+                        peer_addr: peer_addr,
+                        local_addr: local_addr,
+                        accepted_at: Instant::now(),
+                    };
+
+                    return Ok(Accepted {
+                        socket: socket,
+                        info: info,
+                    });
+                }
+                Err(e) => {
+                    // The connection is most likely gone already
+                    debug!("failed to get local address; err={}", e);
+                }
+            }
+        }
+    }
+}
+
+impl ConnectionInfo {
+    /// Returns the ID of the connection, which is unique within the process.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Returns the address of the remote end of the connection.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Returns the local address the connection was accepted on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns when the connection was accepted.
+    pub fn accepted_at(&self) -> Instant {
+        self.accepted_at
+    }
+}
+
+impl Conn for Accepted {
+    type Io = TcpStream;
+    type Socket = net::TcpStream;
+    type Info = ConnectionInfo;
+
+    fn register(self, handle: &Handle) -> io::Result<(TcpStream, net::TcpStream, ConnectionInfo)> {
+        let socket = try!(self.socket.try_clone());
+        let io = try!(TcpStream::from_stream(self.socket, handle));
+        Ok((io, socket, self.info))
+    }
+}
+
+impl Socket for net::TcpStream {
+    fn shutdown(&self, how: net::Shutdown) -> io::Result<()> {
+        net::TcpStream::shutdown(self, how)
+    }
+
+    #[cfg(unix)]
+    fn is_writable(&self) -> io::Result<bool> {
+        server::is_writable(self)
+    }
+
+    #[cfg(windows)]
+    fn is_writable(&self) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+impl<T, P> multiplex::ServerProto<T> for WithMultiplexConfig<P> where
+    T: 'static, P: multiplex::ServerProto<T>
+{
+    type Request = P::Request;
+    type Response = P::Response;
+    type Transport = P::Transport;
+    type BindTransport = P::BindTransport;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        self.proto.bind_transport(io)
+    }
+
+    fn request_timeout(&self) -> Option<Duration> {
+        self.proto.request_timeout()
+    }
+
+    fn multiplex_config(&self) -> MultiplexConfig {
+        self.config.clone()
+    }
+}
+
+impl<T, P> streaming::multiplex::ServerProto<T> for WithMultiplexConfig<P> where
+    T: 'static, P: streaming::multiplex::ServerProto<T>
+{
+    type Request = P::Request;
+    type RequestBody = P::RequestBody;
+    type Response = P::Response;
+    type ResponseBody = P::ResponseBody;
+    type Error = P::Error;
+    type Transport = P::Transport;
+    type BindTransport = P::BindTransport;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        self.proto.bind_transport(io)
+    }
+
+    fn request_timeout(&self) -> Option<Duration> {
+        self.proto.request_timeout()
+    }
+
+    fn multiplex_config(&self) -> MultiplexConfig {
+        self.config.clone()
+    }
+}
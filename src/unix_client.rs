@@ -0,0 +1,41 @@
+use std::io;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+
+use BindClient;
+use tokio_core::reactor::Handle;
+use tokio_uds::UnixStream;
+
+/// Builds client connections to services listening on Unix domain sockets.
+///
+/// This is the Unix socket counterpart of `TcpClient`.
+pub struct UnixClient<Kind, P> {
+    _kind: PhantomData<Kind>,
+    proto: Arc<P>,
+}
+
+impl<Kind, P> UnixClient<Kind, P> where P: BindClient<Kind, UnixStream> {
+    /// Create a builder for the given client protocol.
+    ///
+    /// To connect to a service, you need a *client protocol* implementation;
+    /// see the crate documentation for guidance.
+    pub fn new(protocol: P) -> UnixClient<Kind, P> {
+        UnixClient {
+            _kind: PhantomData,
+            proto: Arc::new(protocol)
+        }
+    }
+
+    /// Establish a connection to the socket at the given path.
+    ///
+    /// # Return value
+    ///
+    /// Connecting to a Unix socket completes right away, so unlike
+    /// `TcpClient::connect`, this returns the instance of `Service` for
+    /// interacting with the server directly.
+    pub fn connect<A: AsRef<Path>>(&self, path: A, handle: &Handle) -> io::Result<P::BindClient> {
+        let socket = try!(UnixStream::connect(path, handle));
+        Ok(self.proto.bind_client(handle, socket))
+    }
+}
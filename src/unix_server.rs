@@ -0,0 +1,422 @@
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::Duration;
+
+use BindServer;
+use accept::{Listen, LimitPolicy};
+use libc;
+use server::{self, Config, Conn, FromNewService, Socket, StdListener, Workers};
+use tokio_core::reactor::Handle;
+use tokio_service::NewService;
+use tokio_uds::{UnixStream, UnixListener};
+
+/// A builder for Unix domain socket servers.
+///
+/// This is the Unix socket counterpart of `TcpServer`. Setting up a server
+/// needs a server protocol implementation, the path of the socket file and a
+/// service to provide.
+///
+/// A stale socket file left at the path, for example by a server that
+/// crashed, is removed when the server starts. The socket file is removed
+/// again when the server is shut down through its `UnixServerHandle`.
+pub struct UnixServer<Kind, P> {
+    _kind: PhantomData<Kind>,
+    proto: Arc<P>,
+    threads: usize,
+    path: PathBuf,
+    mode: Option<u32>,
+    config: Config,
+}
+
+impl<Kind, P> UnixServer<Kind, P> where
+    P: BindServer<Kind, UnixStream> + Send + Sync + 'static
+{
+    /// Starts building a server for the given protocol and socket path, with
+    /// default configuration.
+    ///
+    /// See `TcpServer::new` for details on the protocol.
+    pub fn new<A: AsRef<Path>>(protocol: P, path: A) -> UnixServer<Kind, P> {
+        UnixServer {
+            _kind: PhantomData,
+            proto: Arc::new(protocol),
+            threads: 1,
+            path: path.as_ref().to_path_buf(),
+            mode: None,
+            config: Config::new(),
+        }
+    }
+
+    /// Set the path of the socket file.
+    pub fn path<A: AsRef<Path>>(&mut self, path: A) {
+        self.path = path.as_ref().to_path_buf();
+    }
+
+    /// Set the number of threads running simultaneous event loops.
+    ///
+    /// All threads accept connections from the same socket.
+    pub fn threads(&mut self, threads: usize) {
+        assert!(threads > 0);
+        self.threads = threads;
+    }
+
+    /// Set the permissions of the socket file, e.g. `0o660`.
+    ///
+    /// Connecting to a Unix socket requires write permission on the socket
+    /// file. The socket is bound in a private directory and only moved to
+    /// `path` once it has these permissions. By default, the permissions are
+    /// left to the process umask.
+    pub fn mode(&mut self, mode: u32) {
+        self.mode = Some(mode);
+    }
+
+    /// Set how long to stop accepting connections after an accept error.
+    ///
+    /// See `TcpServer::accept_backoff`. The default is one second.
+    pub fn accept_backoff(&mut self, delay: Duration) {
+        self.config.accept.backoff = delay;
+    }
+
+    /// Set a function to call with each error returned when accepting a
+    /// connection.
+    ///
+    /// See `TcpServer::on_accept_error`.
+    pub fn on_accept_error<F>(&mut self, f: F) where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        self.config.accept.on_error = Some(Arc::new(f));
+    }
+
+    /// Set the maximum number of connections open at once, across all
+    /// threads.
+    ///
+    /// See `TcpServer::max_connections`. There is no limit by default.
+    pub fn max_connections(&mut self, max: usize) {
+        self.config.accept.max_connections = Some(max);
+    }
+
+    /// Set the maximum number of connections open at once on each thread.
+    ///
+    /// See `TcpServer::max_connections_per_worker`. There is no limit by
+    /// default.
+    pub fn max_connections_per_worker(&mut self, max: usize) {
+        self.config.accept.max_connections_per_worker = Some(max);
+    }
+
+    /// Set what happens to new connections once a connection limit is
+    /// reached. The default is `LimitPolicy::Pause`.
+    pub fn limit_policy(&mut self, policy: LimitPolicy) {
+        self.config.accept.limit_policy = policy;
+    }
+
+    /// Set how long a connection may stay idle before it is closed.
+    ///
+    /// See `TcpServer::max_idle_time`. There is no limit by default.
+    pub fn max_idle_time(&mut self, dur: Duration) {
+        self.config.max_idle_time = Some(dur);
+    }
+
+    /// Set how long the peer may go without sending a request before the
+    /// connection is closed.
+    ///
+    /// See `TcpServer::read_timeout`. There is no limit by default.
+    pub fn read_timeout(&mut self, dur: Duration) {
+        self.config.read_timeout = Some(dur);
+    }
+
+    /// Set how long writing to a connection may be stuck before the
+    /// connection is closed.
+    ///
+    /// See `TcpServer::write_timeout`. There is no limit by default.
+    pub fn write_timeout(&mut self, dur: Duration) {
+        self.config.write_timeout = Some(dur);
+    }
+
+    /// Set how long a connection may stay open.
+    ///
+    /// See `TcpServer::max_lifetime`. There is no limit by default.
+    pub fn max_lifetime(&mut self, dur: Duration) {
+        self.config.max_lifetime = Some(dur);
+    }
+
+    /// Start up the server, providing the given service on it.
+    ///
+    /// This method will block the current thread until the server is shut down.
+    pub fn serve<S>(&self, new_service: S) where
+        S: NewService<Request = P::ServiceRequest,
+                      Response = P::ServiceResponse,
+                      Error = P::ServiceError> + Send + Sync + 'static,
+    {
+        let new_service = Arc::new(new_service);
+        self.with_handle(move |_| new_service.clone())
+    }
+
+    /// Start up the server, providing the given service on it, and providing
+    /// access to the event loop handle.
+    ///
+    /// See `TcpServer::with_handle` for details on the `new_service` argument.
+    ///
+    /// This method will block the current thread until the server is shut down.
+    pub fn with_handle<F, S>(&self, new_service: F) where
+        F: Fn(&Handle) -> S + Send + Sync + 'static,
+        S: NewService<Request = P::ServiceRequest,
+                      Response = P::ServiceResponse,
+                      Error = P::ServiceError> + Send + Sync + 'static,
+    {
+        self.start_with_handle(new_service).unwrap().wait()
+    }
+
+    /// Start up the server in the background, providing the given service on
+    /// it.
+    ///
+    /// Returns as soon as the socket is bound. The returned
+    /// `UnixServerHandle` is used to shut the server down.
+    pub fn start<S>(&self, new_service: S) -> io::Result<UnixServerHandle> where
+        S: NewService<Request = P::ServiceRequest,
+                      Response = P::ServiceResponse,
+                      Error = P::ServiceError> + Send + Sync + 'static,
+    {
+        let new_service = Arc::new(new_service);
+        self.start_with_handle(move |_| new_service.clone())
+    }
+
+    /// Start up the server in the background, providing the given service on
+    /// it, and providing access to the event loop handle.
+    ///
+    /// See `with_handle` for details on the `new_service` argument, and `start`
+    /// for details on the return value.
+    pub fn start_with_handle<F, S>(&self, new_service: F) -> io::Result<UnixServerHandle> where
+        F: Fn(&Handle) -> S + Send + Sync + 'static,
+        S: NewService<Request = P::ServiceRequest,
+                      Response = P::ServiceResponse,
+                      Error = P::ServiceError> + Send + Sync + 'static,
+    {
+        let listener = try!(self.listener());
+        let mut listeners = Vec::with_capacity(self.threads);
+
+        for _ in 0..self.threads {
+            match listener.try_clone() {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => {
+                    remove_socket(&self.path);
+                    return Err(e);
+                }
+            }
+        }
+
+        let workers = Workers::start(&self.proto,
+                                     &self.config,
+                                     listeners,
+                                     move |handle| FromNewService(new_service(handle)));
+
+        match workers {
+            Ok(workers) => {
+                Ok(UnixServerHandle {
+                    path: self.path.clone(),
+                    workers: workers,
+                })
+            }
+            Err(e) => {
+                remove_socket(&self.path);
+                Err(e)
+            }
+        }
+    }
+
+    /// Start up the server on an existing event loop, providing the given
+    /// service on it.
+    ///
+    /// Connections are accepted on the event loop behind `handle`, and the
+    /// `threads` setting is ignored. The server runs for as long as the event
+    /// loop does, and the socket file is left behind when it stops.
+    pub fn spawn<S>(&self, handle: &Handle, new_service: S) -> io::Result<()> where
+        Kind: 'static,
+        S: NewService<Request = P::ServiceRequest,
+                      Response = P::ServiceResponse,
+                      Error = P::ServiceError> + 'static,
+    {
+        let listener = try!(self.listener());
+
+        server::spawn(self.proto.clone(),
+                      self.config.clone(),
+                      listener,
+                      FromNewService(new_service),
+                      handle)
+    }
+
+    fn listener(&self) -> io::Result<net::UnixListener> {
+        try!(remove_stale_socket(&self.path));
+
+        match self.mode {
+            Some(mode) => bind_with_mode(&self.path, mode),
+            None => net::UnixListener::bind(&self.path),
+        }
+    }
+}
+
+/// A handle to a Unix domain socket server running in the background.
+///
+/// Returned by `UnixServer::start`. Dropping the handle leaves the server
+/// running.
+pub struct UnixServerHandle {
+    path: PathBuf,
+    workers: Workers,
+}
+
+impl UnixServerHandle {
+    /// Returns the path of the socket file the server is bound to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stop the server immediately and remove its socket file.
+    ///
+    /// See `ServerHandle::shutdown`.
+    pub fn shutdown(self) {
+        self.workers.shutdown();
+        remove_socket(&self.path);
+    }
+
+    /// Stop the server gracefully and remove its socket file.
+    ///
+    /// See `ServerHandle::shutdown_graceful`. The socket file is removed once
+    /// every worker thread has exited.
+    pub fn shutdown_graceful(self, timeout: Duration) {
+        self.workers.shutdown_graceful(timeout);
+        remove_socket(&self.path);
+    }
+
+    /// Block the current thread until the server stops running, then remove
+    /// its socket file.
+    pub fn wait(self) {
+        self.workers.wait();
+        remove_socket(&self.path);
+    }
+}
+
+/// Removes the socket file at `path`, if it is left over from a server that is
+/// no longer running.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    // Never remove something that is not a socket
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                  "path exists and is not a socket"));
+    }
+
+    match net::UnixStream::connect(path) {
+        Ok(_) => {
+            Err(io::Error::new(io::ErrorKind::AddrInUse,
+                               "another server is listening on the socket"))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            debug!("removing stale socket file; path={:?}", path);
+            fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn remove_socket(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        debug!("failed to remove socket file; path={:?}; err={}", path, e);
+    }
+}
+
+/// Binds a listener at `path` whose socket file has the permissions in
+/// `mode` from the start.
+///
+/// The socket is bound in a new directory that only the current user can
+/// access, so nobody can connect to it before its permissions are set. It is
+/// then linked to `path`, which fails if something appeared there in the
+/// meantime.
+fn bind_with_mode(path: &Path, mode: u32) -> io::Result<net::UnixListener> {
+    static NEXT_DIR: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid socket path")),
+    };
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let dir = parent.join(format!(".{}.{}.{}",
+                                  name,
+                                  process::id(),
+                                  NEXT_DIR.fetch_add(1, Ordering::Relaxed)));
+    try!(fs::DirBuilder::new().mode(0o700).create(&dir));
+
+    let tmp = dir.join(&name);
+    let res = net::UnixListener::bind(&tmp).and_then(|listener| {
+        try!(fs::set_permissions(&tmp, fs::Permissions::from_mode(mode)));
+        try!(fs::hard_link(&tmp, path));
+        Ok(listener)
+    });
+
+    drop(fs::remove_file(&tmp));
+    drop(fs::remove_dir(&dir));
+
+    res
+}
+
+impl StdListener for net::UnixListener {
+    type Listener = UnixListener;
+    type Conn = UnixStream;
+
+    fn register(self, handle: &Handle) -> io::Result<UnixListener> {
+        UnixListener::from_listener(self, handle)
+    }
+}
+
+impl Listen for UnixListener {
+    type Conn = UnixStream;
+
+    fn accept(&mut self) -> io::Result<UnixStream> {
+        UnixListener::accept(self).map(|(socket, _)| socket)
+    }
+}
+
+impl Conn for UnixStream {
+    type Io = UnixStream;
+    type Socket = net::UnixStream;
+    type Info = ();
+
+    fn register(self, _handle: &Handle) -> io::Result<(UnixStream, net::UnixStream, ())> {
+        // The stream is registered with the event loop as it is accepted.
+        // Keep a duplicate of its descriptor to shut it down with.
+        let fd = unsafe { libc::fcntl(self.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let socket = unsafe { net::UnixStream::from_raw_fd(fd) };
+        Ok((self, socket, ()))
+    }
+}
+
+impl Socket for net::UnixStream {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        net::UnixStream::shutdown(self, how)
+    }
+
+    fn is_writable(&self) -> io::Result<bool> {
+        server::is_writable(self)
+    }
+}
@@ -0,0 +1,303 @@
+//! Load balancing over several backends
+//!
+//! `Balancer` spreads the requests made to it over a set of services,
+//! typically one client connection per backend server, and stops using the
+//! backends that keep failing.
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Poll, Async};
+use rand::{self, Rng};
+use tokio_service::Service;
+
+/// How a `Balancer` picks the backend for a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Use the backends in turn.
+    RoundRobin,
+    /// Use the backend with the fewest outstanding requests.
+    LeastOutstanding,
+    /// Pick two backends at random, and use the one with fewer outstanding
+    /// requests.
+    PowerOfTwoChoices,
+}
+
+/// A `Service` that spreads requests over several backend services.
+///
+/// A backend is ejected after `eject_after` consecutive requests to it have
+/// failed, and receives no requests for the `eject_for` period. After that,
+/// the next request picked for the backend is sent as a probe: the backend
+/// is put back into rotation if the probe succeeds, and ejected again if it
+/// fails.
+///
+/// Every failed request counts as a transport error, so this is meant for
+/// client services whose errors come from the connection, like the ones
+/// bound by `TcpClient`.
+pub struct Balancer<S> {
+    backends: Vec<S>,
+    health: Rc<RefCell<Vec<Health>>>,
+    policy: Policy,
+    // The backend to start looking from
+    next: Cell<usize>,
+    eject_after: usize,
+    eject_for: Duration,
+}
+
+/// Response future returned from a `Balancer`.
+pub struct BalancerResponse<F> where F: Future {
+    inner: Result<F, Option<F::Error>>,
+    outcome: Option<Outcome>,
+}
+
+struct Health {
+    outstanding: usize,
+    // Requests that failed in a row
+    failures: usize,
+    ejected_until: Option<Instant>,
+    // Set while a probe request is in flight
+    probing: bool,
+}
+
+// Reports the outcome of a request to the health of its backend
+struct Outcome {
+    health: Rc<RefCell<Vec<Health>>>,
+    backend: usize,
+    probe: bool,
+    eject_after: usize,
+    eject_for: Duration,
+}
+
+impl<S> Balancer<S> where S: Service, S::Error: From<io::Error> {
+    /// Create a balancer over the given backends, using `policy` to pick
+    /// among them.
+    pub fn new(backends: Vec<S>, policy: Policy) -> Balancer<S> {
+        let health = backends.iter().map(|_| {
+            Health {
+                outstanding: 0,
+                failures: 0,
+                ejected_until: None,
+                probing: false,
+            }
+        }).collect();
+
+        Balancer {
+            backends: backends,
+            health: Rc::new(RefCell::new(health)),
+            policy: policy,
+            next: Cell::new(0),
+            eject_after: 5,
+            eject_for: Duration::from_secs(10),
+        }
+    }
+
+    /// Set the number of consecutive failed requests after which a backend
+    /// is ejected.
+    ///
+    /// Default is 5.
+    pub fn eject_after(&mut self, failures: usize) {
+        assert!(failures > 0);
+        self.eject_after = failures;
+    }
+
+    /// Set how long an ejected backend receives no requests, before it is
+    /// probed again.
+    ///
+    /// Default is 10 seconds.
+    pub fn eject_for(&mut self, dur: Duration) {
+        self.eject_for = dur;
+    }
+
+    /// Returns the backends.
+    pub fn backends(&self) -> &[S] {
+        &self.backends
+    }
+
+    /// Returns true if the backend at `index` is currently ejected.
+    pub fn is_ejected(&self, index: usize) -> bool {
+        self.health.borrow()[index].ejected_until.is_some()
+    }
+
+    /// Returns the number of outstanding requests to the backend at `index`.
+    pub fn outstanding(&self, index: usize) -> usize {
+        self.health.borrow()[index].outstanding
+    }
+
+    fn pick(&self, health: &[Health]) -> Option<usize> {
+        let now = Instant::now();
+        let len = health.len();
+
+        if len == 0 {
+            return None;
+        }
+
+        let start = self.next.get() % len;
+        self.next.set(start + 1);
+
+        let mut available = (0..len)
+            .map(|i| (start + i) % len)
+            .filter(|&i| health[i].is_available(now));
+
+        match self.policy {
+            Policy::RoundRobin => {
+                let picked = available.next();
+
+                if let Some(i) = picked {
+                    self.next.set(i + 1);
+                }
+
+                picked
+            }
+            Policy::LeastOutstanding => {
+                available.min_by_key(|&i| health[i].outstanding)
+            }
+            Policy::PowerOfTwoChoices => {
+                let available = available.collect::<Vec<_>>();
+                let mut rng = rand::thread_rng();
+
+                match available.len() {
+                    0 => None,
+                    1 => Some(available[0]),
+                    n => {
+                        let a = rng.gen_range(0, n);
+                        let b = (a + rng.gen_range(1, n)) % n;
+                        let (a, b) = (available[a], available[b]);
+
+                        if health[b].outstanding < health[a].outstanding {
+                            Some(b)
+                        } else {
+                            Some(a)
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<S> Service for Balancer<S> where S: Service, S::Error: From<io::Error> {
+    type Request = S::Request;
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BalancerResponse<S::Future>;
+
+    fn call(&self, request: S::Request) -> Self::Future {
+        let mut health = self.health.borrow_mut();
+
+        let i = match self.pick(&health) {
+            Some(i) => i,
+            None => {
+                let err = io::Error::new(io::ErrorKind::NotConnected, "no backends available");
+
+                return BalancerResponse {
+                    inner: Err(Some(err.into())),
+                    outcome: None,
+                };
+            }
+        };
+
+        let probe = health[i].ejected_until.is_some();
+
+        if probe {
+            trace!("probing ejected backend; index={}", i);
+            health[i].probing = true;
+        }
+
+        health[i].outstanding += 1;
+        drop(health);
+
+        BalancerResponse {
+            inner: Ok(self.backends[i].call(request)),
+            outcome: Some(Outcome {
+                health: self.health.clone(),
+                backend: i,
+                probe: probe,
+                eject_after: self.eject_after,
+                eject_for: self.eject_for,
+            }),
+        }
+    }
+}
+
+impl Health {
+    fn is_available(&self, now: Instant) -> bool {
+        match self.ejected_until {
+            None => true,
+            // Only a single probe at a time
+            Some(until) => until <= now && !self.probing,
+        }
+    }
+}
+
+impl Outcome {
+    fn report(self, success: Option<bool>) {
+        let mut health = self.health.borrow_mut();
+        let health = &mut health[self.backend];
+
+        health.outstanding -= 1;
+
+        if self.probe {
+            health.probing = false;
+        }
+
+        match success {
+            Some(true) => {
+                if health.ejected_until.take().is_some() {
+                    debug!("backend recovered; index={}", self.backend);
+                }
+
+                health.failures = 0;
+            }
+            Some(false) => {
+                health.failures += 1;
+
+                // A failed probe ejects the backend again right away
+                let eject = self.probe || (health.ejected_until.is_none() &&
+                                           health.failures >= self.eject_after);
+
+                if eject {
+                    debug!("ejecting backend; index={}", self.backend);
+                    health.ejected_until = Some(Instant::now() + self.eject_for);
+                }
+            }
+            // The response future was dropped
+            None => {}
+        }
+    }
+}
+
+impl<F> Future for BalancerResponse<F> where F: Future {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let res = match self.inner {
+            Ok(ref mut future) => future.poll(),
+            Err(ref mut err) => {
+                return Err(err.take().expect("cannot poll BalancerResponse twice"));
+            }
+        };
+
+        let success = match res {
+            Ok(Async::NotReady) => return res,
+            Ok(Async::Ready(_)) => true,
+            Err(_) => false,
+        };
+
+        if let Some(outcome) = self.outcome.take() {
+            outcome.report(Some(success));
+        }
+
+        res
+    }
+}
+
+impl<F> Drop for BalancerResponse<F> where F: Future {
+    fn drop(&mut self) {
+        if let Some(outcome) = self.outcome.take() {
+            outcome.report(None);
+        }
+    }
+}
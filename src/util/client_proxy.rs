@@ -16,47 +16,177 @@ use tokio_service::Service;
 use futures::{Future, Async, Poll, Stream, AsyncSink, Sink};
 use futures::sync::mpsc;
 use futures::sync::oneshot;
+use futures::task::{self, Task};
 use std::io;
 use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
 
 /// Client `Service` for pipeline or multiplex protocols
 pub struct ClientProxy<R, S, E> {
-    tx: RefCell<mpsc::UnboundedSender<io::Result<Envelope<R, S, E>>>>,
+    tx: RefCell<Sender<R, S, E>>,
+    state: Arc<Mutex<State>>,
+}
+
+enum Sender<R, S, E> {
+    Unbounded(mpsc::UnboundedSender<io::Result<Request<R, S, E>>>),
+    Bounded(mpsc::Sender<io::Result<Request<R, S, E>>>),
 }
 
 impl<R, S, E> Clone for ClientProxy<R, S, E> {
     fn clone(&self) -> Self {
+        let tx = match *self.tx.borrow() {
+            Sender::Unbounded(ref tx) => Sender::Unbounded(tx.clone()),
+            Sender::Bounded(ref tx) => Sender::Bounded(tx.clone()),
+        };
+
         ClientProxy {
-            tx: RefCell::new(self.tx.borrow().clone()),
+            tx: RefCell::new(tx),
+            state: self.state.clone(),
         }
     }
 }
 
+/// Thread-safe client `Service` for pipeline or multiplex protocols
+///
+/// Unlike `ClientProxy`, this handle is `Send` and `Sync` for `Send` request
+/// and response types, so requests can be made from any thread. The requests
+/// are processed by the connection's dispatcher, on the event loop the
+/// connection was established on.
+pub struct SyncClient<R, S, E> {
+    tx: Arc<Mutex<Sender<R, S, E>>>,
+    state: Arc<Mutex<State>>,
+}
+
 /// Response future returned from a client
+///
+/// Dropping the future tells the connection that the response is no longer
+/// wanted. Multiplexed connections cancel the request in that case.
 pub struct Response<T, E> {
     inner: oneshot::Receiver<Result<T, E>>,
+    state: Arc<Mutex<State>>,
+}
+
+/// Future returned by `ClientProxy::closed`
+///
+/// Resolves once the connection has ended. Fails with the error that ended the
+/// connection, if any.
+pub struct Closed {
+    state: Arc<Mutex<State>>,
+}
+
+/// Records the error that ended a connection
+///
+/// Returned by `Receiver::error_handle`.
+pub struct ErrorHandle {
+    state: Arc<Mutex<State>>,
+}
+
+/// The state of the connection, shared by both ends of the channel
+struct State {
+    // True once the receiving end has been dropped
+    closed: bool,
+    // The error that ended the connection
+    error: Option<io::Error>,
+    // Tasks waiting for the connection to end
+    waiters: Vec<Task>,
 }
 
 /// Message used to dispatch requests to the task managing the client
 /// connection.
 type Envelope<R, S, E> = (R, oneshot::Sender<Result<S, E>>);
 
+/// A request submitted to the client, as returned by `Receiver::poll_request`
+pub enum Request<R, S, E> {
+    /// A request that expects a response, made with `call`
+    Call(R, oneshot::Sender<Result<S, E>>),
+    /// A one-way notification, made with `notify`, to be completed once it
+    /// has been written
+    Notify(R, oneshot::Sender<Result<(), E>>),
+}
+
 /// A client / receiver pair
 pub type Pair<R, S, E> = (ClientProxy<R, S, E>, Receiver<R, S, E>);
 
 /// Receive requests submitted to the client
-pub type Receiver<R, S, E> = mpsc::UnboundedReceiver<io::Result<Envelope<R, S, E>>>;
+pub struct Receiver<R, S, E> {
+    rx: Rx<R, S, E>,
+    state: Arc<Mutex<State>>,
+}
+
+enum Rx<R, S, E> {
+    Unbounded(mpsc::UnboundedReceiver<io::Result<Request<R, S, E>>>),
+    Bounded(mpsc::Receiver<io::Result<Request<R, S, E>>>),
+}
 
 /// Return a client handle and a handle used to receive requests on
 pub fn pair<R, S, E>() -> Pair<R, S, E> {
     // Create a stream
     let (tx, rx) = mpsc::unbounded();
 
+    let state = State::new();
+
     // Use the sender handle to create a `Client` handle
-    let client = ClientProxy { tx: RefCell::new(tx) };
+    let client = ClientProxy {
+        tx: RefCell::new(Sender::Unbounded(tx)),
+        state: state.clone(),
+    };
 
     // Return the pair
-    (client, rx)
+    (client, Receiver { rx: Rx::Unbounded(rx), state: state })
+}
+
+/// Return a client handle and a handle used to receive requests on, where at
+/// most `capacity` requests are queued up between the two.
+///
+/// Once the queue is full, the client is no longer ready; see
+/// `ClientProxy::poll_ready`.
+pub fn bounded<R, S, E>(capacity: usize) -> Pair<R, S, E> {
+    let (tx, rx) = mpsc::channel(capacity);
+
+    let state = State::new();
+
+    let client = ClientProxy {
+        tx: RefCell::new(Sender::Bounded(tx)),
+        state: state.clone(),
+    };
+
+    (client, Receiver { rx: Rx::Bounded(rx), state: state })
+}
+
+impl<R, S, E: From<io::Error>> ClientProxy<R, S, E> {
+    /// Returns `Async::Ready` when a request can be made without exceeding
+    /// the capacity of the queue.
+    ///
+    /// Always ready when the queue is unbounded. When it is not ready, the
+    /// current task is notified once a request has been taken off the queue.
+    pub fn poll_ready(&self) -> Async<()> {
+        self.tx.borrow_mut().poll_ready()
+    }
+
+    /// Make a request, unless the queue is full, in which case the request is
+    /// handed back.
+    pub fn try_call(&self, request: R) -> Result<Response<S, E>, R> {
+        self.tx.borrow_mut().try_call(request, &self.state)
+    }
+
+    /// Send a one-way notification, which is not responded to.
+    ///
+    /// Notifications share the queue with requests. The returned future
+    /// completes once the notification has been written. Like `call`, it
+    /// fails when the queue is full, and it also fails when the connection
+    /// cannot carry notifications, which only multiplexed connections do.
+    pub fn notify(&self, request: R) -> Response<(), E> {
+        self.tx.borrow_mut().notify(request, &self.state)
+    }
+
+    /// Returns a future that resolves once the connection has ended.
+    ///
+    /// The future fails with the error that ended the connection. It resolves
+    /// successfully if the connection was shut down cleanly, for example
+    /// because the server closed it.
+    pub fn closed(&self) -> Closed {
+        Closed { state: self.state.clone() }
+    }
 }
 
 impl<R, S, E: From<io::Error>> Service for ClientProxy<R, S, E> {
@@ -66,18 +196,187 @@ impl<R, S, E: From<io::Error>> Service for ClientProxy<R, S, E> {
     type Future = Response<S, E>;
 
     fn call(&self, request: R) -> Self::Future {
+        self.tx.borrow_mut().call(request, &self.state)
+    }
+}
+
+impl<R, S, E> SyncClient<R, S, E> {
+    /// Create a thread-safe handle that makes requests through `client`.
+    pub fn new(client: ClientProxy<R, S, E>) -> SyncClient<R, S, E> {
+        let ClientProxy { tx, state } = client;
+
+        SyncClient {
+            tx: Arc::new(Mutex::new(tx.into_inner())),
+            state: state,
+        }
+    }
+}
+
+impl<R, S, E: From<io::Error>> SyncClient<R, S, E> {
+    /// Make a request, unless the queue is full, in which case the request is
+    /// handed back.
+    pub fn try_call(&self, request: R) -> Result<Response<S, E>, R> {
+        self.tx.lock().unwrap().try_call(request, &self.state)
+    }
+
+    /// Make a request, and block the current thread until the response
+    /// arrives.
+    ///
+    /// This must not be called from the thread running the connection's
+    /// event loop, which would never get to process the request.
+    pub fn call_wait(&self, request: R) -> Result<S, E> {
+        self.call(request).wait()
+    }
+
+    /// Returns a future that resolves once the connection has ended.
+    ///
+    /// See `ClientProxy::closed`.
+    pub fn closed(&self) -> Closed {
+        Closed { state: self.state.clone() }
+    }
+}
+
+impl<R, S, E> Clone for SyncClient<R, S, E> {
+    fn clone(&self) -> Self {
+        SyncClient {
+            tx: self.tx.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<R, S, E: From<io::Error>> Service for SyncClient<R, S, E> {
+    type Request = R;
+    type Response = S;
+    type Error = E;
+    type Future = Response<S, E>;
+
+    fn call(&self, request: R) -> Self::Future {
+        self.tx.lock().unwrap().call(request, &self.state)
+    }
+}
+
+impl<R, S, E: From<io::Error>> Sender<R, S, E> {
+    fn poll_ready(&mut self) -> Async<()> {
+        match *self {
+            Sender::Unbounded(_) => Async::Ready(()),
+            Sender::Bounded(ref mut tx) => {
+                match tx.poll_ready() {
+                    Ok(Async::NotReady) => Async::NotReady,
+                    // A closed connection is ready to fail the request
+                    _ => Async::Ready(()),
+                }
+            }
+        }
+    }
+
+    // Queue up the request, unless the queue is full, in which case the
+    // request is handed back
+    fn try_send(&mut self, request: Request<R, S, E>) -> Result<(), Request<R, S, E>> {
+        match *self {
+            Sender::Unbounded(ref tx_requests) => {
+                let _ = tx_requests.unbounded_send(Ok(request));
+            }
+            Sender::Bounded(ref mut tx_requests) => {
+                if let Err(e) = tx_requests.try_send(Ok(request)) {
+                    if e.is_full() {
+                        match e.into_inner() {
+                            Ok(request) => return Err(request),
+                            Err(_) => unreachable!(),
+                        }
+                    }
+
+                    // Otherwise, the connection is closed, see `call`
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn try_call(&mut self, request: R, state: &Arc<Mutex<State>>) -> Result<Response<S, E>, R> {
         let (tx, rx) = oneshot::channel();
 
+        match self.try_send(Request::Call(request, tx)) {
+            Ok(()) => Ok(Response { inner: rx, state: state.clone() }),
+            Err(Request::Call(request, _)) => Err(request),
+            Err(Request::Notify(..)) => unreachable!(),
+        }
+    }
+
+    fn notify(&mut self, request: R, state: &Arc<Mutex<State>>) -> Response<(), E> {
+        let (tx, rx) = oneshot::channel();
+
+        if let Err(request) = self.try_send(Request::Notify(request, tx)) {
+            match request {
+                Request::Notify(_, tx) => tx.complete(Err(queue_full().into())),
+                Request::Call(..) => unreachable!(),
+            }
+        }
+
+        Response { inner: rx, state: state.clone() }
+    }
+
+    fn call(&mut self, request: R, state: &Arc<Mutex<State>>) -> Response<S, E> {
         // If send returns an Err, its because the other side has been dropped.
         // By ignoring it, we are just dropping the `tx`, which will mean the
         // rx will return Canceled when polled. In turn, that is translated
-        // into a BrokenPipe, which conveys the proper error.
-        // NOTE: If Service changes to have some sort of `try_call`, it'd
-        // probably be more appropriate to return the Request.
-        let _ = mpsc::UnboundedSender::send(&mut self.tx.borrow_mut(),
-                                            Ok((request, tx)));
+        // into the error that ended the connection.
+        match self.try_call(request, state) {
+            Ok(response) => response,
+            Err(_) => {
+                // The queue is full. Callers that want to wait for room use
+                // `poll_ready` or `try_call` instead.
+                let (tx, rx) = oneshot::channel();
+                tx.complete(Err(queue_full().into()));
+
+                Response { inner: rx, state: state.clone() }
+            }
+        }
+    }
+}
 
-        Response { inner: rx }
+impl<R, S, E> Receiver<R, S, E> {
+    /// Returns a handle used to record the error that ended the connection.
+    ///
+    /// The handle is meant to be kept by whatever drives the connection, so
+    /// the error can be recorded after the receiver has been moved into the
+    /// dispatcher.
+    pub fn error_handle(&self) -> ErrorHandle {
+        ErrorHandle { state: self.state.clone() }
+    }
+
+    /// Receive the next request or notification.
+    ///
+    /// The `Stream` implementation only yields requests that expect a
+    /// response. Dispatchers that can write one-way messages use this instead.
+    pub fn poll_request(&mut self) -> Poll<Option<io::Result<Request<R, S, E>>>, ()> {
+        match self.rx {
+            Rx::Unbounded(ref mut rx) => rx.poll(),
+            Rx::Bounded(ref mut rx) => rx.poll(),
+        }
+    }
+}
+
+impl<R, S, E: From<io::Error>> Stream for Receiver<R, S, E> {
+    type Item = io::Result<Envelope<R, S, E>>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, ()> {
+        loop {
+            match try_ready!(self.poll_request()) {
+                Some(Ok(Request::Call(request, complete))) => {
+                    return Ok(Async::Ready(Some(Ok((request, complete)))));
+                }
+                Some(Ok(Request::Notify(_, complete))) => {
+                    let err = io::Error::new(io::ErrorKind::Other,
+                                             "notifications are not supported");
+                    complete.complete(Err(err.into()));
+                }
+                Some(Err(e)) => return Ok(Async::Ready(Some(Err(e)))),
+                None => return Ok(Async::Ready(None)),
+            }
+        }
     }
 }
 
@@ -93,9 +392,86 @@ impl<T, E> Future for Response<T, E>
             Ok(Async::Ready(Err(e))) => Err(e),
             Ok(Async::NotReady) => Ok(Async::NotReady),
             Err(_) => {
-                let e = io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe");
+                // The connection ended before the request was answered
+                let e = self.state.lock().unwrap().error();
                 Err(e.into())
             }
         }
     }
 }
+
+impl Future for Closed {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.closed {
+            if !state.waiters.iter().any(|task| task.will_notify_current()) {
+                state.waiters.push(task::current());
+            }
+
+            return Ok(Async::NotReady);
+        }
+
+        match state.error {
+            Some(ref e) => Err(copy_error(e)),
+            None => Ok(Async::Ready(())),
+        }
+    }
+}
+
+impl ErrorHandle {
+    /// Record the error that ended the connection.
+    ///
+    /// Requests that are still pending once the receiver is dropped, and the
+    /// ones made afterwards, fail with this error. Only the first error
+    /// recorded is kept.
+    pub fn set(&self, error: io::Error) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.error.is_none() {
+            state.error = Some(error);
+        }
+    }
+}
+
+impl<R, S, E> Drop for Receiver<R, S, E> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+
+        for task in state.waiters.drain(..) {
+            task.notify();
+        }
+    }
+}
+
+impl State {
+    fn new() -> Arc<Mutex<State>> {
+        Arc::new(Mutex::new(State {
+            closed: false,
+            error: None,
+            waiters: vec![],
+        }))
+    }
+
+    // Returns the error to fail requests with once the connection has ended
+    fn error(&self) -> io::Error {
+        match self.error {
+            Some(ref e) => copy_error(e),
+            None => io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"),
+        }
+    }
+}
+
+fn queue_full() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "request queue is full")
+}
+
+// `io::Error` is not `Clone`, so the error is handed out as a new error with
+// the same kind and message
+fn copy_error(e: &io::Error) -> io::Error {
+    io::Error::new(e.kind(), e.to_string())
+}
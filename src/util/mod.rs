@@ -1,3 +1,5 @@
 //! Utilities for building protocols
 
 pub mod client_proxy;
+pub mod pool;
+pub mod balance;
@@ -0,0 +1,400 @@
+//! A pool of client connections
+//!
+//! Pipelined protocols answer requests in order, so a slow response holds up
+//! every request behind it on the same connection. `Pool` spreads requests
+//! over several connections to each server instead.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+
+use BindClient;
+use client_stream::ClientStream;
+use tcp_client::{ConnectSocket, Connector, TcpClient};
+use futures::{Future, Poll, Async};
+use futures::sync::oneshot;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+
+/// A pool of client connections, established with a `TcpClient`.
+///
+/// Connections to an address are created lazily, when a request is made and
+/// every existing connection to the address has requests outstanding, up to
+/// `max_connections` of them. Each request is sent on the connection to its
+/// address with the fewest outstanding requests.
+///
+/// A connection is evicted from the pool once the task managing it ends, for
+/// example because the server closed it, and a new one is created when it is
+/// needed again.
+pub struct Pool<Kind, P> where P: BindClient<Kind, ClientStream> {
+    inner: Rc<RefCell<Inner<Kind, P>>>,
+}
+
+/// Statistics about the connections in a `Pool`.
+#[derive(Debug, Clone)]
+pub struct PoolStats {
+    connections: usize,
+    connecting: usize,
+    outstanding: usize,
+    created: usize,
+    evicted: usize,
+}
+
+/// Response future returned from a `Pool`.
+pub struct PoolResponse<F> where F: Future {
+    state: Response<F>,
+    // The number of outstanding requests on the connection, which this
+    // request counts towards until it completes
+    outstanding: Option<Rc<Cell<usize>>>,
+}
+
+enum Response<F> {
+    Sent(F),
+    Queued(oneshot::Receiver<io::Result<F>>),
+}
+
+struct Inner<Kind, P> where P: BindClient<Kind, ClientStream> {
+    client: TcpClient<Kind, P>,
+    handle: Handle,
+    max_connections: usize,
+    hosts: HashMap<SocketAddr, Vec<Conn<P::BindClient>>>,
+    next_id: usize,
+    created: usize,
+    evicted: usize,
+}
+
+struct Conn<S> where S: Service {
+    id: usize,
+    outstanding: Rc<Cell<usize>>,
+    state: ConnState<S>,
+}
+
+enum ConnState<S> where S: Service {
+    // Requests waiting for the connection to be established
+    Connecting(Vec<(S::Request, oneshot::Sender<io::Result<S::Future>>)>),
+    Connected(S),
+}
+
+/// Establishes a pooled connection, and evicts it once it has been closed.
+struct Connection<Kind, P> where P: BindClient<Kind, ClientStream> {
+    pool: Weak<RefCell<Inner<Kind, P>>>,
+    addr: SocketAddr,
+    id: usize,
+    step: Step,
+}
+
+enum Step {
+    Connecting(ConnectSocket),
+    Connected(oneshot::Receiver<()>),
+}
+
+impl<Kind, P> Pool<Kind, P> where
+    Kind: 'static,
+    P: BindClient<Kind, ClientStream>,
+    P::ServiceError: From<io::Error>,
+{
+    /// Create a pool that establishes its connections with `client`, on the
+    /// event loop behind `handle`.
+    pub fn new(client: TcpClient<Kind, P>, handle: &Handle) -> Pool<Kind, P> {
+        Pool {
+            inner: Rc::new(RefCell::new(Inner {
+                client: client,
+                handle: handle.clone(),
+                max_connections: 4,
+                hosts: HashMap::new(),
+                next_id: 0,
+                created: 0,
+                evicted: 0,
+            })),
+        }
+    }
+
+    /// Set the maximum number of connections to each address.
+    ///
+    /// Default is 4.
+    pub fn max_connections(&mut self, max: usize) {
+        assert!(max > 0);
+        self.inner.borrow_mut().max_connections = max;
+    }
+
+    /// Send a request to the server at `addr`.
+    pub fn call(&self, addr: &SocketAddr, request: P::ServiceRequest)
+                -> PoolResponse<<P::BindClient as Service>::Future> {
+        let weak = Rc::downgrade(&self.inner);
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+
+        let conns = inner.hosts.entry(*addr).or_insert_with(Vec::new);
+
+        let least = conns.iter()
+            .enumerate()
+            .min_by_key(|&(_, conn)| conn.outstanding.get())
+            .map(|(i, conn)| (i, conn.outstanding.get()));
+
+        let i = match least {
+            Some((i, n)) if n == 0 || conns.len() >= inner.max_connections => i,
+            _ => {
+                let id = inner.next_id;
+                inner.next_id += 1;
+                inner.created += 1;
+
+                trace!("creating pooled connection; addr={}; id={}", addr, id);
+
+                let socket = inner.client.connect_socket(&[*addr], &inner.handle);
+
+                inner.handle.spawn(Connection {
+                    pool: weak,
+                    addr: *addr,
+                    id: id,
+                    step: Step::Connecting(socket),
+                });
+
+                conns.push(Conn {
+                    id: id,
+                    outstanding: Rc::new(Cell::new(0)),
+                    state: ConnState::Connecting(Vec::new()),
+                });
+
+                conns.len() - 1
+            }
+        };
+
+        let conn = &mut conns[i];
+        conn.outstanding.set(conn.outstanding.get() + 1);
+
+        let state = match conn.state {
+            ConnState::Connected(ref client) => Response::Sent(client.call(request)),
+            ConnState::Connecting(ref mut queue) => {
+                let (tx, rx) = oneshot::channel();
+                queue.push((request, tx));
+                Response::Queued(rx)
+            }
+        };
+
+        PoolResponse {
+            state: state,
+            outstanding: Some(conn.outstanding.clone()),
+        }
+    }
+
+    /// Returns statistics about the connections in the pool.
+    pub fn stats(&self) -> PoolStats {
+        let inner = self.inner.borrow();
+
+        let mut stats = PoolStats {
+            connections: 0,
+            connecting: 0,
+            outstanding: 0,
+            created: inner.created,
+            evicted: inner.evicted,
+        };
+
+        for conn in inner.hosts.values().flat_map(|conns| conns.iter()) {
+            stats.connections += 1;
+            stats.outstanding += conn.outstanding.get();
+
+            if let ConnState::Connecting(..) = conn.state {
+                stats.connecting += 1;
+            }
+        }
+
+        stats
+    }
+}
+
+impl<Kind, P> Clone for Pool<Kind, P> where P: BindClient<Kind, ClientStream> {
+    fn clone(&self) -> Self {
+        Pool { inner: self.inner.clone() }
+    }
+}
+
+impl PoolStats {
+    /// Returns the number of connections in the pool, including the ones
+    /// that are still being established.
+    pub fn connections(&self) -> usize {
+        self.connections
+    }
+
+    /// Returns the number of connections that are being established.
+    pub fn connecting(&self) -> usize {
+        self.connecting
+    }
+
+    /// Returns the number of requests that have not been answered yet.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding
+    }
+
+    /// Returns the number of connections the pool has created.
+    pub fn created(&self) -> usize {
+        self.created
+    }
+
+    /// Returns the number of connections that have been evicted from the pool
+    /// after being closed.
+    pub fn evicted(&self) -> usize {
+        self.evicted
+    }
+}
+
+impl<Kind, P> Inner<Kind, P> where P: BindClient<Kind, ClientStream> {
+    fn remove(&mut self, addr: &SocketAddr, id: usize) -> Option<Conn<P::BindClient>> {
+        let (conn, empty) = match self.hosts.get_mut(addr) {
+            Some(conns) => {
+                let conn = conns.iter()
+                    .position(|conn| conn.id == id)
+                    .map(|i| conns.remove(i));
+
+                (conn, conns.is_empty())
+            }
+            None => return None,
+        };
+
+        if empty {
+            self.hosts.remove(addr);
+        }
+
+        conn
+    }
+
+    fn connected(&mut self, addr: &SocketAddr, id: usize, socket: TcpStream) -> oneshot::Receiver<()> {
+        let (stream, rx) = ClientStream::new(socket);
+
+        let client = self.client.protocol().bind_client(&self.handle, stream);
+
+        let conn = self.hosts.get_mut(addr)
+            .and_then(|conns| conns.iter_mut().find(|conn| conn.id == id));
+
+        if let Some(conn) = conn {
+            if let ConnState::Connecting(ref mut queue) = conn.state {
+                for (request, tx) in queue.drain(..) {
+                    // The response future has been dropped if this fails
+                    drop(tx.send(Ok(client.call(request))));
+                }
+            }
+
+            conn.state = ConnState::Connected(client);
+        }
+
+        rx
+    }
+}
+
+impl<Kind, P> Future for Connection<Kind, P> where P: BindClient<Kind, ClientStream> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            // Stop once the pool has been dropped
+            let pool = match self.pool.upgrade() {
+                Some(pool) => pool,
+                None => return Ok(Async::Ready(())),
+            };
+            let mut pool = pool.borrow_mut();
+
+            let closed = match self.step {
+                Step::Connecting(ref mut socket) => {
+                    match socket.poll() {
+                        Ok(Async::Ready((socket, _))) => {
+                            trace!("pooled connection established; addr={}; id={}", self.addr, self.id);
+                            pool.connected(&self.addr, self.id, socket)
+                        }
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(e) => {
+                            debug!("failed to connect; addr={}; err={}", self.addr, e);
+
+                            if let Some(conn) = pool.remove(&self.addr, self.id) {
+                                if let ConnState::Connecting(queue) = conn.state {
+                                    for (_, tx) in queue {
+                                        let err = io::Error::new(e.kind(), e.to_string());
+                                        drop(tx.send(Err(err)));
+                                    }
+                                }
+                            }
+
+                            return Ok(Async::Ready(()));
+                        }
+                    }
+                }
+                Step::Connected(ref mut closed) => {
+                    match closed.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        _ => {
+                            debug!("evicting closed connection; addr={}; id={}", self.addr, self.id);
+
+                            if pool.remove(&self.addr, self.id).is_some() {
+                                pool.evicted += 1;
+                            }
+
+                            return Ok(Async::Ready(()));
+                        }
+                    }
+                }
+            };
+
+            self.step = Step::Connected(closed);
+        }
+    }
+}
+
+impl<F> PoolResponse<F> where
+    F: Future,
+    F::Error: From<io::Error>,
+{
+    fn poll_response(&mut self) -> Poll<F::Item, F::Error> {
+        loop {
+            let future = match self.state {
+                Response::Sent(ref mut future) => return future.poll(),
+                Response::Queued(ref mut rx) => {
+                    match rx.poll() {
+                        Ok(Async::Ready(Ok(future))) => future,
+                        Ok(Async::Ready(Err(e))) => return Err(e.into()),
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(_) => {
+                            let e = io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe");
+                            return Err(e.into());
+                        }
+                    }
+                }
+            };
+
+            self.state = Response::Sent(future);
+        }
+    }
+}
+
+impl<F> Future for PoolResponse<F> where
+    F: Future,
+    F::Error: From<io::Error>,
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let res = self.poll_response();
+
+        if let Ok(Async::NotReady) = res {
+            return res;
+        }
+
+        // The request no longer counts towards the connection's outstanding
+        // requests
+        if let Some(outstanding) = self.outstanding.take() {
+            outstanding.set(outstanding.get() - 1);
+        }
+
+        res
+    }
+}
+
+impl<F> Drop for PoolResponse<F> where F: Future {
+    fn drop(&mut self) {
+        if let Some(outstanding) = self.outstanding.take() {
+            outstanding.set(outstanding.get() - 1);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! A newline-delimited protocol, for tests that need a real socket.
+
+extern crate tokio_core;
+extern crate tokio_proto;
+
+use std::io::{self, Write};
+use std::str;
+
+use self::tokio_core::io::{Io, Codec, Framed, EasyBuf};
+use self::tokio_proto::{pipeline, multiplex};
+
+pub struct LineCodec;
+
+impl Codec for LineCodec {
+    type In = String;
+    type Out = String;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<String>> {
+        match buf.as_slice().iter().position(|&b| b == b'\n') {
+            Some(i) => {
+                let line = buf.drain_to(i + 1);
+                let line = &line.as_slice()[..i];
+
+                str::from_utf8(line)
+                    .map(|s| Some(s.to_string()))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn encode(&mut self, msg: String, buf: &mut Vec<u8>) -> io::Result<()> {
+        writeln!(buf, "{}", msg)
+    }
+}
+
+pub struct LineProto;
+
+impl<T: Io + 'static> pipeline::ServerProto<T> for LineProto {
+    type Request = String;
+    type Response = String;
+    type Transport = Framed<T, LineCodec>;
+    type BindTransport = io::Result<Self::Transport>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(LineCodec))
+    }
+}
+
+impl<T: Io + 'static> pipeline::ClientProto<T> for LineProto {
+    type Request = String;
+    type Response = String;
+    type Transport = Framed<T, LineCodec>;
+    type BindTransport = io::Result<Self::Transport>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(LineCodec))
+    }
+}
+
+/// Lines prefixed with the request ID and a space, such as `3 hello`. Solo
+/// messages have a `!` after the ID, such as `3! hello`.
+pub struct MultiplexLineCodec;
+
+impl Codec for MultiplexLineCodec {
+    type In = (multiplex::RequestId, String, bool);
+    type Out = (multiplex::RequestId, String, bool);
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> io::Result<Option<Self::In>> {
+        let line = match try!(LineCodec.decode(buf)) {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+        let mut parts = line.splitn(2, ' ');
+        let (id, solo) = match parts.next() {
+            Some(id) if id.ends_with('!') => (&id[..id.len() - 1], true),
+            Some(id) => (id, false),
+            None => ("", false),
+        };
+
+        match (id.parse().ok(), parts.next()) {
+            (Some(id), Some(msg)) => Ok(Some((id, msg.to_string(), solo))),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "missing request ID")),
+        }
+    }
+
+    fn encode(&mut self, (id, msg, solo): Self::Out, buf: &mut Vec<u8>) -> io::Result<()> {
+        let marker = if solo { "!" } else { "" };
+        writeln!(buf, "{}{} {}", id, marker, msg)
+    }
+}
+
+pub struct MultiplexLineProto;
+
+impl<T: Io + 'static> multiplex::ServerProto<T> for MultiplexLineProto {
+    type Request = String;
+    type Response = String;
+    type Transport = Framed<T, MultiplexLineCodec>;
+    type BindTransport = io::Result<Self::Transport>;
+
+    fn bind_transport(&self, io: T) -> Self::BindTransport {
+        Ok(io.framed(MultiplexLineCodec))
+    }
+}
@@ -8,6 +8,9 @@ use std::any::Any;
 use std::thread;
 use std::cell::RefCell;
 use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use self::futures::stream::Wait;
 use self::futures::sync::mpsc;
@@ -18,11 +21,15 @@ use self::tokio_core::reactor::Core;
 use self::tokio_proto::streaming::multiplex;
 use self::tokio_proto::streaming::pipeline;
 use self::tokio_proto::streaming::{Message, Body};
-use self::tokio_proto::util::client_proxy::Response;
+use self::tokio_proto::util::client_proxy::{ClientProxy, Response};
 use self::tokio_proto::{BindClient, BindServer};
 use self::tokio_service::Service;
 
-struct MockProtocol<T>(RefCell<Option<MockTransport<T>>>);
+struct MockProtocol<T> {
+    transport: RefCell<Option<MockTransport<T>>>,
+    request_timeout: Option<Duration>,
+    multiplex_config: multiplex::MultiplexConfig,
+}
 
 impl<T, U, I> pipeline::ClientProto<I> for MockProtocol<pipeline::Frame<T, U, io::Error>>
     where T: 'static,
@@ -39,7 +46,11 @@ impl<T, U, I> pipeline::ClientProto<I> for MockProtocol<pipeline::Frame<T, U, io
 
     fn bind_transport(&self, _io: I)
                       -> Result<MockTransport<pipeline::Frame<T, U, io::Error>>, io::Error> {
-        Ok(self.0.borrow_mut().take().unwrap())
+        Ok(self.transport.borrow_mut().take().unwrap())
+    }
+
+    fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout
     }
 }
 
@@ -58,7 +69,15 @@ impl<T, U, I> multiplex::ClientProto<I> for MockProtocol<multiplex::Frame<T, U,
 
     fn bind_transport(&self, _io: I)
                       -> Result<MockTransport<multiplex::Frame<T, U, io::Error>>, io::Error> {
-        Ok(self.0.borrow_mut().take().unwrap())
+        Ok(self.transport.borrow_mut().take().unwrap())
+    }
+
+    fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout
+    }
+
+    fn multiplex_config(&self) -> multiplex::MultiplexConfig {
+        self.multiplex_config.clone()
     }
 }
 
@@ -77,7 +96,11 @@ impl<T, U, I> pipeline::ServerProto<I> for MockProtocol<pipeline::Frame<T, U, io
 
     fn bind_transport(&self, _io: I)
                       -> Result<MockTransport<pipeline::Frame<T, U, io::Error>>, io::Error> {
-        Ok(self.0.borrow_mut().take().unwrap())
+        Ok(self.transport.borrow_mut().take().unwrap())
+    }
+
+    fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout
     }
 }
 
@@ -96,13 +119,29 @@ impl<T, U, I> multiplex::ServerProto<I> for MockProtocol<multiplex::Frame<T, U,
 
     fn bind_transport(&self, _io: I)
                       -> Result<MockTransport<multiplex::Frame<T, U, io::Error>>, io::Error> {
-        Ok(self.0.borrow_mut().take().unwrap())
+        Ok(self.transport.borrow_mut().take().unwrap())
+    }
+
+    fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout
+    }
+
+    fn multiplex_config(&self) -> multiplex::MultiplexConfig {
+        self.multiplex_config.clone()
     }
 }
 
 struct MockTransport<T> {
     tx: mpsc::Sender<T>,
     rx: mpsc::UnboundedReceiver<io::Result<T>>,
+    canceled: Arc<Mutex<Vec<multiplex::RequestId>>>,
+    written: Arc<AtomicUsize>,
+    sent_windows: Arc<Mutex<Vec<(multiplex::RequestId, usize)>>>,
+    peer_windows: mpsc::UnboundedReceiver<(multiplex::RequestId, usize)>,
+    peer_canceled: mpsc::UnboundedReceiver<multiplex::RequestId>,
+    read: Arc<AtomicUsize>,
+    ticks: Arc<AtomicUsize>,
+    wake: mpsc::UnboundedReceiver<()>,
 }
 
 impl<T: 'static> Stream for MockTransport<T> {
@@ -111,7 +150,10 @@ impl<T: 'static> Stream for MockTransport<T> {
 
     fn poll(&mut self) -> Poll<Option<T>, io::Error> {
         match self.rx.poll().expect("rx cannot fail") {
-            Async::Ready(Some(Ok(e))) => Ok(Async::Ready(Some(e))),
+            Async::Ready(Some(Ok(e))) => {
+                self.read.fetch_add(1, Ordering::SeqCst);
+                Ok(Async::Ready(Some(e)))
+            }
             Async::Ready(Some(Err(e))) => Err(e),
             Async::Ready(None) => Ok(Async::Ready(None)),
             Async::NotReady => Ok(Async::NotReady),
@@ -124,7 +166,13 @@ impl<T: 'static> Sink for MockTransport<T> {
     type SinkError = io::Error;
 
     fn start_send(&mut self, item: T) -> StartSend<T, io::Error> {
-        Ok(self.tx.start_send(item).expect("should not be closed"))
+        let res = self.tx.start_send(item).expect("should not be closed");
+
+        if res.is_ready() {
+            self.written.fetch_add(1, Ordering::SeqCst);
+        }
+
+        Ok(res)
     }
 
     fn poll_complete(&mut self) -> Poll<(), io::Error> {
@@ -132,8 +180,49 @@ impl<T: 'static> Sink for MockTransport<T> {
     }
 }
 
-impl<T: 'static> pipeline::Transport for MockTransport<T> {}
-impl<B, T: 'static> multiplex::Transport<B> for MockTransport<T> {}
+impl<T: 'static> MockTransport<T> {
+    fn tick(&mut self) {
+        // Polling the wake channel registers the task for `run_task`
+        while let Async::Ready(Some(())) = self.wake.poll().expect("rx cannot fail") {}
+        self.ticks.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl<T: 'static> pipeline::Transport for MockTransport<T> {
+    fn tick(&mut self) {
+        MockTransport::tick(self)
+    }
+}
+
+impl<B, T: 'static> multiplex::Transport<B> for MockTransport<T> {
+    fn tick(&mut self) {
+        MockTransport::tick(self)
+    }
+
+    fn cancel(&mut self, request_id: multiplex::RequestId) -> io::Result<()> {
+        self.canceled.lock().unwrap().push(request_id);
+        Ok(())
+    }
+
+    fn send_window_update(&mut self, id: multiplex::RequestId, increment: usize) -> io::Result<()> {
+        self.sent_windows.lock().unwrap().push((id, increment));
+        Ok(())
+    }
+
+    fn poll_window_update(&mut self) -> Async<(multiplex::RequestId, usize)> {
+        match self.peer_windows.poll().expect("rx cannot fail") {
+            Async::Ready(Some(update)) => Async::Ready(update),
+            _ => Async::NotReady,
+        }
+    }
+
+    fn poll_canceled(&mut self) -> Async<multiplex::RequestId> {
+        match self.peer_canceled.poll().expect("rx cannot fail") {
+            Async::Ready(Some(id)) => Async::Ready(id),
+            _ => Async::NotReady,
+        }
+    }
+}
 
 struct MockIo;
 
@@ -160,6 +249,14 @@ pub type MockBodyStream = Box<Stream<Item = u32, Error = io::Error> + Send>;
 pub struct MockTransportCtl<T> {
     tx: Option<mpsc::UnboundedSender<io::Result<T>>>,
     rx: Wait<mpsc::Receiver<T>>,
+    canceled: Arc<Mutex<Vec<multiplex::RequestId>>>,
+    written: Arc<AtomicUsize>,
+    sent_windows: Arc<Mutex<Vec<(multiplex::RequestId, usize)>>>,
+    peer_windows: mpsc::UnboundedSender<(multiplex::RequestId, usize)>,
+    peer_canceled: mpsc::UnboundedSender<multiplex::RequestId>,
+    read: Arc<AtomicUsize>,
+    ticks: Arc<AtomicUsize>,
+    wake: mpsc::UnboundedSender<()>,
 }
 
 impl<T> MockTransportCtl<T> {
@@ -168,6 +265,12 @@ impl<T> MockTransportCtl<T> {
             .expect("should not be closed");
     }
 
+    /// Cancels an exchange on behalf of the peer
+    pub fn peer_cancel(&mut self, id: multiplex::RequestId) {
+        mpsc::UnboundedSender::send(&mut self.peer_canceled, id)
+            .expect("should not be closed");
+    }
+
     pub fn error(&mut self, error: io::Error) {
         mpsc::UnboundedSender::send(self.tx.as_mut().unwrap(), Err(error))
             .expect("should not be closed");
@@ -177,24 +280,100 @@ impl<T> MockTransportCtl<T> {
         self.rx.next().unwrap().expect("cannot error")
     }
 
+    /// Returns the request IDs the dispatcher canceled on the transport
+    pub fn canceled(&self) -> Vec<multiplex::RequestId> {
+        self.canceled.lock().unwrap().clone()
+    }
+
+    /// Returns the number of frames the dispatcher wrote to the transport
+    pub fn written(&self) -> usize {
+        self.written.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of frames the dispatcher read from the transport
+    pub fn read(&self) -> usize {
+        self.read.load(Ordering::SeqCst)
+    }
+
+    /// Wakes up the dispatcher, and waits until it has run in full at least
+    /// once since the call
+    pub fn run_task(&mut self) {
+        // The task has finished the run that noticed the first wake-up once
+        // it starts the run that notices the second one
+        for _ in 0..2 {
+            let ticks = self.ticks.load(Ordering::SeqCst);
+            mpsc::UnboundedSender::send(&mut self.wake, ())
+                .expect("should not be closed");
+
+            while self.ticks.load(Ordering::SeqCst) == ticks {
+                thread::yield_now();
+            }
+        }
+    }
+
+    /// Returns the window updates the dispatcher sent to the peer
+    pub fn sent_windows(&self) -> Vec<(multiplex::RequestId, usize)> {
+        self.sent_windows.lock().unwrap().clone()
+    }
+
+    /// Sends a window update from the peer to the dispatcher
+    pub fn window_update(&mut self, id: multiplex::RequestId, increment: usize) {
+        mpsc::UnboundedSender::send(&mut self.peer_windows, (id, increment))
+            .expect("should not be closed");
+    }
+
     pub fn allow_and_assert_drop(&mut self) {
         drop(self.tx.take());
         assert!(self.rx.next().is_none());
     }
+
+    /// Waits for the dispatcher to drop the transport on its own
+    pub fn assert_drop(&mut self) {
+        assert!(self.rx.next().is_none());
+    }
 }
 
-fn transport<T>() -> (MockTransportCtl<T>, MockProtocol<T>) {
+fn transport<T>(request_timeout: Option<Duration>) -> (MockTransportCtl<T>, MockProtocol<T>) {
     let (tx1, rx1) = mpsc::channel(1);
     let (tx2, rx2) = mpsc::unbounded();
+    let (tx3, rx3) = mpsc::unbounded();
+    let (tx4, rx4) = mpsc::unbounded();
+    let (tx5, rx5) = mpsc::unbounded();
+    let read = Arc::new(AtomicUsize::new(0));
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let canceled = Arc::new(Mutex::new(vec![]));
+    let written = Arc::new(AtomicUsize::new(0));
+    let sent_windows = Arc::new(Mutex::new(vec![]));
     let ctl = MockTransportCtl {
         tx: Some(tx2),
         rx: rx1.wait(),
+        canceled: canceled.clone(),
+        written: written.clone(),
+        sent_windows: sent_windows.clone(),
+        peer_windows: tx3,
+        peer_canceled: tx4,
+        read: read.clone(),
+        ticks: ticks.clone(),
+        wake: tx5,
     };
     let transport = MockTransport {
         tx: tx1,
         rx: rx2,
+        canceled: canceled,
+        written: written,
+        sent_windows: sent_windows,
+        peer_windows: rx3,
+        peer_canceled: rx4,
+        read: read,
+        ticks: ticks,
+        wake: rx5,
     };
-    (ctl, MockProtocol(RefCell::new(Some(transport))))
+    let proto = MockProtocol {
+        transport: RefCell::new(Some(transport)),
+        request_timeout: request_timeout,
+        multiplex_config: multiplex::MultiplexConfig::default(),
+    };
+    (ctl, proto)
 }
 
 struct CompleteOnDrop {
@@ -217,10 +396,22 @@ pub fn pipeline_client()
                     Future = Response<Message<&'static str, Body<u32, io::Error>>,
                                               io::Error>>>,
         Box<Any>)
+{
+    pipeline_client_with_timeout(None)
+}
+
+pub fn pipeline_client_with_timeout(request_timeout: Option<Duration>)
+    -> (MockTransportCtl<pipeline::Frame<&'static str, u32, io::Error>>,
+        Box<Service<Request = Message<&'static str, MockBodyStream>,
+                    Response = Message<&'static str, Body<u32, io::Error>>,
+                    Error = io::Error,
+                    Future = Response<Message<&'static str, Body<u32, io::Error>>,
+                                              io::Error>>>,
+        Box<Any>)
 {
     drop(env_logger::init());
 
-    let (ctl, proto) = transport();
+    let (ctl, proto) = transport(request_timeout);
 
     let (tx, rx) = oneshot::channel();
     let (finished_tx, finished_rx) = oneshot::channel();
@@ -247,10 +438,19 @@ pub fn pipeline_server<S>(s: S)
     where S: Service<Request = Message<&'static str, Body<u32, io::Error>>,
                      Response = Message<&'static str, MockBodyStream>,
                      Error = io::Error> + Send + 'static,
+{
+    pipeline_server_with_timeout(s, None)
+}
+
+pub fn pipeline_server_with_timeout<S>(s: S, request_timeout: Option<Duration>)
+    -> (MockTransportCtl<pipeline::Frame<&'static str, u32, io::Error>>, Box<Any>)
+    where S: Service<Request = Message<&'static str, Body<u32, io::Error>>,
+                     Response = Message<&'static str, MockBodyStream>,
+                     Error = io::Error> + Send + 'static,
 {
     drop(env_logger::init());
 
-    let (ctl, proto) = transport();
+    let (ctl, proto) = transport(request_timeout);
 
     let (finished_tx, finished_rx) = oneshot::channel();
     let t = thread::spawn(move || {
@@ -276,10 +476,60 @@ pub fn multiplex_client()
                     Future = Response<Message<&'static str, Body<u32, io::Error>>,
                                               io::Error>>>,
         Box<Any>)
+{
+    multiplex_client_with_timeout(None)
+}
+
+pub fn multiplex_client_with_timeout(request_timeout: Option<Duration>)
+    -> (MockTransportCtl<multiplex::Frame<&'static str, u32, io::Error>>,
+        Box<Service<Request = Message<&'static str, MockBodyStream>,
+                    Response = Message<&'static str, Body<u32, io::Error>>,
+                    Error = io::Error,
+                    Future = Response<Message<&'static str, Body<u32, io::Error>>,
+                                              io::Error>>>,
+        Box<Any>)
+{
+    multiplex_client_with(request_timeout, multiplex::MultiplexConfig::default())
+}
+
+pub fn multiplex_client_with_config(config: multiplex::MultiplexConfig)
+    -> (MockTransportCtl<multiplex::Frame<&'static str, u32, io::Error>>,
+        Box<Service<Request = Message<&'static str, MockBodyStream>,
+                    Response = Message<&'static str, Body<u32, io::Error>>,
+                    Error = io::Error,
+                    Future = Response<Message<&'static str, Body<u32, io::Error>>,
+                                              io::Error>>>,
+        Box<Any>)
+{
+    multiplex_client_with(None, config)
+}
+
+fn multiplex_client_with(request_timeout: Option<Duration>, config: multiplex::MultiplexConfig)
+    -> (MockTransportCtl<multiplex::Frame<&'static str, u32, io::Error>>,
+        Box<Service<Request = Message<&'static str, MockBodyStream>,
+                    Response = Message<&'static str, Body<u32, io::Error>>,
+                    Error = io::Error,
+                    Future = Response<Message<&'static str, Body<u32, io::Error>>,
+                                              io::Error>>>,
+        Box<Any>)
+{
+    let (ctl, service, srv) = multiplex_client_proxy(request_timeout, config);
+    return (ctl, Box::new(service), srv);
+}
+
+/// Returns the client itself instead of a boxed `Service`, for access to
+/// `ClientProxy::notify` and the like
+pub fn multiplex_client_proxy(request_timeout: Option<Duration>, config: multiplex::MultiplexConfig)
+    -> (MockTransportCtl<multiplex::Frame<&'static str, u32, io::Error>>,
+        ClientProxy<Message<&'static str, MockBodyStream>,
+                    Message<&'static str, Body<u32, io::Error>>,
+                    io::Error>,
+        Box<Any>)
 {
     drop(env_logger::init());
 
-    let (ctl, proto) = transport();
+    let (ctl, mut proto) = transport(request_timeout);
+    proto.multiplex_config = config;
 
     let (tx, rx) = oneshot::channel();
     let (finished_tx, finished_rx) = oneshot::channel();
@@ -298,7 +548,7 @@ pub fn multiplex_client()
         thread: Some(t),
         tx: Some(finished_tx),
     };
-    return (ctl, Box::new(service), Box::new(srv));
+    return (ctl, service, Box::new(srv));
 }
 
 pub fn multiplex_server<S>(s: S)
@@ -306,10 +556,40 @@ pub fn multiplex_server<S>(s: S)
     where S: Service<Request = Message<&'static str, Body<u32, io::Error>>,
                      Response = Message<&'static str, MockBodyStream>,
                      Error = io::Error> + Send + 'static,
+{
+    multiplex_server_with_timeout(s, None)
+}
+
+pub fn multiplex_server_with_timeout<S>(s: S, request_timeout: Option<Duration>)
+    -> (MockTransportCtl<multiplex::Frame<&'static str, u32, io::Error>>, Box<Any>)
+    where S: Service<Request = Message<&'static str, Body<u32, io::Error>>,
+                     Response = Message<&'static str, MockBodyStream>,
+                     Error = io::Error> + Send + 'static,
+{
+    multiplex_server_with(s, request_timeout, multiplex::MultiplexConfig::default())
+}
+
+pub fn multiplex_server_with_config<S>(s: S, config: multiplex::MultiplexConfig)
+    -> (MockTransportCtl<multiplex::Frame<&'static str, u32, io::Error>>, Box<Any>)
+    where S: Service<Request = Message<&'static str, Body<u32, io::Error>>,
+                     Response = Message<&'static str, MockBodyStream>,
+                     Error = io::Error> + Send + 'static,
+{
+    multiplex_server_with(s, None, config)
+}
+
+fn multiplex_server_with<S>(s: S,
+                            request_timeout: Option<Duration>,
+                            config: multiplex::MultiplexConfig)
+    -> (MockTransportCtl<multiplex::Frame<&'static str, u32, io::Error>>, Box<Any>)
+    where S: Service<Request = Message<&'static str, Body<u32, io::Error>>,
+                     Response = Message<&'static str, MockBodyStream>,
+                     Error = io::Error> + Send + 'static,
 {
     drop(env_logger::init());
 
-    let (ctl, proto) = transport();
+    let (ctl, mut proto) = transport(request_timeout);
+    proto.multiplex_config = config;
 
     let (finished_tx, finished_rx) = oneshot::channel();
     let t = thread::spawn(move || {
@@ -326,3 +606,38 @@ pub fn multiplex_server<S>(s: S)
     };
     return (ctl, Box::new(srv));
 }
+
+pub fn multiplex_peer<S>(side: multiplex::Side, s: S)
+    -> (MockTransportCtl<multiplex::Frame<&'static str, u32, io::Error>>,
+        multiplex::Peer<Message<&'static str, MockBodyStream>,
+                        Message<&'static str, Body<u32, io::Error>>,
+                        io::Error>,
+        Box<Any>)
+    where S: Service<Request = Message<&'static str, Body<u32, io::Error>>,
+                     Response = Message<&'static str, MockBodyStream>,
+                     Error = io::Error> + Send + 'static,
+{
+    drop(env_logger::init());
+
+    let (ctl, proto) = transport(None);
+
+    let (tx, rx) = oneshot::channel();
+    let (finished_tx, finished_rx) = oneshot::channel();
+    let t = thread::spawn(move || {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let transport = proto.transport.borrow_mut().take().unwrap();
+        let peer = multiplex::Peer::bind(&handle, transport, side, s);
+        tx.complete(peer);
+        drop(core.run(finished_rx));
+    });
+
+    let peer = rx.wait().unwrap();
+
+    let srv = CompleteOnDrop {
+        thread: Some(t),
+        tx: Some(finished_tx),
+    };
+    return (ctl, peer, Box::new(srv));
+}
@@ -0,0 +1,139 @@
+#![allow(deprecated)]
+
+extern crate futures;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::cell::Cell;
+use std::io;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use futures::{future, Future};
+use tokio_proto::util::balance::{Balancer, Policy};
+use tokio_service::Service;
+
+// A backend that answers with its index, or fails while `failing` is set.
+struct Backend {
+    index: usize,
+    failing: Rc<Cell<bool>>,
+}
+
+impl Service for Backend {
+    type Request = ();
+    type Response = usize;
+    type Error = io::Error;
+    type Future = future::FutureResult<usize, io::Error>;
+
+    fn call(&self, _: ()) -> Self::Future {
+        if self.failing.get() {
+            future::err(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset"))
+        } else {
+            future::ok(self.index)
+        }
+    }
+}
+
+fn balancer(n: usize, policy: Policy) -> (Balancer<Backend>, Vec<Rc<Cell<bool>>>) {
+    let failing = (0..n).map(|_| Rc::new(Cell::new(false))).collect::<Vec<_>>();
+    let backends = failing.iter().enumerate().map(|(i, failing)| {
+        Backend { index: i, failing: failing.clone() }
+    }).collect();
+
+    (Balancer::new(backends, policy), failing)
+}
+
+#[test]
+fn test_round_robin() {
+    let (balancer, _) = balancer(3, Policy::RoundRobin);
+
+    let picked = (0..6).map(|_| balancer.call(()).wait().unwrap()).collect::<Vec<_>>();
+    assert_eq!(vec![0, 1, 2, 0, 1, 2], picked);
+}
+
+#[test]
+fn test_least_outstanding() {
+    let (balancer, _) = balancer(3, Policy::LeastOutstanding);
+
+    // Unfinished responses count as outstanding requests
+    let pending = (0..3).map(|_| balancer.call(())).collect::<Vec<_>>();
+
+    for i in 0..3 {
+        assert_eq!(1, balancer.outstanding(i));
+    }
+
+    let mut pending = pending.into_iter();
+    let first = pending.next().unwrap().wait().unwrap();
+    assert_eq!(0, balancer.outstanding(first));
+
+    // Only the backend that answered has no outstanding requests
+    for _ in 0..3 {
+        assert_eq!(first, balancer.call(()).wait().unwrap());
+    }
+}
+
+#[test]
+fn test_power_of_two_choices() {
+    let (balancer, _) = balancer(2, Policy::PowerOfTwoChoices);
+
+    let busy = balancer.call(());
+    let busy_index = (0..2).find(|&i| balancer.outstanding(i) == 1).unwrap();
+
+    // With two backends, both are always among the choices
+    for _ in 0..10 {
+        assert_eq!(1 - busy_index, balancer.call(()).wait().unwrap());
+    }
+
+    assert_eq!(busy_index, busy.wait().unwrap());
+}
+
+#[test]
+fn test_ejects_failing_backend() {
+    let (mut balancer, failing) = balancer(2, Policy::RoundRobin);
+    balancer.eject_after(2);
+    balancer.eject_for(Duration::from_millis(50));
+
+    failing[0].set(true);
+
+    assert!(balancer.call(()).wait().is_err());
+    assert_eq!(1, balancer.call(()).wait().unwrap());
+    assert!(!balancer.is_ejected(0));
+
+    assert!(balancer.call(()).wait().is_err());
+    assert!(balancer.is_ejected(0));
+
+    for _ in 0..4 {
+        assert_eq!(1, balancer.call(()).wait().unwrap());
+    }
+
+    // Once the ejection period is over, a failed probe ejects the backend
+    // again
+    thread::sleep(Duration::from_millis(60));
+
+    let resps = (0..2).map(|_| balancer.call(()).wait()).collect::<Vec<_>>();
+    assert_eq!(1, resps.iter().filter(|resp| resp.is_err()).count());
+    assert!(balancer.is_ejected(0));
+
+    // A successful probe puts the backend back into rotation
+    failing[0].set(false);
+    thread::sleep(Duration::from_millis(60));
+
+    let mut resps = (0..2).map(|_| balancer.call(()).wait().unwrap()).collect::<Vec<_>>();
+    resps.sort();
+    assert_eq!(vec![0, 1], resps);
+    assert!(!balancer.is_ejected(0));
+}
+
+#[test]
+fn test_no_backends_available() {
+    let (mut balancer, failing) = balancer(1, Policy::LeastOutstanding);
+    balancer.eject_after(1);
+
+    failing[0].set(true);
+    assert!(balancer.call(()).wait().is_err());
+    assert!(balancer.is_ejected(0));
+
+    let err = balancer.call(()).wait().err().unwrap();
+    assert_eq!(io::ErrorKind::NotConnected, err.kind());
+}
@@ -0,0 +1,196 @@
+#![allow(deprecated)]
+
+extern crate futures;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io;
+use std::thread;
+
+use futures::{future, Async, Future, Stream};
+use tokio_proto::util::client_proxy::{self, ClientProxy, Receiver, Request, SyncClient};
+use tokio_service::Service;
+
+type Proxy = ClientProxy<u32, u32, io::Error>;
+
+// Queues requests until `try_call` hands one back, and returns how many were
+// queued.
+fn fill(client: &Proxy) -> u32 {
+    let mut i = 0;
+
+    while let Ok(_) = client.try_call(i) {
+        i += 1;
+    }
+
+    i
+}
+
+#[test]
+fn test_bounded_try_call() {
+    let (client, mut rx): (Proxy, Receiver<_, _, _>) = client_proxy::bounded(2);
+
+    future::lazy(|| {
+        assert!(client.poll_ready().is_ready());
+
+        // The queue holds one extra request for every sender
+        assert_eq!(3, fill(&client));
+        assert!(!client.poll_ready().is_ready());
+        assert_eq!(Err(7), client.try_call(7).map(|_| ()));
+
+        // Calling a full client fails right away
+        let err = client.call(7).wait().unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+
+        // Taking a request off the queue makes room for another one
+        match rx.poll() {
+            Ok(Async::Ready(Some(Ok((req, _))))) => assert_eq!(0, req),
+            _ => panic!("expected a request"),
+        }
+
+        assert!(client.poll_ready().is_ready());
+        assert!(client.try_call(8).is_ok());
+
+        Ok::<(), ()>(())
+    }).wait().unwrap();
+}
+
+#[test]
+fn test_bounded_closed() {
+    let (client, rx): (Proxy, Receiver<_, _, _>) = client_proxy::bounded(1);
+    drop(rx);
+
+    future::lazy(|| {
+        // A closed client is ready to fail requests
+        assert!(client.poll_ready().is_ready());
+
+        let err = client.try_call(1).ok().unwrap().wait().unwrap_err();
+        assert_eq!(io::ErrorKind::BrokenPipe, err.kind());
+
+        Ok::<(), ()>(())
+    }).wait().unwrap();
+}
+
+#[test]
+fn test_unbounded_always_ready() {
+    let (client, _rx): (Proxy, Receiver<_, _, _>) = client_proxy::pair();
+
+    for i in 0..100 {
+        assert!(client.poll_ready().is_ready());
+        assert!(client.try_call(i).is_ok());
+    }
+}
+
+#[test]
+fn test_connection_error() {
+    let (client, rx): (Proxy, Receiver<_, _, _>) = client_proxy::pair();
+    let closed = client.closed();
+
+    let pending = client.call(1);
+
+    rx.error_handle().set(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+    drop(rx);
+
+    // Pending and future requests fail with the error that ended the
+    // connection
+    let err = pending.wait().unwrap_err();
+    assert_eq!(io::ErrorKind::ConnectionReset, err.kind());
+    assert_eq!("reset", err.to_string());
+
+    let err = client.call(2).wait().unwrap_err();
+    assert_eq!(io::ErrorKind::ConnectionReset, err.kind());
+
+    let err = closed.wait().unwrap_err();
+    assert_eq!(io::ErrorKind::ConnectionReset, err.kind());
+}
+
+#[test]
+fn test_closed_cleanly() {
+    let (client, rx): (Proxy, Receiver<_, _, _>) = client_proxy::pair();
+    let closed = client.closed();
+
+    future::lazy(|| {
+        let mut closed = client.closed();
+        assert!(!closed.poll().unwrap().is_ready());
+
+        Ok::<(), ()>(())
+    }).wait().unwrap();
+
+    drop(rx);
+    closed.wait().unwrap();
+}
+
+#[test]
+fn test_notify() {
+    let (client, mut rx): (Proxy, Receiver<_, _, _>) = client_proxy::pair();
+
+    future::lazy(|| {
+        let notified = client.notify(1);
+        let pending = client.call(2);
+
+        // Notifications come out of the same queue as requests
+        match rx.poll_request() {
+            Ok(Async::Ready(Some(Ok(Request::Notify(req, complete))))) => {
+                assert_eq!(1, req);
+                complete.complete(Ok(()));
+            }
+            _ => panic!("expected a notification"),
+        }
+
+        notified.wait().unwrap();
+
+        match rx.poll_request() {
+            Ok(Async::Ready(Some(Ok(Request::Call(req, _))))) => assert_eq!(2, req),
+            _ => panic!("expected a request"),
+        }
+
+        drop(pending);
+        Ok::<(), ()>(())
+    }).wait().unwrap();
+}
+
+#[test]
+fn test_notify_unsupported() {
+    let (client, mut rx): (Proxy, Receiver<_, _, _>) = client_proxy::pair();
+
+    future::lazy(|| {
+        let notified = client.notify(1);
+        let _pending = client.call(2);
+
+        // Receivers used as a `Stream` only yield requests, and fail the
+        // notifications in between
+        match rx.poll() {
+            Ok(Async::Ready(Some(Ok((req, _))))) => assert_eq!(2, req),
+            _ => panic!("expected a request"),
+        }
+
+        let err = notified.wait().unwrap_err();
+        assert_eq!(io::ErrorKind::Other, err.kind());
+
+        Ok::<(), ()>(())
+    }).wait().unwrap();
+}
+
+fn assert_send_sync<T: Send + Sync + Clone>() {}
+
+#[test]
+fn test_sync_client() {
+    assert_send_sync::<SyncClient<u32, u32, io::Error>>();
+
+    let (client, rx): (Proxy, Receiver<_, _, _>) = client_proxy::pair();
+    let client = SyncClient::new(client);
+
+    let threads = (0..4).map(|i| {
+        let client = client.clone();
+        thread::spawn(move || client.call_wait(i).unwrap())
+    }).collect::<Vec<_>>();
+
+    // Answer each request with its double
+    for envelope in rx.wait().take(4) {
+        let (req, complete) = envelope.unwrap().unwrap();
+        complete.complete(Ok(req * 2));
+    }
+
+    let mut responses = threads.into_iter().map(|t| t.join().unwrap()).collect::<Vec<_>>();
+    responses.sort();
+    assert_eq!(vec![0, 2, 4, 6], responses);
+}
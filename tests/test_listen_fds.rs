@@ -0,0 +1,181 @@
+#![cfg(unix)]
+#![allow(deprecated)]
+
+extern crate futures;
+extern crate libc;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::os::unix::process::CommandExt;
+use std::process::{self, Child, Command};
+use std::time::Duration;
+
+use futures::future;
+use tokio_proto::{listen_fds, TcpServer};
+
+mod support;
+use support::line::LineProto;
+use support::service::simple_service;
+
+// Runs the ignored test `name` in a child process, passing `fd` the way a
+// supervisor would. The shell sets `LISTEN_PID` to its own ID, which the test
+// binary keeps when the shell execs it.
+fn spawn_with_listen_fd(fd: RawFd, name: &str) -> Child {
+    unsafe {
+        Command::new("/bin/sh")
+            .arg("-c")
+            .arg("export LISTEN_PID=$$; exec \"$0\" \"$@\"")
+            .arg(env::current_exe().unwrap())
+            .args(&["--ignored", "--exact", name])
+            .env("LISTEN_FDS", "1")
+            .pre_exec(move || {
+                let ret = if fd == 3 {
+                    libc::fcntl(fd, libc::F_SETFD, 0)
+                } else {
+                    libc::dup2(fd, 3)
+                };
+
+                if ret == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                Ok(())
+            })
+            .spawn()
+            .unwrap()
+    }
+}
+
+#[test]
+fn test_inherited_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut child = spawn_with_listen_fd(listener.as_raw_fd(), "serve_inherited_listener");
+    drop(listener);
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    sock.write_all(b"ping\n").unwrap();
+
+    let mut resp = [0; 5];
+    let res = sock.read_exact(&mut resp);
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    res.unwrap();
+    assert_eq!(b"PING\n", &resp);
+}
+
+#[test]
+fn test_rejects_udp_socket() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut child = spawn_with_listen_fd(socket.as_raw_fd(), "reject_inherited_socket");
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn test_rejects_unix_socket() {
+    let path = env::temp_dir().join(format!("tokio-proto-listen-fds-{}.sock", process::id()));
+    let listener = UnixListener::bind(&path).unwrap();
+    let mut child = spawn_with_listen_fd(listener.as_raw_fd(), "reject_inherited_socket");
+    let status = child.wait().unwrap();
+
+    drop(fs::remove_file(&path));
+    assert!(status.success());
+}
+
+#[test]
+fn test_rejects_socket_that_is_not_listening() {
+    let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+    assert!(socket >= 0);
+
+    let mut child = spawn_with_listen_fd(socket, "reject_inherited_socket");
+    let status = child.wait().unwrap();
+
+    unsafe { libc::close(socket) };
+    assert!(status.success());
+}
+
+#[test]
+#[ignore]
+fn reject_inherited_socket() {
+    // Only does something when run by the `test_rejects_*` tests
+    if env::var("LISTEN_FDS").is_err() {
+        return;
+    }
+
+    let err = listen_fds().unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+}
+
+#[test]
+#[ignore]
+fn serve_inherited_listener() {
+    let listeners = listen_fds().unwrap();
+
+    // Only does something when run by `test_inherited_listener`
+    if listeners.is_empty() {
+        return;
+    }
+
+    let mut server = TcpServer::from_listeners(LineProto, listeners).unwrap();
+    server.threads(2);
+    server.serve(|| Ok(simple_service(|req: String| future::ok(req.to_uppercase()))));
+}
+
+#[test]
+fn test_spawn_successor() {
+    let mut server = TcpServer::new(LineProto, "127.0.0.1:0".parse().unwrap());
+    server.threads(2);
+
+    let server = server.start(|| Ok(simple_service(|req: String| future::ok(req)))).unwrap();
+    let addr = server.local_addr();
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    assert_eq!("ping\n", echo(&mut sock, "ping").unwrap());
+
+    let mut command = Command::new(env::current_exe().unwrap());
+    command.args(&["--ignored", "--exact", "serve_inherited_listener"]);
+
+    let mut child = server.spawn_successor(&mut command).unwrap();
+    server.shutdown_graceful(Duration::from_secs(5));
+
+    // The open connection is closed along with the old server, while new
+    // connections are served by the successor.
+    assert!(echo(&mut sock, "ping").is_err());
+
+    let res = (0..4).map(|_| {
+        let mut sock = try!(TcpStream::connect(&addr));
+        echo(&mut sock, "ping")
+    }).collect::<io::Result<Vec<_>>>();
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    for resp in res.unwrap() {
+        assert_eq!("PING\n", resp);
+    }
+}
+
+#[test]
+fn test_no_listen_fds() {
+    assert!(listen_fds().unwrap().is_empty());
+}
+
+fn echo(sock: &mut TcpStream, msg: &str) -> io::Result<String> {
+    try!(sock.set_read_timeout(Some(Duration::from_secs(10))));
+    try!(sock.write_all(format!("{}\n", msg).as_bytes()));
+
+    let mut resp = vec![0; msg.len() + 1];
+    try!(sock.read_exact(&mut resp));
+    Ok(String::from_utf8(resp).unwrap())
+}
@@ -11,11 +11,13 @@ extern crate log;
 extern crate env_logger;
 
 use std::io;
+use std::thread;
+use std::time::Duration;
 
 use futures::stream::{Stream};
 use futures::{Future};
 use tokio_proto::streaming::Message;
-use tokio_proto::streaming::multiplex::{RequestId, Frame};
+use tokio_proto::streaming::multiplex::{RequestId, Frame, MultiplexConfig};
 use tokio_service::Service;
 
 mod support;
@@ -89,6 +91,298 @@ fn drop_client_while_streaming_body() {
     mock.allow_and_assert_drop();
 }
 
+#[test]
+fn test_request_timeout() {
+    let (mut mock, service, _other) =
+        mock::multiplex_client_with_timeout(Some(Duration::from_millis(50)));
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+    assert_eq!(io::ErrorKind::TimedOut, pong.wait().unwrap_err().kind());
+    assert_eq!(vec![0], mock.canceled());
+
+    // The late response is dropped and the connection stays usable
+    mock.send(msg(0, "late pong"));
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(1, mock.next_write().request_id());
+    mock.send(msg(1, "pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_reaching_max_in_flight_requests() {
+    let (mut mock, service, _other) = mock::multiplex_client();
+
+    let responses = (0..33).map(|_| {
+        service.call(Message::WithoutBody("ping"))
+    }).collect::<Vec<_>>();
+
+    for i in 0..32 {
+        assert_eq!(i, mock.next_write().request_id());
+    }
+
+    // The last request waits for a slot
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(32, mock.written());
+
+    mock.send(msg(5, "pong"));
+    assert_eq!(32, mock.next_write().request_id());
+
+    for (i, response) in responses.into_iter().enumerate() {
+        if i != 5 {
+            mock.send(msg(i as RequestId, "pong"));
+        }
+
+        assert_eq!("pong", response.wait().unwrap().into_inner());
+    }
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_max_queued_requests() {
+    let mut config = MultiplexConfig::default();
+    config.max_in_flight = 1;
+    config.max_queued = Some(1);
+
+    let (mut mock, service, _other) = mock::multiplex_client_with_config(config);
+
+    let first = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+
+    // The queue holds one request, plus one for the client
+    let queued = (0..2).map(|_| {
+        service.call(Message::WithoutBody("ping"))
+    }).collect::<Vec<_>>();
+
+    let err = service.call(Message::WithoutBody("ping")).wait().unwrap_err();
+    assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+
+    mock.send(msg(0, "pong"));
+    assert_eq!("pong", first.wait().unwrap().into_inner());
+
+    for (i, response) in queued.into_iter().enumerate() {
+        let id = i as RequestId + 1;
+        assert_eq!(id, mock.next_write().request_id());
+        mock.send(msg(id, "pong"));
+        assert_eq!("pong", response.wait().unwrap().into_inner());
+    }
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_drop_response_cancels_request() {
+    let (mut mock, service, _other) = mock::multiplex_client();
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+
+    drop(pong);
+    wait_for_cancel(&mock);
+    assert_eq!(vec![0], mock.canceled());
+
+    // The late response is dropped and the connection stays usable
+    mock.send(msg(0, "late pong"));
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(1, mock.next_write().request_id());
+    mock.send(msg(1, "pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_drop_response_body_cancels_request() {
+    let (mut mock, service, _other) = mock::multiplex_client();
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+
+    mock.send(msg_with_body(0, "pong"));
+    mock.send(body(0, Some(0)));
+
+    let mut pong = pong.wait().unwrap();
+    drop(pong.take_body());
+
+    // The dropped body is noticed once the next chunk arrives
+    mock.send(body(0, Some(1)));
+    wait_for_cancel(&mock);
+    assert_eq!(vec![0], mock.canceled());
+
+    mock.send(body(0, Some(2)));
+    mock.send(body(0, None));
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_transport_error_fails_requests() {
+    let (mut mock, service, _other) = mock::multiplex_client();
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+
+    mock.error(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+
+    // Pending and future requests see the error that ended the connection
+    assert_eq!(io::ErrorKind::ConnectionReset, pong.wait().unwrap_err().kind());
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(io::ErrorKind::ConnectionReset, pong.wait().unwrap_err().kind());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_max_canceled_requests() {
+    let mut config = MultiplexConfig::default();
+    config.max_canceled = 2;
+
+    let (mut mock, service, _other) = mock::multiplex_client_with_config(config);
+
+    // Canceled one by one, so that they are canceled in order
+    for i in 0..3 {
+        let pong = service.call(Message::WithoutBody("ping"));
+        assert_eq!(i, mock.next_write().request_id());
+        drop(pong);
+
+        while mock.canceled().len() <= i as usize {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Late responses to the most recently canceled requests are dropped
+    mock.send(msg(1, "late pong"));
+    mock.send(msg(2, "late pong"));
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(3, mock.next_write().request_id());
+    mock.send(msg(3, "pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+
+    // The oldest one has been forgotten, but its ID was issued, so its late
+    // response is dropped as well
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(4, mock.next_write().request_id());
+    mock.send(msg(0, "late pong"));
+    mock.send(msg(4, "pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+
+    // An ID that was never issued is still unexpected
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(5, mock.next_write().request_id());
+    mock.send(msg(6, "pong"));
+    assert_eq!(io::ErrorKind::InvalidData, pong.wait().unwrap_err().kind());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_unexpected_response() {
+    let (mut mock, service, _other) = mock::multiplex_client();
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+
+    // A response to an unknown request shuts the connection down, failing
+    // the outstanding requests
+    mock.send(msg(7, "pong"));
+    assert_eq!(io::ErrorKind::InvalidData, pong.wait().unwrap_err().kind());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_notify() {
+    let (mut mock, client, _other) = mock::multiplex_client_proxy(None, MultiplexConfig::default());
+
+    let written = client.notify(Message::WithoutBody("hello"));
+
+    match mock.next_write() {
+        Frame::Message { id, message, solo, .. } => {
+            assert_eq!(0, id);
+            assert_eq!("hello", message);
+            assert!(solo);
+        }
+        _ => panic!("unexpected frame"),
+    }
+
+    written.wait().unwrap();
+
+    // Notifications take up request IDs, but are not waited on
+    client.notify(Message::WithoutBody("again")).wait().unwrap();
+    assert_eq!(1, mock.next_write().request_id());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_notify_connection_error() {
+    let (mut mock, client, _other) = mock::multiplex_client_proxy(None, MultiplexConfig::default());
+
+    // The transport holds two frames, so the third is never flushed
+    let _first = client.notify(Message::WithoutBody("one"));
+    let _second = client.notify(Message::WithoutBody("two"));
+    let third = client.notify(Message::WithoutBody("three"));
+
+    while mock.written() < 2 {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    mock.error(io::Error::new(io::ErrorKind::ConnectionReset, "boom"));
+
+    let err = third.wait().unwrap_err();
+    assert_eq!(io::ErrorKind::ConnectionReset, err.kind());
+}
+
+#[test]
+fn test_notify_max_queued() {
+    let mut config = MultiplexConfig::default();
+    config.max_in_flight = 1;
+    config.max_queued = Some(1);
+
+    let (mut mock, client, _other) = mock::multiplex_client_proxy(None, config);
+
+    let first = client.call(Message::WithoutBody("ping"));
+    assert_eq!(0, mock.next_write().request_id());
+
+    // Notifications wait in the same queue as requests
+    let queued = client.notify(Message::WithoutBody("hello"));
+    let second = client.call(Message::WithoutBody("ping"));
+
+    let err = client.notify(Message::WithoutBody("hello")).wait().unwrap_err();
+    assert_eq!(io::ErrorKind::WouldBlock, err.kind());
+
+    mock.send(msg(0, "pong"));
+    assert_eq!("pong", first.wait().unwrap().into_inner());
+
+    match mock.next_write() {
+        Frame::Message { id, solo, .. } => {
+            assert_eq!(1, id);
+            assert!(solo);
+        }
+        _ => panic!("unexpected frame"),
+    }
+    queued.wait().unwrap();
+
+    assert_eq!(2, mock.next_write().request_id());
+    mock.send(msg(2, "pong"));
+    assert_eq!("pong", second.wait().unwrap().into_inner());
+
+    mock.allow_and_assert_drop();
+}
+
+fn wait_for_cancel<T>(mock: &mock::MockTransportCtl<T>) {
+    while mock.canceled().is_empty() {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
 fn msg(id: RequestId, msg: &'static str) -> Frame<&'static str, u32, io::Error> {
     Frame::Message {
         id: id,
@@ -0,0 +1,112 @@
+#![allow(deprecated)]
+
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io;
+
+use futures::Future;
+use futures::future;
+use tokio_proto::streaming::{Message, Body};
+use tokio_proto::streaming::multiplex::{Frame, RequestId, Side};
+use tokio_service::Service;
+
+mod support;
+use support::mock;
+use support::service::simple_service;
+
+#[test]
+fn test_call_uses_odd_request_ids() {
+    let service = simple_service(|_| future::empty());
+
+    let (mut mock, peer, _other) = mock::multiplex_peer(Side::Odd, service);
+
+    for &id in &[1, 3] {
+        let pong = peer.call(Message::WithoutBody("ping"));
+
+        let wr = mock.next_write();
+        assert_eq!(id, wr.request_id());
+        assert_eq!("ping", wr.unwrap_msg());
+
+        mock.send(msg(id, "pong"));
+        assert_eq!("pong", pong.wait().unwrap().into_inner());
+    }
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_call_uses_even_request_ids() {
+    let service = simple_service(|_| future::empty());
+
+    let (mut mock, peer, _other) = mock::multiplex_peer(Side::Even, service);
+
+    for &id in &[0, 2] {
+        let pong = peer.call(Message::WithoutBody("ping"));
+
+        let wr = mock.next_write();
+        assert_eq!(id, wr.request_id());
+
+        mock.send(msg(id, "pong"));
+        assert_eq!("pong", pong.wait().unwrap().into_inner());
+    }
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_requests_in_both_directions() {
+    let service = simple_service(|req: Message<&'static str, Body<u32, io::Error>>| {
+        assert_eq!(req, "hello");
+        future::ok(Message::WithoutBody("welcome"))
+    });
+
+    let (mut mock, peer, _other) = mock::multiplex_peer(Side::Odd, service);
+
+    let pong = peer.call(Message::WithoutBody("ping"));
+
+    let wr = mock.next_write();
+    assert_eq!(1, wr.request_id());
+    assert_eq!("ping", wr.unwrap_msg());
+
+    // The other end issues a request while its response is outstanding
+    mock.send(msg(0, "hello"));
+
+    let wr = mock.next_write();
+    assert_eq!(0, wr.request_id());
+    assert_eq!("welcome", wr.unwrap_msg());
+
+    mock.send(msg(1, "pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_serving_after_peer_dropped() {
+    let service = simple_service(|req: Message<&'static str, Body<u32, io::Error>>| {
+        future::ok(Message::WithoutBody(req.into_inner()))
+    });
+
+    let (mut mock, peer, _other) = mock::multiplex_peer(Side::Odd, service);
+    drop(peer);
+
+    mock.send(msg(0, "hello"));
+
+    let wr = mock.next_write();
+    assert_eq!(0, wr.request_id());
+    assert_eq!("hello", wr.unwrap_msg());
+
+    mock.allow_and_assert_drop();
+}
+
+fn msg(id: RequestId, msg: &'static str) -> Frame<&'static str, u32, io::Error> {
+    Frame::Message {
+        id: id,
+        message: msg,
+        body: false,
+        solo: false,
+    }
+}
@@ -18,10 +18,11 @@ use std::time::Duration;
 
 use futures::{Future, Stream, Sink};
 use futures::future;
+use futures::stream;
 use futures::sync::oneshot;
 use futures::sync::mpsc;
 use tokio_proto::streaming::{Message, Body};
-use tokio_proto::streaming::multiplex::{Frame, RequestId};
+use tokio_proto::streaming::multiplex::{Frame, MultiplexConfig, RequestId};
 use rand::Rng;
 
 mod support;
@@ -282,6 +283,66 @@ fn test_reaching_max_in_flight_requests() {
     mock.allow_and_assert_drop();
 }
 
+#[test]
+fn test_configured_max_in_flight_requests() {
+    let (mut tx, rx) = mpsc::unbounded();
+    let rx = RefCell::new(rx.wait());
+
+    let c1 = Arc::new(AtomicUsize::new(0));
+    let c2 = c1.clone();
+
+    let service = simple_service(move |_| {
+        c2.fetch_add(1, Ordering::SeqCst);
+        let fut = rx.borrow_mut().next().unwrap().unwrap();
+        let fut: oneshot::Receiver<_> = fut;
+        fut.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"))
+           .and_then(|res| res)
+    });
+
+    let mut config = MultiplexConfig::default();
+    config.max_in_flight = 2;
+
+    let mut responses = vec![];
+
+    let (mut mock, _other) = mock::multiplex_server_with_config(service, config);
+    for i in 0..3 {
+        let (c, resp) = oneshot::channel();
+        mpsc::UnboundedSender::send(&mut tx, resp).unwrap();
+        responses.push((i, c));
+        mock.send(msg(i, "request"));
+    }
+
+    // Only 2 requests processed
+    while c1.load(Ordering::SeqCst) < 2 {
+        thread::yield_now();
+    }
+
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(2, c1.load(Ordering::SeqCst));
+
+    let (i, c) = responses.remove(1);
+    c.complete(Ok(Message::WithoutBody("zomg")));
+
+    let wr = mock.next_write();
+    assert_eq!(i, wr.request_id());
+    assert_eq!("zomg", wr.unwrap_msg());
+
+    // Next request is processed
+    while 3 != c1.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+
+    for (i, c) in responses.drain(..) {
+        c.complete(Ok(Message::WithoutBody("zomg")));
+
+        let wr = mock.next_write();
+        assert_eq!(i, wr.request_id());
+        assert_eq!("zomg", wr.unwrap_msg());
+    }
+
+    mock.allow_and_assert_drop();
+}
+
 #[test]
 fn test_basic_streaming_response_body() {
     let (tx, rx) = mpsc::channel(1);
@@ -436,8 +497,209 @@ fn test_transport_provides_invalid_request_ids() {
 }
 
 #[test]
-#[ignore]
 fn test_reaching_max_buffered_frames() {
+    let (c, fut) = oneshot::channel::<io::Result<Message<&'static str, _>>>();
+    let fut = RefCell::new(Some(fut));
+    let (tx, rx) = mpsc::unbounded();
+
+    let service = simple_service(move |mut req: Message<&'static str, Body<u32, io::Error>>| {
+        match fut.borrow_mut().take() {
+            Some(fut) => {
+                // Hold on to the body without reading it
+                mpsc::UnboundedSender::send(&tx, req.take_body().unwrap()).unwrap();
+                fut.then(|r| r.unwrap()).boxed()
+            }
+            None => future::ok(Message::WithoutBody(req.into_inner())).boxed(),
+        }
+    });
+
+    let mut config = MultiplexConfig::default();
+    config.max_buffered_frames = 32;
+
+    let (mut mock, _other) = mock::multiplex_server_with_config(service, config);
+    mock.send(msg_with_body(0, "slow"));
+
+    let body = rx.wait().next().unwrap().unwrap();
+
+    // The first chunk is held by the body stream and the next 32 fill up the
+    // frame buffer. The rest is left unread.
+    for i in 0..43 {
+        mock.send(Frame::Body { id: 0, chunk: Some(i) });
+    }
+
+    mock.send(Frame::Body { id: 0, chunk: None });
+    mock.send(msg(1, "fast"));
+
+    // Once the buffer is full, running the dispatcher again reads nothing
+    // more, so the fast request is not even seen
+    while mock.read() < 34 {
+        thread::yield_now();
+    }
+
+    mock.run_task();
+    assert_eq!(34, mock.read());
+    assert_eq!(0, mock.written());
+
+    // Reading the body frees up the buffer
+    let chunks = body.wait().map(|chunk| chunk.unwrap()).collect::<Vec<_>>();
+    assert_eq!((0..43).collect::<Vec<_>>(), chunks);
+
+    c.complete(Ok(Message::WithoutBody("slow")));
+
+    let mut wrs = vec![mock.next_write(), mock.next_write()].into_iter()
+        .map(|wr| (wr.request_id(), wr.unwrap_msg()))
+        .collect::<Vec<_>>();
+    wrs.sort();
+
+    assert_eq!(vec![(0, "slow"), (1, "fast")], wrs);
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_reaching_max_buffered_chunks() {
+    let (tx, rx) = mpsc::unbounded();
+
+    let service = simple_service(move |mut req: Message<&'static str, Body<u32, io::Error>>| {
+        // Hold on to the body without reading it
+        mpsc::UnboundedSender::send(&tx, req.take_body().unwrap()).unwrap();
+        future::empty()
+    });
+
+    let mut config = MultiplexConfig::default();
+    config.max_buffered_chunks = 2;
+
+    let (mut mock, _other) = mock::multiplex_server_with_config(service, config);
+    mock.send(msg_with_body(0, "have-body"));
+
+    let _body = rx.wait().next().unwrap().unwrap();
+
+    // The first chunk is held by the body stream, the next two are buffered
+    // and the last one is one too many
+    for i in 0..4 {
+        mock.send(Frame::Body { id: 0, chunk: Some(i) });
+    }
+
+    mock.assert_drop();
+}
+
+#[test]
+fn test_flow_control_sends_window_updates() {
+    let (tx, rx) = mpsc::unbounded();
+
+    let service = simple_service(move |mut req: Message<&'static str, Body<u32, io::Error>>| {
+        let body = req.take_body().unwrap();
+        let mut tx = tx.clone();
+
+        body.for_each(move |chunk| {
+            mpsc::UnboundedSender::send(&mut tx, chunk).unwrap();
+            Ok(())
+        }).and_then(|_| {
+            Ok(Message::WithoutBody("hi2u"))
+        })
+    });
+
+    let mut config = MultiplexConfig::default();
+    config.window = Some(4);
+
+    let (mut mock, _other) = mock::multiplex_server_with_config(service, config);
+    mock.send(msg_with_body(0, "have-body"));
+
+    let mut rx = rx.wait();
+
+    for round in 0..2 {
+        // Send a full window of chunks
+        for i in 0..4 {
+            mock.send(Frame::Body { id: 0, chunk: Some(round * 4 + i) });
+        }
+
+        for i in 0..4 {
+            assert_eq!(round * 4 + i, rx.next().unwrap().unwrap());
+        }
+
+        // The window is replenished in halves
+        while mock.sent_windows().len() < 2 * (round as usize + 1) {
+            thread::yield_now();
+        }
+    }
+
+    assert_eq!(vec![(0, 2); 4], mock.sent_windows());
+
+    mock.send(Frame::Body { id: 0, chunk: None });
+
+    let wr = mock.next_write();
+    assert_eq!(0, wr.request_id());
+    assert_eq!("hi2u", wr.unwrap_msg());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_flow_control_window_exceeded() {
+    let (tx, rx) = mpsc::unbounded();
+
+    let service = simple_service(move |mut req: Message<&'static str, Body<u32, io::Error>>| {
+        // Hold on to the body without reading it
+        mpsc::UnboundedSender::send(&tx, req.take_body().unwrap()).unwrap();
+        future::empty()
+    });
+
+    let mut config = MultiplexConfig::default();
+    config.window = Some(2);
+
+    let (mut mock, _other) = mock::multiplex_server_with_config(service, config);
+    mock.send(msg_with_body(0, "have-body"));
+
+    let _body = rx.wait().next().unwrap().unwrap();
+
+    // The first chunk is handed to the body stream, which replenishes the
+    // window by one. The peer then sends one chunk too many.
+    for i in 0..4 {
+        mock.send(Frame::Body { id: 0, chunk: Some(i) });
+    }
+
+    mock.assert_drop();
+    assert_eq!(vec![(0, 1)], mock.sent_windows());
+}
+
+#[test]
+fn test_flow_control_holds_back_response_body() {
+    let service = simple_service(|req| {
+        assert_eq!(req, "want-body");
+
+        let body: mock::MockBodyStream = Box::new(stream::iter_ok(0..5));
+        future::ok(Message::WithBody("hi2u", body))
+    });
+
+    let mut config = MultiplexConfig::default();
+    config.window = Some(2);
+
+    let (mut mock, _other) = mock::multiplex_server_with_config(service, config);
+    mock.send(msg(3, "want-body"));
+
+    let wr = mock.next_write();
+    assert_eq!(3, wr.request_id());
+    assert_eq!("hi2u", wr.unwrap_msg());
+
+    for i in 0..5 {
+        if i > 0 && i % 2 == 0 {
+            // Wait for the body to be held back, then allow more chunks
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(1 + i as usize, mock.written());
+
+            mock.window_update(3, 2);
+        }
+
+        let wr = mock.next_write();
+        assert_eq!(3, wr.request_id());
+        assert_eq!(Some(i), wr.unwrap_body());
+    }
+
+    let wr = mock.next_write();
+    assert_eq!(3, wr.request_id());
+    assert_eq!(None, wr.unwrap_body());
+
+    mock.allow_and_assert_drop();
 }
 
 #[test]
@@ -469,6 +731,161 @@ fn test_read_error_during_stream() {
 fn test_error_handling_before_message_dispatched() {
 }
 
+#[test]
+fn test_request_timeout() {
+    let service = simple_service(|req: Message<&'static str, Body<u32, io::Error>>| {
+        match req.into_inner() {
+            "slow" => future::empty().boxed(),
+            req => future::ok(Message::WithoutBody(req)).boxed(),
+        }
+    });
+
+    let (mut mock, _other) = mock::multiplex_server_with_timeout(
+        service, Some(Duration::from_millis(50)));
+
+    mock.send(msg(0, "slow"));
+    mock.send(msg(1, "fast"));
+
+    let wr = mock.next_write();
+    assert_eq!(1, wr.request_id());
+    assert_eq!("fast", wr.unwrap_msg());
+
+    let wr = mock.next_write();
+    assert_eq!(0, wr.request_id());
+    assert_eq!(io::ErrorKind::TimedOut, wr.unwrap_err().kind());
+    assert_eq!(vec![0], mock.canceled());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_error_frame_cancels_in_flight_request() {
+    let (c, fut) = oneshot::channel::<io::Result<Message<&'static str, _>>>();
+    let fut = RefCell::new(Some(fut));
+    let (tx, rx) = mpsc::unbounded();
+
+    let service = simple_service(move |mut req: Message<&'static str, Body<u32, io::Error>>| {
+        match fut.borrow_mut().take() {
+            Some(fut) => {
+                mpsc::UnboundedSender::send(&tx, req.take_body().unwrap()).unwrap();
+                fut.then(|r| r.unwrap()).boxed()
+            }
+            None => future::ok(Message::WithoutBody(req.into_inner())).boxed(),
+        }
+    });
+
+    let (mut mock, _other) = mock::multiplex_server(service);
+    mock.send(msg_with_body(0, "slow"));
+    mock.send(Frame::Body { id: 0, chunk: Some(1) });
+
+    let body = rx.wait().next().unwrap().unwrap();
+
+    mock.send(Frame::Error {
+        id: 0,
+        error: io::Error::new(io::ErrorKind::Other, "boom"),
+    });
+
+    // The error ends the request body, and the response future is dropped
+    let chunks = body.wait().collect::<Vec<_>>();
+    assert_eq!(2, chunks.len());
+    assert_eq!(1, *chunks[0].as_ref().unwrap());
+    assert_eq!(io::ErrorKind::Other, chunks[1].as_ref().unwrap_err().kind());
+
+    while !c.is_canceled() {
+        thread::yield_now();
+    }
+
+    assert_eq!(vec![0], mock.canceled());
+
+    // Nothing is written for the canceled request
+    mock.send(msg(1, "fast"));
+
+    let wr = mock.next_write();
+    assert_eq!(1, wr.request_id());
+    assert_eq!("fast", wr.unwrap_msg());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_transport_cancel_cancels_in_flight_request() {
+    let (c, fut) = oneshot::channel::<io::Result<Message<&'static str, _>>>();
+    let fut = RefCell::new(Some(fut));
+    let (tx, rx) = mpsc::unbounded();
+
+    let service = simple_service(move |mut req: Message<&'static str, Body<u32, io::Error>>| {
+        match fut.borrow_mut().take() {
+            Some(fut) => {
+                mpsc::UnboundedSender::send(&tx, req.take_body().unwrap()).unwrap();
+                fut.then(|r| r.unwrap()).boxed()
+            }
+            None => future::ok(Message::WithoutBody(req.into_inner())).boxed(),
+        }
+    });
+
+    let (mut mock, _other) = mock::multiplex_server(service);
+    mock.send(msg_with_body(0, "slow"));
+    mock.send(Frame::Body { id: 0, chunk: Some(1) });
+
+    let body = rx.wait().next().unwrap().unwrap();
+
+    mock.peer_cancel(0);
+
+    // The request body ends, and the response future is dropped
+    let chunks = body.wait().collect::<Vec<_>>();
+    assert_eq!(1, chunks.len());
+    assert_eq!(1, *chunks[0].as_ref().unwrap());
+
+    while !c.is_canceled() {
+        thread::yield_now();
+    }
+
+    // The peer canceled the exchange, so it is not canceled back
+    assert!(mock.canceled().is_empty());
+
+    // Frames read for the canceled request are ignored, and nothing is
+    // written for it
+    mock.send(Frame::Body { id: 0, chunk: Some(2) });
+    mock.send(msg(1, "fast"));
+
+    let wr = mock.next_write();
+    assert_eq!(1, wr.request_id());
+    assert_eq!("fast", wr.unwrap_msg());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_solo_request_is_not_responded_to() {
+    let (tx, rx) = mpsc::channel(1);
+    let tx = RefCell::new(tx.wait());
+
+    let service = simple_service(move |req: Message<&'static str, Body<u32, io::Error>>| {
+        tx.borrow_mut().send(req.into_inner()).unwrap();
+        future::ok(Message::WithoutBody("goodbye"))
+    });
+
+    let (mut mock, _other) = mock::multiplex_server(service);
+    mock.send(Frame::Message {
+        id: 0,
+        message: "notification",
+        body: false,
+        solo: true,
+    });
+    mock.send(msg(1, "hello"));
+
+    let mut rx = rx.wait();
+    assert_eq!("notification", rx.next().unwrap().unwrap());
+    assert_eq!("hello", rx.next().unwrap().unwrap());
+
+    // Only the regular request is responded to
+    let wr = mock.next_write();
+    assert_eq!(wr.request_id(), 1);
+    assert_eq!(wr.unwrap_msg(), "goodbye");
+
+    mock.allow_and_assert_drop();
+}
+
 fn msg(id: RequestId, msg: &'static str) -> Frame<&'static str, u32, io::Error> {
     Frame::Message {
         id: id,
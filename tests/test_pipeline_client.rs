@@ -74,6 +74,27 @@ fn test_streaming_request_body() {
     mock.allow_and_assert_drop();
 }
 
+#[test]
+fn test_streaming_request_body_error() {
+    let (mut mock, service, _other) = mock::pipeline_client();
+
+    let (tx, rx) = mpsc::channel(1);
+
+    let pong = service.call(Message::WithBody("ping",
+                                              rx.then(|r| r.unwrap()).boxed()));
+
+    assert_eq!("ping", mock.next_write().unwrap_msg());
+
+    let tx = tx.send(Ok(0)).wait().unwrap();
+    assert_eq!(Some(0), mock.next_write().unwrap_body());
+
+    // A failing body shuts the connection down, failing the request
+    drop(tx.send(Err(io::Error::new(io::ErrorKind::Other, "oops"))).wait().unwrap());
+    mock.allow_and_assert_drop();
+
+    assert_eq!(io::ErrorKind::Other, pong.wait().unwrap_err().kind());
+}
+
 #[test]
 #[ignore]
 fn test_streaming_response_body() {
@@ -117,6 +138,38 @@ fn test_streaming_client_transport_dropped() {
     mock.allow_and_assert_drop();
 }
 
+#[test]
+fn test_request_timeout() {
+    let (mut mock, service, _other) =
+        mock::pipeline_client_with_timeout(Some(Duration::from_millis(50)));
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!("ping", mock.next_write().unwrap_msg());
+    assert_eq!(io::ErrorKind::TimedOut, pong.wait().unwrap_err().kind());
+
+    // The late response is dropped and the connection stays usable
+    mock.send(msg("late pong"));
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!("ping", mock.next_write().unwrap_msg());
+    mock.send(msg("pong"));
+    assert_eq!("pong", pong.wait().unwrap().into_inner());
+
+    mock.allow_and_assert_drop();
+}
+
+#[test]
+fn test_unexpected_response() {
+    let (mut mock, service, _other) = mock::pipeline_client();
+
+    // A response without a request shuts the connection down
+    mock.send(msg("pong"));
+    mock.allow_and_assert_drop();
+
+    let pong = service.call(Message::WithoutBody("ping"));
+    assert_eq!(io::ErrorKind::InvalidData, pong.wait().unwrap_err().kind());
+}
+
 fn msg(msg: &'static str) -> Frame<&'static str, u32, io::Error> {
     Frame::Message {
         message: msg,
@@ -382,6 +382,28 @@ fn test_streaming_response_body() {
     mock.allow_and_assert_drop();
 }
 
+#[test]
+fn test_request_timeout() {
+    let service = simple_service(|req: Message<&'static str, Body<u32, io::Error>>| {
+        match req.into_inner() {
+            "slow" => future::empty().boxed(),
+            req => future::ok(Message::WithoutBody(req)).boxed(),
+        }
+    });
+
+    let (mut mock, _other) = mock::pipeline_server_with_timeout(
+        service, Some(Duration::from_millis(50)));
+
+    mock.send(msg("slow"));
+    mock.send(msg("fast"));
+
+    // Responses are written in order, so the timeout holds up "fast"
+    assert_eq!(io::ErrorKind::TimedOut, mock.next_write().unwrap_err().kind());
+    assert_eq!("fast", mock.next_write().unwrap_msg());
+
+    mock.allow_and_assert_drop();
+}
+
 fn msg(msg: &'static str) -> Frame<&'static str, u32, io::Error> {
     Frame::Message { message: msg, body: false }
 }
@@ -0,0 +1,119 @@
+#![allow(deprecated)]
+
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use tokio_core::reactor::Core;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_proto::util::pool::Pool;
+
+mod support;
+use support::line::LineProto;
+use support::service::simple_service;
+
+// Runs the event loop until `f` returns true.
+fn run_until<F: FnMut() -> bool>(core: &mut Core, mut f: F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    while !f() {
+        assert!(Instant::now() < deadline, "timed out");
+        core.turn(Some(Duration::from_millis(10)));
+    }
+}
+
+#[test]
+fn test_routes_to_least_outstanding_connection() {
+    let server = TcpServer::new(LineProto, "127.0.0.1:0".parse().unwrap());
+    let server = server.start(|| Ok(simple_service(|req: String| future::ok(req)))).unwrap();
+    let addr = server.local_addr();
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let mut pool = Pool::new(TcpClient::new(LineProto), &handle);
+    pool.max_connections(2);
+
+    // The first two requests each get a connection of their own, while the
+    // third has to share one
+    let reqs = ["one", "two", "three"].iter()
+        .map(|msg| pool.call(&addr, msg.to_string()))
+        .collect::<Vec<_>>();
+
+    let stats = pool.stats();
+    assert_eq!(2, stats.connections());
+    assert_eq!(2, stats.connecting());
+    assert_eq!(3, stats.outstanding());
+
+    let resps = core.run(future::join_all(reqs)).unwrap();
+    assert_eq!(vec!["one", "two", "three"], resps);
+
+    let stats = pool.stats();
+    assert_eq!(2, stats.connections());
+    assert_eq!(0, stats.connecting());
+    assert_eq!(0, stats.outstanding());
+
+    // Idle connections are reused
+    assert_eq!("four", core.run(pool.call(&addr, "four".to_string())).unwrap());
+    assert_eq!(2, pool.stats().created());
+}
+
+#[test]
+fn test_evicts_closed_connections() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // Answers a single line on every connection, and then closes it
+    thread::spawn(move || {
+        for socket in listener.incoming() {
+            let mut socket = socket.unwrap();
+            let mut line = String::new();
+
+            if BufReader::new(&mut socket).read_line(&mut line).unwrap() > 0 {
+                socket.write_all(line.as_bytes()).unwrap();
+            }
+        }
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let pool = Pool::new(TcpClient::new(LineProto), &handle);
+
+    for (i, msg) in ["one", "two"].iter().enumerate() {
+        assert_eq!(*msg, core.run(pool.call(&addr, msg.to_string())).unwrap());
+
+        run_until(&mut core, || pool.stats().evicted() == i + 1);
+        assert_eq!(0, pool.stats().connections());
+    }
+
+    assert_eq!(2, pool.stats().created());
+}
+
+#[test]
+fn test_connect_error() {
+    let addr: SocketAddr = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    };
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let pool = Pool::new(TcpClient::new(LineProto), &handle);
+
+    let err = core.run(pool.call(&addr, "hello".to_string())).err().unwrap();
+    assert_eq!(io::ErrorKind::ConnectionRefused, err.kind());
+
+    let stats = pool.stats();
+    assert_eq!(0, stats.connections());
+    assert_eq!(0, stats.outstanding());
+    assert_eq!(0, stats.evicted());
+}
@@ -0,0 +1,147 @@
+#![allow(deprecated)]
+
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio_core::reactor::Core;
+use tokio_proto::{TcpClient, ReconnectingClient, ConnectionState};
+use tokio_service::Service;
+
+mod support;
+use support::line::LineProto;
+
+// Answers a single line on every connection, and then closes it. Returns the
+// number of connections accepted so far.
+fn one_shot_server(listener: TcpListener) -> Arc<AtomicUsize> {
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let accepted2 = accepted.clone();
+
+    thread::spawn(move || {
+        for socket in listener.incoming() {
+            let mut socket = socket.unwrap();
+            accepted2.fetch_add(1, Ordering::SeqCst);
+
+            let mut line = String::new();
+
+            if BufReader::new(&mut socket).read_line(&mut line).unwrap() > 0 {
+                socket.write_all(line.as_bytes()).unwrap();
+            }
+        }
+    });
+
+    accepted
+}
+
+// Returns an address nothing is listening on.
+fn unused_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+// Runs the event loop until `f` returns true.
+fn run_until<F: FnMut() -> bool>(core: &mut Core, mut f: F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    while !f() {
+        assert!(Instant::now() < deadline, "timed out");
+        core.turn(Some(Duration::from_millis(10)));
+    }
+}
+
+#[test]
+fn test_reconnects_after_connection_closed() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accepted = one_shot_server(listener);
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let client = ReconnectingClient::new(TcpClient::new(LineProto), &[addr], &handle);
+    assert_eq!(ConnectionState::Connecting, client.state());
+
+    for (i, msg) in ["one", "two", "three"].iter().enumerate() {
+        run_until(&mut core, || client.state() == ConnectionState::Connected);
+
+        let resp = core.run(client.call(msg.to_string())).unwrap();
+        assert_eq!(*msg, resp);
+
+        // The server closes the connection after answering, and the client
+        // connects again
+        run_until(&mut core, || accepted.load(Ordering::SeqCst) == i + 2);
+    }
+}
+
+#[test]
+fn test_backs_off_when_connections_are_closed_right_away() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let accepted2 = accepted.clone();
+
+    thread::spawn(move || {
+        for socket in listener.incoming() {
+            drop(socket.unwrap());
+            accepted2.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let mut client = ReconnectingClient::new(TcpClient::new(LineProto), &[addr], &handle);
+    client.backoff(Duration::from_millis(50), Duration::from_millis(200));
+
+    let deadline = Instant::now() + Duration::from_millis(500);
+
+    while Instant::now() < deadline {
+        core.turn(Some(Duration::from_millis(10)));
+    }
+
+    // Waits 50, 100 and 200 milliseconds between the connections
+    let accepted = accepted.load(Ordering::SeqCst);
+    assert!(accepted >= 2 && accepted <= 5, "accepted {} connections", accepted);
+}
+
+#[test]
+fn test_queues_requests_while_reconnecting() {
+    let addr = unused_addr();
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let mut client = ReconnectingClient::new(TcpClient::new(LineProto), &[addr], &handle);
+    client.backoff(Duration::from_millis(10), Duration::from_millis(50));
+
+    let resp = client.call("hello".to_string());
+    run_until(&mut core, || client.state() == ConnectionState::Backoff);
+
+    drop(one_shot_server(TcpListener::bind(addr).unwrap()));
+
+    assert_eq!("hello", core.run(resp).unwrap());
+}
+
+#[test]
+fn test_fail_fast() {
+    let addr = unused_addr();
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let mut client = ReconnectingClient::new(TcpClient::new(LineProto), &[addr], &handle);
+    client.fail_fast(true);
+
+    let err = core.run(client.call("hello".to_string())).err().unwrap();
+    assert_eq!(io::ErrorKind::NotConnected, err.kind());
+
+    run_until(&mut core, || client.state() == ConnectionState::Backoff);
+}
@@ -0,0 +1,135 @@
+#![allow(deprecated)]
+
+extern crate futures;
+extern crate net2;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io;
+use std::net::{self, SocketAddr};
+use std::time::{Duration, Instant};
+
+use futures::future;
+use net2::TcpBuilder;
+use tokio_core::reactor::Core;
+use tokio_proto::{TcpClient, TcpServer};
+use tokio_service::Service;
+
+mod support;
+use support::line::LineProto;
+use support::service::simple_service;
+
+fn server() -> tokio_proto::ServerHandle {
+    let server = TcpServer::new(LineProto, "127.0.0.1:0".parse().unwrap());
+    server.start(|| Ok(simple_service(|req: String| future::ok(req)))).unwrap()
+}
+
+// Returns an address nothing is listening on.
+fn refused_addr() -> SocketAddr {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+// Returns an address that never answers connection attempts. The listen
+// backlog of the socket is full, so the attempts are left hanging.
+fn unresponsive_addr() -> (net::TcpListener, Vec<net::TcpStream>, SocketAddr) {
+    let listener = TcpBuilder::new_v4().unwrap()
+        .bind("127.0.0.1:0").unwrap()
+        .listen(0).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut backlog = Vec::new();
+
+    loop {
+        let socket = TcpBuilder::new_v4().unwrap();
+        let socket = socket.to_tcp_stream().unwrap();
+        socket.set_nonblocking(true).unwrap();
+
+        // Queue connections until one can no longer be established
+        drop(net2::TcpStreamExt::connect(&socket, addr));
+
+        let start = Instant::now();
+        let connected = loop {
+            if socket.peer_addr().is_ok() {
+                break true;
+            }
+            if start.elapsed() > Duration::from_millis(200) {
+                break false;
+            }
+        };
+
+        backlog.push(socket);
+
+        if !connected {
+            return (listener, backlog, addr);
+        }
+    }
+}
+
+#[test]
+fn test_connect_any_skips_refused_addresses() {
+    let server = server();
+    let addrs = [refused_addr(), server.local_addr()];
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let connect = TcpClient::new(LineProto).connect_any(&addrs, &handle).with_addr();
+    let (client, addr) = core.run(connect).unwrap();
+    assert_eq!(server.local_addr(), addr);
+
+    let resp = core.run(client.call("hello".to_string())).unwrap();
+    assert_eq!("hello", resp);
+}
+
+#[test]
+fn test_connect_any_reports_last_error() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let addrs = [refused_addr(), refused_addr()];
+    let err = core.run(TcpClient::new(LineProto).connect_any(&addrs, &handle))
+        .err().unwrap();
+    assert_eq!(io::ErrorKind::ConnectionRefused, err.kind());
+
+    let err = core.run(TcpClient::new(LineProto).connect_any(&[], &handle))
+        .err().unwrap();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+}
+
+#[test]
+fn test_connect_timeout() {
+    let (_listener, _backlog, addr) = unresponsive_addr();
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let mut client = TcpClient::new(LineProto);
+    client.connect_timeout(Duration::from_millis(100));
+
+    let start = Instant::now();
+    let err = core.run(client.connect(&addr, &handle)).err().unwrap();
+    assert_eq!(io::ErrorKind::TimedOut, err.kind());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_connect_any_staggers_attempts() {
+    let server = server();
+    let (_listener, _backlog, unresponsive) = unresponsive_addr();
+    let addrs = [unresponsive, server.local_addr()];
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let mut client = TcpClient::new(LineProto);
+    client.attempt_delay(Duration::from_millis(50));
+    client.connect_timeout(Duration::from_secs(5));
+
+    let (client, addr) = core.run(client.connect_any(&addrs, &handle).with_addr()).unwrap();
+    assert_eq!(server.local_addr(), addr);
+
+    let resp = core.run(client.call("hello".to_string())).unwrap();
+    assert_eq!("hello", resp);
+}
@@ -0,0 +1,548 @@
+#![allow(deprecated)]
+
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::{mpsc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use futures::sync::oneshot;
+use tokio_core::io::{Framed, Io};
+use tokio_core::reactor::Core;
+use tokio_proto::{TcpClient, TcpServer, LimitPolicy, ConnectionInfo};
+use tokio_proto::multiplex::MultiplexConfig;
+use tokio_proto::pipeline::Pipeline;
+use tokio_service::Service;
+
+mod support;
+use support::line::{LineCodec, LineProto, MultiplexLineProto};
+use support::service::simple_service;
+
+// Starts a server whose responses are delayed by `delay`. The returned
+// receiver is notified every time a request reaches the service.
+fn slow_server(delay: Duration) -> (tokio_proto::ServerHandle, mpsc::Receiver<String>) {
+    slow_server_with(delay, |_| {})
+}
+
+fn slow_server_with<F>(delay: Duration, configure: F)
+    -> (tokio_proto::ServerHandle, mpsc::Receiver<String>)
+    where F: FnOnce(&mut TcpServer<Pipeline, LineProto>)
+{
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    let mut server = TcpServer::new(LineProto, "127.0.0.1:0".parse().unwrap());
+    configure(&mut server);
+
+    let server = server.start(move || {
+        let tx = Mutex::new(tx.lock().unwrap().clone());
+
+        Ok(simple_service(move |req: String| {
+            tx.lock().unwrap().send(req.clone()).unwrap();
+
+            let (c, resp) = oneshot::channel();
+            thread::spawn(move || {
+                thread::sleep(delay);
+                c.complete(req);
+            });
+            resp.map_err(|_| unreachable!())
+        }))
+    }).unwrap();
+
+    (server, rx)
+}
+
+#[test]
+fn test_graceful_shutdown_answers_in_flight_requests() {
+    let (server, requests) = slow_server(Duration::from_millis(100));
+    let addr = server.local_addr();
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    sock.write_all(b"hello\n").unwrap();
+    assert_eq!("hello", requests.recv().unwrap());
+
+    server.shutdown_graceful(Duration::from_secs(5));
+
+    let mut resp = String::new();
+    sock.read_to_string(&mut resp).unwrap();
+    assert_eq!("hello\n", resp);
+
+    assert!(TcpStream::connect(&addr).is_err());
+}
+
+#[test]
+fn test_graceful_shutdown_times_out() {
+    let (server, requests) = slow_server(Duration::from_secs(5));
+    let addr = server.local_addr();
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    sock.write_all(b"hello\n").unwrap();
+    assert_eq!("hello", requests.recv().unwrap());
+
+    server.shutdown_graceful(Duration::from_millis(50));
+
+    let mut resp = String::new();
+    let _ = sock.read_to_string(&mut resp);
+    assert_eq!("", resp);
+}
+
+#[test]
+fn test_shutdown_drops_in_flight_requests() {
+    let (server, requests) = slow_server(Duration::from_secs(5));
+    let addr = server.local_addr();
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    sock.write_all(b"hello\n").unwrap();
+    assert_eq!("hello", requests.recv().unwrap());
+
+    server.shutdown();
+
+    let mut resp = String::new();
+    let _ = sock.read_to_string(&mut resp);
+    assert_eq!("", resp);
+
+    assert!(TcpStream::connect(&addr).is_err());
+}
+
+#[test]
+fn test_spawn_on_existing_core() {
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let server = TcpServer::new(LineProto, "127.0.0.1:0".parse().unwrap());
+    let addr = server.spawn(&handle, || {
+        Ok(simple_service(|req: String| future::ok(req.to_uppercase())))
+    }).unwrap();
+
+    assert!(addr.port() != 0);
+
+    let resp = TcpClient::new(LineProto).connect(&addr, &handle).and_then(|client| {
+        client.call("hello".to_string())
+    });
+
+    assert_eq!("HELLO", core.run(resp).unwrap());
+}
+
+#[test]
+fn test_new_service_error_closes_only_that_connection() {
+    let calls = AtomicUsize::new(0);
+
+    let server = TcpServer::new(LineProto, "127.0.0.1:0".parse().unwrap());
+    let server = server.start(move || {
+        if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "nope"));
+        }
+
+        Ok(simple_service(|req: String| future::ok(req)))
+    }).unwrap();
+
+    let addr = server.local_addr();
+
+    // The first connection is closed without being served
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    let _ = sock.write_all(b"one\n");
+
+    let mut resp = String::new();
+    let _ = sock.read_to_string(&mut resp);
+    assert_eq!("", resp);
+
+    // The server keeps accepting connections
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    sock.write_all(b"two\n").unwrap();
+
+    let mut resp = [0; 4];
+    sock.read_exact(&mut resp).unwrap();
+    assert_eq!(b"two\n", &resp);
+
+    server.shutdown();
+}
+
+#[test]
+fn test_new_service_error_keeps_other_connections_open() {
+    let calls = AtomicUsize::new(0);
+
+    let server = TcpServer::new(LineProto, "127.0.0.1:0".parse().unwrap());
+    let server = server.start(move || {
+        if calls.fetch_add(1, Ordering::SeqCst) == 1 {
+            return Err(io::Error::new(io::ErrorKind::Other, "nope"));
+        }
+
+        Ok(simple_service(|req: String| future::ok(req)))
+    }).unwrap();
+
+    let addr = server.local_addr();
+
+    let mut one = TcpStream::connect(&addr).unwrap();
+    assert_eq!("one\n", echo(&mut one, "one").unwrap());
+
+    // Only the connection whose service failed is closed
+    let mut two = TcpStream::connect(&addr).unwrap();
+    assert!(echo(&mut two, "two").is_err());
+
+    assert_eq!("three\n", echo(&mut one, "three").unwrap());
+
+    server.shutdown();
+}
+
+fn echo_server(max: usize, policy: LimitPolicy) -> tokio_proto::ServerHandle {
+    echo_server_with_threads(max, policy, 1)
+}
+
+fn echo_server_with_threads(max: usize, policy: LimitPolicy, threads: usize)
+    -> tokio_proto::ServerHandle
+{
+    let mut server = TcpServer::new(LineProto, "127.0.0.1:0".parse().unwrap());
+    server.threads(threads);
+    server.max_connections(max);
+    server.limit_policy(policy);
+    server.start(|| Ok(simple_service(|req: String| future::ok(req)))).unwrap()
+}
+
+fn echo(sock: &mut TcpStream, msg: &str) -> io::Result<String> {
+    try!(sock.write_all(format!("{}\n", msg).as_bytes()));
+
+    let mut resp = vec![0; msg.len() + 1];
+    try!(sock.read_exact(&mut resp));
+    Ok(String::from_utf8(resp).unwrap())
+}
+
+#[test]
+fn test_connection_limit_reject() {
+    let server = echo_server(1, LimitPolicy::Reject);
+    let addr = server.local_addr();
+
+    let mut one = TcpStream::connect(&addr).unwrap();
+    assert_eq!("one\n", echo(&mut one, "one").unwrap());
+
+    // Over the limit, the connection is closed right away
+    let mut two = TcpStream::connect(&addr).unwrap();
+    assert!(echo(&mut two, "two").is_err());
+
+    // Closing the first connection frees up its slot
+    drop(one);
+    thread::sleep(Duration::from_millis(50));
+
+    let mut three = TcpStream::connect(&addr).unwrap();
+    assert_eq!("three\n", echo(&mut three, "three").unwrap());
+
+    server.shutdown();
+}
+
+#[test]
+fn test_connection_limit_pause() {
+    let server = echo_server(1, LimitPolicy::Pause);
+    let addr = server.local_addr();
+
+    let mut one = TcpStream::connect(&addr).unwrap();
+    assert_eq!("one\n", echo(&mut one, "one").unwrap());
+
+    // Over the limit, the connection waits in the backlog
+    let mut two = TcpStream::connect(&addr).unwrap();
+    two.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    assert!(echo(&mut two, "two").is_err());
+
+    // ... until the first one closes
+    drop(one);
+    two.set_read_timeout(None).unwrap();
+
+    let mut resp = [0; 4];
+    two.read_exact(&mut resp).unwrap();
+    assert_eq!(b"two\n", &resp);
+
+    server.shutdown();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_connection_limit_pause_shared_by_workers() {
+    let server = echo_server_with_threads(1, LimitPolicy::Pause, 8);
+    let addr = server.local_addr();
+
+    // Connecting all at once makes several workers accept a connection, so
+    // that all but one have to hold on to theirs until a slot frees up
+    let mut socks = (0..16).map(|_| {
+        let mut sock = TcpStream::connect(&addr).unwrap();
+        sock.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        sock.write_all(b"hello\n").unwrap();
+        sock
+    }).collect::<Vec<_>>();
+    thread::sleep(Duration::from_millis(100));
+
+    // Waiting for a slot does not keep the workers busy
+    let before = cpu_time();
+    thread::sleep(Duration::from_millis(300));
+    assert!(cpu_time() - before < Duration::from_millis(100));
+
+    // Every connection is served once the previous ones are closed
+    let start = Instant::now();
+
+    while !socks.is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let served = socks.iter_mut().position(|sock| {
+            let mut resp = [0; 6];
+            sock.read_exact(&mut resp).is_ok()
+        });
+
+        if let Some(served) = served {
+            drop(socks.remove(served));
+        }
+    }
+
+    server.shutdown();
+}
+
+// Returns the CPU time used by the process so far
+#[cfg(target_os = "linux")]
+fn cpu_time() -> Duration {
+    let mut stat = String::new();
+    std::fs::File::open("/proc/self/stat").unwrap().read_to_string(&mut stat).unwrap();
+
+    // The fields after the command name, which is in parentheses
+    let fields = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect::<Vec<_>>();
+    let utime: u64 = fields[11].parse().unwrap();
+    let stime: u64 = fields[12].parse().unwrap();
+
+    // Clock ticks are 1/100 s on Linux
+    Duration::from_millis((utime + stime) * 10)
+}
+
+#[test]
+fn test_max_idle_time_closes_idle_connection() {
+    let (server, requests) = slow_server_with(Duration::from_millis(0), |server| {
+        server.max_idle_time(Duration::from_millis(100));
+    });
+    let addr = server.local_addr();
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    assert_eq!("hello\n", echo(&mut sock, "hello").unwrap());
+    assert_eq!("hello", requests.recv().unwrap());
+
+    let mut resp = String::new();
+    sock.read_to_string(&mut resp).unwrap();
+    assert_eq!("", resp);
+
+    server.shutdown();
+}
+
+#[test]
+fn test_max_idle_time_closes_stalled_connection() {
+    let (server, requests) = slow_server_with(Duration::from_millis(500), |server| {
+        server.max_idle_time(Duration::from_millis(100));
+    });
+    let addr = server.local_addr();
+
+    // No data moves while the service works on the request
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    sock.write_all(b"hello\n").unwrap();
+    assert_eq!("hello", requests.recv().unwrap());
+
+    let mut resp = String::new();
+    sock.read_to_string(&mut resp).unwrap();
+    assert_eq!("", resp);
+
+    server.shutdown();
+}
+
+#[test]
+fn test_max_idle_time_counts_data_moving() {
+    let (server, _requests) = slow_server_with(Duration::from_millis(0), |server| {
+        server.max_idle_time(Duration::from_millis(100));
+    });
+    let addr = server.local_addr();
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+
+    for _ in 0..6 {
+        assert_eq!("hello\n", echo(&mut sock, "hello").unwrap());
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    server.shutdown();
+}
+
+#[test]
+fn test_read_timeout_closes_connection() {
+    let (server, requests) = slow_server_with(Duration::from_millis(500), |server| {
+        server.read_timeout(Duration::from_millis(100));
+    });
+    let addr = server.local_addr();
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    sock.write_all(b"hello\n").unwrap();
+    assert_eq!("hello", requests.recv().unwrap());
+
+    let mut resp = String::new();
+    sock.read_to_string(&mut resp).unwrap();
+    assert_eq!("", resp);
+
+    server.shutdown();
+}
+
+#[test]
+fn test_write_timeout_closes_connection() {
+    let (server, _requests) = slow_server_with(Duration::from_millis(0), |server| {
+        server.write_timeout(Duration::from_millis(100));
+    });
+    let addr = server.local_addr();
+
+    // Send more requests than the socket buffers can hold the responses to,
+    // without reading the responses for a while
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    let line = format!("{}\n", "a".repeat(64 * 1024));
+
+    for _ in 0..200 {
+        sock.write_all(line.as_bytes()).unwrap();
+    }
+
+    thread::sleep(Duration::from_millis(500));
+
+    // The server gave up on writing the responses
+    let mut resp = vec![];
+    let _ = sock.read_to_end(&mut resp);
+    assert!(resp.len() < 200 * line.len());
+
+    server.shutdown();
+}
+
+#[test]
+fn test_max_lifetime_answers_in_flight_requests() {
+    let (server, requests) = slow_server_with(Duration::from_millis(200), |server| {
+        server.max_lifetime(Duration::from_millis(100));
+    });
+    let addr = server.local_addr();
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    sock.write_all(b"hello\n").unwrap();
+    assert_eq!("hello", requests.recv().unwrap());
+
+    let mut resp = String::new();
+    sock.read_to_string(&mut resp).unwrap();
+    assert_eq!("hello\n", resp);
+
+    server.shutdown();
+}
+
+// A protocol that only works on TCP sockets
+struct NoDelayLineProto;
+
+impl tokio_proto::pipeline::ServerProto<tokio_core::net::TcpStream> for NoDelayLineProto {
+    type Request = String;
+    type Response = String;
+    type Transport = Framed<tokio_core::net::TcpStream, LineCodec>;
+    type BindTransport = io::Result<Self::Transport>;
+
+    fn bind_transport(&self, io: tokio_core::net::TcpStream) -> Self::BindTransport {
+        try!(io.set_nodelay(true));
+        Ok(io.framed(LineCodec))
+    }
+}
+
+#[test]
+fn test_protocol_for_tcp_stream() {
+    let mut server = TcpServer::new(NoDelayLineProto, "127.0.0.1:0".parse().unwrap());
+    server.max_idle_time(Duration::from_secs(10));
+    let server = server.start(|| Ok(simple_service(|req: String| future::ok(req)))).unwrap();
+
+    let mut sock = TcpStream::connect(&server.local_addr()).unwrap();
+    assert_eq!("hello\n", echo(&mut sock, "hello").unwrap());
+
+    server.shutdown();
+}
+
+#[test]
+fn test_connection_info() {
+    let server = TcpServer::new(LineProto, "127.0.0.1:0".parse().unwrap());
+    let server = server.start_with_connection_info(|info: &ConnectionInfo| {
+        let info = info.clone();
+        Ok(simple_service(move |_: String| {
+            future::ok(format!("{} {} {}", info.id(), info.peer_addr(), info.local_addr()))
+        }))
+    }).unwrap();
+    let addr = server.local_addr();
+
+    let mut one = TcpStream::connect(&addr).unwrap();
+    let mut two = TcpStream::connect(&addr).unwrap();
+
+    let mut resp = String::new();
+    one.write_all(b"who\n").unwrap();
+    BufReader::new(&mut one).read_line(&mut resp).unwrap();
+    two.write_all(b"who\n").unwrap();
+    BufReader::new(&mut two).read_line(&mut resp).unwrap();
+
+    let lines: Vec<Vec<&str>> = resp.lines().map(|l| l.split(' ').collect()).collect();
+
+    assert!(lines[0][0] != lines[1][0]);
+    assert_eq!(one.local_addr().unwrap().to_string(), lines[0][1]);
+    assert_eq!(two.local_addr().unwrap().to_string(), lines[1][1]);
+    assert_eq!(addr.to_string(), lines[0][2]);
+
+    server.shutdown();
+}
+
+#[test]
+fn test_multiplex_config() {
+    let mut config = MultiplexConfig::default();
+    config.max_in_flight = 1;
+
+    let server = TcpServer::new(MultiplexLineProto, "127.0.0.1:0".parse().unwrap())
+        .multiplex_config(config);
+
+    let server = server.start(|| {
+        Ok(simple_service(|req: String| {
+            let (c, resp) = oneshot::channel();
+            let delay = if req == "slow" { 100 } else { 0 };
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(delay));
+                c.complete(req);
+            });
+            resp.map_err(|_| unreachable!())
+        }))
+    }).unwrap();
+
+    let mut sock = TcpStream::connect(&server.local_addr()).unwrap();
+    sock.write_all(b"0 slow\n1 fast\n").unwrap();
+
+    // With a single request in flight, the fast request is only handled
+    // once the slow one has been answered
+    let mut lines = BufReader::new(sock).lines();
+    assert_eq!("0 slow", lines.next().unwrap().unwrap());
+    assert_eq!("1 fast", lines.next().unwrap().unwrap());
+
+    server.shutdown();
+}
+
+#[test]
+fn test_multiplex_notification_is_not_responded_to() {
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    let server = TcpServer::new(MultiplexLineProto, "127.0.0.1:0".parse().unwrap());
+    let server = server.start(move || {
+        let tx = Mutex::new(tx.lock().unwrap().clone());
+
+        Ok(simple_service(move |req: String| {
+            tx.lock().unwrap().send(req.clone()).unwrap();
+            future::ok(req)
+        }))
+    }).unwrap();
+
+    let mut sock = TcpStream::connect(&server.local_addr()).unwrap();
+    sock.write_all(b"0! hello\n1 ping\n").unwrap();
+
+    // Both reach the service, but only the request is answered
+    assert_eq!("hello", rx.recv().unwrap());
+    assert_eq!("ping", rx.recv().unwrap());
+
+    let mut lines = BufReader::new(sock).lines();
+    assert_eq!("1 ping", lines.next().unwrap().unwrap());
+
+    server.shutdown();
+}
@@ -0,0 +1,192 @@
+#![cfg(unix)]
+#![allow(deprecated)]
+
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_proto;
+extern crate tokio_service;
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::Duration;
+
+use futures::future;
+use tokio_core::reactor::Core;
+use tokio_proto::{UnixClient, UnixServer, UnixServerHandle, LimitPolicy};
+use tokio_proto::pipeline::Pipeline;
+use tokio_service::Service;
+
+mod support;
+use support::line::LineProto;
+use support::service::simple_service;
+
+fn socket_path() -> PathBuf {
+    static NEXT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    let name = format!("tokio-proto-test-{}-{}.sock",
+                       std::process::id(),
+                       NEXT.fetch_add(1, Ordering::Relaxed));
+    std::env::temp_dir().join(name)
+}
+
+fn echo_server<F>(configure: F) -> io::Result<UnixServerHandle>
+    where F: FnOnce(&mut UnixServer<Pipeline, LineProto>)
+{
+    let mut server = UnixServer::new(LineProto, socket_path());
+    configure(&mut server);
+    server.start(|| Ok(simple_service(|req: String| future::ok(req))))
+}
+
+fn echo(server: &UnixServerHandle, msg: &str) -> String {
+    let mut core = Core::new().unwrap();
+    let client = UnixClient::new(LineProto).connect(server.path(), &core.handle()).unwrap();
+    core.run(client.call(msg.to_string())).unwrap()
+}
+
+#[test]
+fn test_ping_pong() {
+    let server = echo_server(|server| server.threads(2)).unwrap();
+
+    for _ in 0..4 {
+        assert_eq!("ping", echo(&server, "ping"));
+    }
+
+    server.shutdown();
+}
+
+#[test]
+fn test_shutdown_removes_socket_file() {
+    let server = echo_server(|_| {}).unwrap();
+    let path = server.path().to_path_buf();
+    assert!(path.exists());
+
+    server.shutdown();
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_removes_stale_socket_file() {
+    let path = socket_path();
+
+    // Leaves the socket file behind
+    drop(UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let server = echo_server(|server| server.path(&path)).unwrap();
+    assert_eq!("ping", echo(&server, "ping"));
+    server.shutdown();
+}
+
+#[test]
+fn test_socket_in_use() {
+    let server = echo_server(|_| {}).unwrap();
+    let path = server.path().to_path_buf();
+
+    let err = echo_server(|server| server.path(&path)).err().unwrap();
+    assert_eq!(io::ErrorKind::AddrInUse, err.kind());
+
+    // The running server is left alone
+    assert_eq!("ping", echo(&server, "ping"));
+    server.shutdown();
+}
+
+#[test]
+fn test_path_is_not_a_socket() {
+    let path = socket_path();
+    fs::File::create(&path).unwrap();
+
+    assert!(echo_server(|server| server.path(&path)).is_err());
+    assert!(path.exists());
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_mode() {
+    let server = echo_server(|server| server.mode(0o600)).unwrap();
+
+    let mode = fs::metadata(server.path()).unwrap().permissions().mode();
+    assert_eq!(0o600, mode & 0o777);
+    assert_eq!("ping", echo(&server, "ping"));
+
+    // The directory the socket was bound in is gone
+    let name = server.path().file_name().unwrap().to_str().unwrap().to_string();
+    let parent = server.path().parent().unwrap().to_path_buf();
+    let leftovers = fs::read_dir(parent).unwrap().filter(|entry| {
+        let entry = entry.as_ref().unwrap().file_name();
+        entry.to_str().unwrap().starts_with(&format!(".{}.", name))
+    }).count();
+    assert_eq!(0, leftovers);
+
+    server.shutdown();
+}
+
+#[test]
+fn test_connection_limit_reject() {
+    let server = echo_server(|server| {
+        server.threads(2);
+        server.max_connections(1);
+        server.limit_policy(LimitPolicy::Reject);
+    }).unwrap();
+
+    let mut one = UnixStream::connect(server.path()).unwrap();
+    one.write_all(b"one\n").unwrap();
+    let mut resp = [0; 4];
+    one.read_exact(&mut resp).unwrap();
+    assert_eq!(b"one\n", &resp);
+
+    // Over the limit, the connection is closed right away
+    let mut two = UnixStream::connect(server.path()).unwrap();
+    let _ = two.write_all(b"two\n");
+    let mut resp = Vec::new();
+    let _ = two.read_to_end(&mut resp);
+    assert!(resp.is_empty());
+
+    // Closing the first connection frees up its slot
+    drop(one);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!("three", echo(&server, "three"));
+
+    server.shutdown();
+}
+
+#[test]
+fn test_shutdown_graceful() {
+    let server = echo_server(|_| {}).unwrap();
+    let path = server.path().to_path_buf();
+
+    let mut sock = UnixStream::connect(&path).unwrap();
+    sock.write_all(b"one\n").unwrap();
+    let mut resp = [0; 4];
+    sock.read_exact(&mut resp).unwrap();
+
+    // The open connection is wound down rather than keeping the server up
+    server.shutdown_graceful(Duration::from_secs(10));
+    assert!(!path.exists());
+
+    let mut resp = Vec::new();
+    sock.read_to_end(&mut resp).unwrap();
+    assert!(resp.is_empty());
+}
+
+#[test]
+fn test_max_idle_time() {
+    let server = echo_server(|server| server.max_idle_time(Duration::from_millis(100))).unwrap();
+
+    let mut sock = UnixStream::connect(server.path()).unwrap();
+    sock.write_all(b"one\n").unwrap();
+    let mut resp = [0; 4];
+    sock.read_exact(&mut resp).unwrap();
+
+    // The server closes the connection once it has been idle for a while
+    let mut resp = Vec::new();
+    sock.read_to_end(&mut resp).unwrap();
+    assert!(resp.is_empty());
+
+    server.shutdown();
+}